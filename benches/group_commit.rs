@@ -0,0 +1,70 @@
+//! Measures how write throughput scales with the number of writer threads when every commit is synced.
+//!
+//! Run with `cargo bench --bench group_commit`. With SyncMode::Always each write waits for an fsync of the
+//! WAL, and concurrent writers share those fsyncs through group commit. The average group size and commit
+//! wait show how many commits each fsync covered and what it cost each of them.
+
+extern crate rust_db;
+
+use rust_db::{Db, Options, SyncMode};
+
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+use std::time::Instant;
+
+const WRITES_PER_THREAD: u32 = 2_000;
+const THREADS: [u32; 4] = [1, 2, 4, 8];
+
+fn main() {
+    let path = env::temp_dir().join("rust_db_bench_group_commit.bin");
+
+    println!("{} synced writes per thread", WRITES_PER_THREAD);
+    println!("{:>8} {:>12} {:>8} {:>11} {:>14}", "threads", "writes/s", "speedup", "avg group", "avg wait (us)");
+
+    let mut baseline = None;
+
+    for &threads in &THREADS {
+        remove_files(&path);
+        let options = Options { sync_mode: SyncMode::Always, checkpoint_wal_size: None, ..Options::default() };
+        let db = Arc::new(Db::open(&path, options).unwrap());
+
+        let start = Instant::now();
+        let handles: Vec<_> = (0..threads).map(|t| {
+            let db = db.clone();
+            thread::spawn(move || {
+                for i in 0..WRITES_PER_THREAD {
+                    db.put(&(t, i), &[7u8; 32]).unwrap();
+                }
+            })
+        }).collect();
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        let elapsed = start.elapsed();
+        let stats = db.stats();
+        let writes_per_sec = f64::from(threads * WRITES_PER_THREAD) / elapsed.as_secs_f64();
+        let baseline = *baseline.get_or_insert(writes_per_sec);
+
+        println!("{:>8} {:>12.0} {:>7.2}x {:>11.2} {:>14.1}", threads, writes_per_sec, writes_per_sec / baseline,
+            stats.average_wal_group(), stats.average_commit_wait().as_secs_f64() * 1e6);
+
+        drop(db);
+    }
+
+    remove_files(&path);
+}
+
+fn remove_files(path: &Path) {
+    let _ = fs::remove_file(path);
+    let mut wal_path = path.as_os_str().to_owned();
+    wal_path.push(".wal");
+    let _ = fs::remove_file(wal_path);
+    let mut dwb_path = path.as_os_str().to_owned();
+    dwb_path.push(".dwb");
+    let _ = fs::remove_file(dwb_path);
+}
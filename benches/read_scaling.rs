@@ -0,0 +1,111 @@
+//! Measures how read throughput scales with the number of reader threads sharing one Db.
+//!
+//! Run with `cargo bench --bench read_scaling`. Every key lives in the buffer pool, so the numbers show
+//! contention on the latches rather than disk reads. A second round repeats the reads while another thread
+//! keeps writing. Scaling is bounded by the number of cores of the machine.
+
+extern crate rust_db;
+
+use rust_db::{Db, Options};
+
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const KEYS: u32 = 50_000;
+const READS_PER_THREAD: u32 = 200_000;
+const THREADS: [u32; 4] = [1, 2, 4, 8];
+
+fn main() {
+    let path = env::temp_dir().join("rust_db_bench_read_scaling.bin");
+    remove_files(&path);
+
+    let options = Options { cache_size: 4096, ..Options::default() };
+    let db = Arc::new(Db::open(&path, options).unwrap());
+    for i in 0..KEYS {
+        db.put(&i, &[7u8; 32]).unwrap();
+    }
+    db.flush().unwrap();
+
+    println!("{} keys, {} reads per thread", KEYS, READS_PER_THREAD);
+    run(&db, false);
+    run(&db, true);
+
+    drop(db);
+    remove_files(&path);
+}
+
+/// Reads with every thread count in turn and prints the reads per second, optionally with a writer running.
+fn run(db: &Arc<Db>, with_writer: bool) {
+    println!();
+    println!("{}", if with_writer { "with one concurrent writer" } else { "readers only" });
+    println!("{:>8} {:>14} {:>8}", "threads", "reads/s", "speedup");
+
+    let mut baseline = None;
+
+    for &threads in &THREADS {
+        let stop = Arc::new(AtomicBool::new(false));
+        let writer = match with_writer {
+            true => Some(spawn_writer(db.clone(), stop.clone())),
+            false => None,
+        };
+
+        let elapsed = read_with(db, threads);
+        stop.store(true, Ordering::Relaxed);
+        if let Some(writer) = writer {
+            writer.join().unwrap();
+        }
+
+        let reads_per_sec = f64::from(threads * READS_PER_THREAD) / elapsed.as_secs_f64();
+        let baseline = *baseline.get_or_insert(reads_per_sec);
+        println!("{:>8} {:>14.0} {:>7.2}x", threads, reads_per_sec, reads_per_sec / baseline);
+    }
+}
+
+/// Runs READS_PER_THREAD point reads on each of the threads and returns how long they took together.
+fn read_with(db: &Arc<Db>, threads: u32) -> Duration {
+    let start = Instant::now();
+
+    let handles: Vec<_> = (0..threads).map(|t| {
+        let db = db.clone();
+        thread::spawn(move || {
+            // A cheap per-thread pseudo-random walk over the keys.
+            let mut key = t.wrapping_mul(2_654_435_761) % KEYS;
+            for _ in 0..READS_PER_THREAD {
+                assert!(db.get(&key).unwrap().is_some());
+                key = (key.wrapping_mul(1_103_515_245).wrapping_add(12_345)) % KEYS;
+            }
+        })
+    }).collect();
+
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    start.elapsed()
+}
+
+/// Keeps overwriting existing keys until stop is set, so the readers share the tree with a writer.
+fn spawn_writer(db: Arc<Db>, stop: Arc<AtomicBool>) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        let mut key = 0;
+        while !stop.load(Ordering::Relaxed) {
+            db.put(&key, &[8u8; 32]).unwrap();
+            key = (key + 7) % KEYS;
+        }
+    })
+}
+
+fn remove_files(path: &Path) {
+    let _ = fs::remove_file(path);
+    let mut wal_path = path.as_os_str().to_owned();
+    wal_path.push(".wal");
+    let _ = fs::remove_file(wal_path);
+    let mut dwb_path = path.as_os_str().to_owned();
+    dwb_path.push(".dwb");
+    let _ = fs::remove_file(dwb_path);
+}
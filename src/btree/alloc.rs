@@ -0,0 +1,206 @@
+use btree::checksum::{seal_page, verify_page};
+use btree::error::{DbError, Result};
+use btree::meta::NO_PAGE;
+use btree::store::PageStore;
+
+use std::collections::BTreeSet;
+use std::convert::TryInto;
+use std::io::{self, Seek, Write};
+
+/// Marks a page as a trunk page of the free list.
+const TRUNK_MAGIC: [u8; 4] = *b"FREE";
+
+/// Bytes used by the header of a trunk page: magic (4), checksum (4), next trunk (4) and number of ids (4).
+const TRUNK_HEADER_SIZE: usize = 16;
+
+/// Hands out page ids for new nodes and takes back the pages of removed ones.
+///
+/// Free pages are reused lowest id first, so the file only grows once every free page is in use. Free pages at
+/// the end of the file are dropped when the free list is written, which shrinks the file instead.
+///
+/// The free list is stored in the free pages themselves. Each trunk page holds the ids of some free pages and
+/// the id of the next trunk page, and the first trunk page is recorded in the meta page. A trunk page is laid out as
+///
+/// | magic (4) | checksum (4) | next trunk (4) | count (4) | free page ids (4 each) |
+#[derive(Clone)]
+pub struct PageAllocator {
+    free: BTreeSet<u32>,
+    page_count: u32,
+}
+
+impl PageAllocator {
+    /// Create an allocator for a file of page_count pages without any free pages.
+    pub fn new(page_count: u32) -> Self {
+        Self { free: BTreeSet::new(), page_count }
+    }
+
+    /// Reads the free list starting at the trunk page head. Returns a Corruption error for a trunk page that
+    /// is not marked as one, fails its checksum or lists pages outside the file.
+    pub fn load(file: &mut dyn PageStore, page_size: usize, head: u32, page_count: u32) -> Result<Self> {
+        let mut allocator = PageAllocator::new(page_count);
+        let mut trunk = head;
+        let mut buf = vec![0u8; page_size];
+
+        while trunk != NO_PAGE {
+            let corruption = DbError::Corruption { page_id: trunk };
+
+            if trunk >= page_count || !allocator.free.insert(trunk) {
+                return Err(corruption);
+            }
+
+            file.read_page(page_size as u64 * u64::from(trunk), &mut buf)?;
+
+            let count = read_u32(&buf, 12) as usize;
+
+            if buf[0..4] != TRUNK_MAGIC || !verify_page(&buf, 4) || count > trunk_capacity(page_size) {
+                return Err(corruption);
+            }
+
+            for i in 0..count {
+                let page_id = read_u32(&buf, TRUNK_HEADER_SIZE + 4 * i);
+
+                if page_id == NO_PAGE || page_id >= page_count || !allocator.free.insert(page_id) {
+                    return Err(corruption);
+                }
+            }
+
+            trunk = read_u32(&buf, 8);
+        }
+
+        Ok(allocator)
+    }
+
+    /// Returns the id of a page for a new node, reusing the lowest free page before growing the file.
+    pub fn allocate(&mut self) -> u32 {
+        match self.free.pop_first() {
+            Some(page_id) => page_id,
+            None => {
+                self.page_count += 1;
+                self.page_count - 1
+            }
+        }
+    }
+
+    /// Puts the page of a node that has been removed from the tree on the free list.
+    pub fn free(&mut self, page_id: u32) {
+        self.free.insert(page_id);
+    }
+
+    /// Number of pages in the file, including the meta page and free pages.
+    pub fn page_count(&self) -> u32 {
+        self.page_count
+    }
+
+    pub fn free_count(&self) -> usize {
+        self.free.len()
+    }
+
+    pub fn is_free(&self, page_id: u32) -> bool {
+        self.free.contains(&page_id)
+    }
+
+    /// Drops the free pages at the end of the file, then writes the free list into trunk pages.
+    /// Returns the id of the first trunk page, to be stored in the meta page.
+    pub fn write<W: Write + Seek>(&mut self, file: &mut W, page_size: usize) -> Result<u32> {
+        while self.free.remove(&(self.page_count - 1)) {
+            self.page_count -= 1;
+        }
+
+        let ids: Vec<u32> = self.free.iter().cloned().collect();
+        let chunks: Vec<&[u32]> = ids.chunks(trunk_capacity(page_size) + 1).collect();
+
+        for (i, chunk) in chunks.iter().enumerate() {
+            let next = chunks.get(i + 1).map_or(NO_PAGE, |next| next[0]);
+            let mut buffer = Vec::with_capacity(page_size);
+
+            buffer.extend_from_slice(&TRUNK_MAGIC);
+            buffer.extend_from_slice(&[0u8; 4]);
+            buffer.extend_from_slice(&next.to_le_bytes());
+            buffer.extend_from_slice(&(chunk.len() as u32 - 1).to_le_bytes());
+            for page_id in &chunk[1..] {
+                buffer.extend_from_slice(&page_id.to_le_bytes());
+            }
+            buffer.resize(page_size, 0);
+            seal_page(&mut buffer, 4);
+
+            file.seek(io::SeekFrom::Start(page_size as u64 * u64::from(chunk[0])))?;
+            file.write_all(&buffer)?;
+        }
+
+        Ok(chunks.first().map_or(NO_PAGE, |first| first[0]))
+    }
+}
+
+/// Number of free page ids a single trunk page can hold.
+fn trunk_capacity(page_size: usize) -> usize {
+    (page_size - TRUNK_HEADER_SIZE) / 4
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use btree::file::DbFile;
+    use btree::test_files::{create_files, remove_files};
+    use std::fs::OpenOptions;
+
+    fn open_file(name: &str) -> (DbFile, String, String) {
+        let (file_path, wal_path) = create_files(&format!("alloc_{}", name));
+        let file = DbFile::open(&file_path, OpenOptions::new().read(true).write(true).create(true).truncate(true)).unwrap();
+        (file, file_path, wal_path)
+    }
+
+    #[test]
+    fn test_reuses_lowest_free_page() {
+        let mut pages = PageAllocator::new(2);
+        assert_eq!(pages.allocate(), 2);
+        assert_eq!(pages.allocate(), 3);
+        assert_eq!(pages.allocate(), 4);
+
+        pages.free(3);
+        pages.free(2);
+        assert_eq!(pages.allocate(), 2);
+        assert_eq!(pages.allocate(), 3);
+        assert_eq!(pages.allocate(), 5);
+        assert_eq!(pages.page_count(), 6);
+    }
+
+    #[test]
+    fn test_free_list_round_trip() {
+        let (mut file, file_path, wal_path) = open_file("round_trip");
+        let page_size = 512;
+        let mut pages = PageAllocator::new(1000);
+
+        // Enough free pages to need several trunk pages, plus a free tail that gets trimmed.
+        for page_id in (1..900).step_by(3).chain(995..1000) {
+            pages.free(page_id);
+        }
+
+        file.set_len(page_size as u64 * 1000).unwrap();
+        let head = pages.write(&mut file, page_size).unwrap();
+        assert_eq!(pages.page_count(), 995);
+
+        let loaded = PageAllocator::load(&mut file, page_size, head, pages.page_count()).unwrap();
+        assert_eq!(loaded.free, pages.free);
+        assert_eq!(loaded.free_count(), 300);
+
+        let empty = PageAllocator::new(10).write(&mut file, page_size).unwrap();
+        assert_eq!(empty, NO_PAGE);
+
+        remove_files(&file_path, &wal_path);
+    }
+
+    #[test]
+    fn test_bad_trunk_is_corruption() {
+        let (mut file, file_path, wal_path) = open_file("bad_trunk");
+        file.set_len(512 * 4).unwrap();
+
+        assert!(matches!(PageAllocator::load(&mut file, 512, 2, 4), Err(DbError::Corruption { page_id: 2 })));
+        assert!(matches!(PageAllocator::load(&mut file, 512, 7, 4), Err(DbError::Corruption { page_id: 7 })));
+
+        remove_files(&file_path, &wal_path);
+    }
+}
@@ -0,0 +1,141 @@
+use btree::key::EncodeKey;
+
+use std::convert::TryInto;
+
+/// A list of puts and deletes that BTree::apply_batch() applies as one unit.
+///
+/// The whole batch is logged as a single WAL record and synced at most once, so recovery either replays all of it or
+/// none of it. Unlike a transaction, a batch is put together without reading the tree, which makes it the
+/// cheaper choice for loading large amounts of data.
+///
+/// Serialized into the value of its WAL record, every operation is laid out as
+///
+/// | op (1) | key length (2) | value length (4) | key | value |
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct WriteBatch {
+    /// Keys in the order they were added, with the value to store or None to delete the key.
+    ops: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+}
+
+/// Bytes used by the header of an operation: op (1), key length (2) and value length (4).
+const OP_HEADER_SIZE: usize = 7;
+
+const PUT: u8 = 1;
+const DELETE: u8 = 2;
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a put of val for key. A later operation on the same key in the batch replaces this one.
+    pub fn put<K: EncodeKey + ?Sized>(&mut self, key: &K, val: &[u8]) {
+        self.ops.push((key.encode_key(), Some(val.to_vec())));
+    }
+
+    /// Adds a delete of key. Deleting a key that is not stored does nothing.
+    pub fn delete<K: EncodeKey + ?Sized>(&mut self, key: &K) {
+        self.ops.push((key.encode_key(), None));
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.ops.clear();
+    }
+
+    pub(crate) fn ops(&self) -> &[(Vec<u8>, Option<Vec<u8>>)] {
+        &self.ops
+    }
+
+    /// Sorts the operations by key. The sort is stable, so the last operation on a key still comes last.
+    pub(crate) fn sort(&mut self) {
+        self.ops.sort_by(|a, b| a.0.cmp(&b.0));
+    }
+
+    pub(crate) fn serialize(&self) -> Vec<u8> {
+        let mut buffer = Vec::new();
+
+        for (key, val) in &self.ops {
+            let (op, val): (u8, &[u8]) = match *val {
+                Some(ref val) => (PUT, val),
+                None => (DELETE, &[]),
+            };
+
+            buffer.push(op);
+            buffer.extend_from_slice(&(key.len() as u16).to_le_bytes());
+            buffer.extend_from_slice(&(val.len() as u32).to_le_bytes());
+            buffer.extend_from_slice(key);
+            buffer.extend_from_slice(val);
+        }
+
+        buffer
+    }
+
+    /// Returns None if the buffer does not hold a list of whole operations.
+    pub(crate) fn deserialize(buf: &[u8]) -> Option<WriteBatch> {
+        let mut batch = WriteBatch::new();
+        let mut offset = 0;
+
+        while offset < buf.len() {
+            if offset + OP_HEADER_SIZE > buf.len() {
+                return None;
+            }
+
+            let op = buf[offset];
+            let key_len = u16::from_le_bytes(buf[offset + 1..offset + 3].try_into().unwrap()) as usize;
+            let val_len = u32::from_le_bytes(buf[offset + 3..offset + 7].try_into().unwrap()) as usize;
+            let key_start = offset + OP_HEADER_SIZE;
+            let val_start = key_start + key_len;
+            offset = val_start.checked_add(val_len)?;
+
+            if offset > buf.len() {
+                return None;
+            }
+
+            let key = buf[key_start..val_start].to_vec();
+            match op {
+                PUT => batch.ops.push((key, Some(buf[val_start..offset].to_vec()))),
+                DELETE if val_len == 0 => batch.ops.push((key, None)),
+                _ => return None,
+            }
+        }
+
+        Some(batch)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::WriteBatch;
+
+    #[test]
+    fn test_round_trip() {
+        let mut batch = WriteBatch::new();
+        batch.put(b"b", b"2");
+        batch.delete(b"a");
+        batch.put(b"a", b"");
+        batch.put(b"b", b"3");
+
+        let buf = batch.serialize();
+        assert_eq!(WriteBatch::deserialize(&buf), Some(batch.clone()));
+        assert_eq!(WriteBatch::deserialize(&buf[..buf.len() - 1]), None);
+        assert_eq!(WriteBatch::deserialize(&[]), Some(WriteBatch::new()));
+
+        // Sorting keeps the operations on each key in the order they were added.
+        batch.sort();
+        let ops: Vec<_> = batch.ops().iter().map(|op| (op.0.clone(), op.1.clone())).collect();
+        assert_eq!(ops, vec![
+            (b"a".to_vec(), None),
+            (b"a".to_vec(), Some(Vec::new())),
+            (b"b".to_vec(), Some(b"2".to_vec())),
+            (b"b".to_vec(), Some(b"3".to_vec())),
+        ]);
+    }
+}
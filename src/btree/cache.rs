@@ -1,50 +1,224 @@
-extern crate linked_hash_map;
-use self::linked_hash_map::LinkedHashMap;
-
+use btree::latch::{self, PageLatch};
 use btree::node::BTreeNode;
 
-/// An in-memory cache to hold the most recently accessed pages. 
-/// and reduce the number of I/O operations. Uses Least Recently Used (LRU)
-/// method to evict old pages if the cache is full. 
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
 
+/// A page held by the buffer pool, along with whether it was changed since it was last written to disk.
+struct Frame {
+    page: PageLatch,
+    dirty: bool,
+    /// Stamp of the last time the page was added or handed out, larger for more recent uses.
+    last_used: AtomicU64,
+}
 
-pub struct LRUCache {
-    pub map: LinkedHashMap<u32, BTreeNode>
+/// An in-memory buffer pool to hold the most recently accessed pages
+/// and reduce the number of I/O operations. Uses Least Recently Used (LRU)
+/// method to evict old pages once it holds more than its capacity.
+///
+/// Every use of a page stamps it with the next value of a shared counter, so get() only takes &self and
+/// readers can share the pool. Eviction evicts the page with the smallest stamp.
+///
+/// Pages are handed out behind their latch. Pages that were modified stay dirty until flushed. Evicting a dirty
+/// page hands it back to the caller, who has to write it to disk. Pages that someone else still holds are never
+/// evicted, so the pool may go over its capacity while every page it holds is in use.
+pub struct BufferPool {
+    frames: HashMap<u32, Frame>,
+    capacity: usize,
+    /// The last stamp handed out.
+    clock: AtomicU64,
 }
 
-impl LRUCache {
-    pub fn new() -> Self {
-       Self { 
-        map : LinkedHashMap::new()
+impl BufferPool {
+    /// Create an empty buffer pool that holds up to capacity pages.
+    pub fn new(capacity: usize) -> Self {
+       Self {
+        frames : HashMap::new(),
+        capacity,
+        clock : AtomicU64::new(0),
        }
     }
 
-    pub fn insert(&mut self, key: u32, val: BTreeNode){
-        if(self.map.contains_key(&key)){
-            self.map.remove(&key);
-        } else if (self.map.len() == 10){
-            self.map.pop_back();
-        } 
+    /// Adds the page to the pool as the most recently used one, replacing any older copy. A page that is
+    /// already dirty stays dirty. Returns the dirty pages evicted to make room, which must be written to disk.
+    pub fn insert(&mut self, key: u32, val: BTreeNode, dirty: bool) -> Vec<BTreeNode> {
+        let dirty = match self.frames.remove(&key) {
+            Some(frame) => frame.dirty || dirty,
+            None => dirty,
+        };
 
-        self.map.insert(key, val);
+        let evicted = self.evict(self.capacity.saturating_sub(1));
+        let last_used = AtomicU64::new(self.tick());
+        self.frames.insert(key, Frame { page: Arc::new(RwLock::new(val)), dirty, last_used });
+        evicted
     }
 
-    pub fn get(&self, key: u32) -> &BTreeNode{
-        self.map.get(&key).unwrap()
+    /// Returns the page and marks it as the most recently used one.
+    pub fn get(&self, key: u32) -> Option<PageLatch> {
+        self.frames.get(&key).map(|frame| {
+            frame.last_used.store(self.tick(), Ordering::Relaxed);
+            frame.page.clone()
+        })
     }
 
-    pub fn get_mut(&mut self, key: u32) -> Option<&mut BTreeNode>{
-        self.map.get_mut(&key)
+    pub fn contains_key(&self, key: u32) -> bool {
+        self.frames.contains_key(&key)
     }
 
+    /// Returns whether the page is in the pool and has changed since it was last written to disk.
+    pub fn is_dirty(&self, key: u32) -> bool {
+        self.frames.get(&key).is_some_and(|frame| frame.dirty)
+    }
 
-    pub fn contains_key(&self, key: u32) -> bool {
-        self.map.contains_key(&key)
+    /// Marks the page as changed, once a writer has replaced the node behind its latch.
+    pub fn mark_dirty(&mut self, key: u32) {
+        if let Some(frame) = self.frames.get_mut(&key) {
+            frame.dirty = true;
+        }
+    }
+
+    /// Takes the page out of the pool. Returns the page and whether it was dirty.
+    pub fn remove(&mut self, key: u32) -> Option<(PageLatch, bool)> {
+        self.frames.remove(&key).map(|frame| (frame.page, frame.dirty))
+    }
+
+    /// Returns every page that has changed since it was last written to disk.
+    pub fn dirty_pages(&self) -> Vec<PageLatch> {
+        self.frames.values().filter(|frame| frame.dirty).map(|frame| frame.page.clone()).collect()
     }
 
-    pub fn remove(&mut self, key: u32) -> BTreeNode {
-        self.map.remove(&key).unwrap()
+    /// Marks every page as clean once the dirty pages have been written to disk.
+    pub fn mark_clean(&mut self) {
+        for (_, frame) in self.frames.iter_mut() {
+            frame.dirty = false;
+        }
     }
 
+    /// Returns the next stamp.
+    fn tick(&self) -> u64 {
+        self.clock.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// Evicts the least recently used pages nobody else holds until at most target pages are left,
+    /// or only pages in use are. Returns the evicted pages that were dirty.
+    fn evict(&mut self, target: usize) -> Vec<BTreeNode> {
+        let mut evicted = Vec::new();
+
+        while self.frames.len() > target {
+            let victim = self.frames.iter()
+                .filter(|(_, frame)| Arc::strong_count(&frame.page) == 1)
+                .min_by_key(|(_, frame)| frame.last_used.load(Ordering::Relaxed))
+                .map(|(&key, _)| key);
+
+            let frame = match victim.and_then(|key| self.frames.remove(&key)) {
+                Some(frame) => frame,
+                None => break,
+            };
+
+            if frame.dirty {
+                // Nobody else holds the page, so the pool's reference is the only one left.
+                let node = match Arc::try_unwrap(frame.page) {
+                    Ok(page) => page.into_inner().unwrap_or_else(|err| err.into_inner()),
+                    Err(page) => latch::read(&page).clone(),
+                };
+                evicted.push(node);
+            }
+        }
+
+        evicted
+    }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn page(id: u32) -> BTreeNode {
+        let mut node = BTreeNode::new();
+        node.id = id;
+        node
+    }
+
+    #[test]
+    fn test_get_promotes_page() {
+        let mut pool = BufferPool::new(2);
+        pool.insert(1, page(1), false);
+        pool.insert(2, page(2), false);
+
+        // Page 1 becomes the most recently used page, so page 2 is the one evicted.
+        assert!(pool.get(1).is_some());
+        pool.insert(3, page(3), false);
+
+        assert!(pool.contains_key(1));
+        assert!(!pool.contains_key(2));
+        assert!(pool.contains_key(3));
+
+        // Page 3 was added after page 1 was used, then page 3 is used again after page 4 was added.
+        pool.insert(4, page(4), false);
+        assert!(!pool.contains_key(1));
+        assert!(pool.get(3).is_some());
+        pool.insert(5, page(5), false);
+        assert!(pool.contains_key(3));
+        assert!(!pool.contains_key(4));
+    }
+
+    #[test]
+    fn test_evicted_dirty_pages_are_returned() {
+        let mut pool = BufferPool::new(2);
+        assert!(pool.insert(1, page(1), true).is_empty());
+        assert!(pool.insert(2, page(2), false).is_empty());
+
+        let evicted = pool.insert(3, page(3), false);
+        assert_eq!(evicted.len(), 1);
+        assert_eq!(evicted[0].id, 1);
+
+        // Clean pages are dropped without being handed back.
+        assert!(pool.insert(4, page(4), false).is_empty());
+        assert!(!pool.contains_key(2) && pool.contains_key(3) && pool.contains_key(4));
+    }
+
+    #[test]
+    fn test_pages_in_use_are_not_evicted() {
+        let mut pool = BufferPool::new(2);
+        pool.insert(1, page(1), true);
+        pool.insert(2, page(2), true);
+        let first = pool.get(1).unwrap();
+        let second = pool.get(2).unwrap();
+
+        // Every page is in use, so the pool grows past its capacity instead.
+        assert!(pool.insert(3, page(3), false).is_empty());
+        assert!(pool.contains_key(1) && pool.contains_key(2) && pool.contains_key(3));
+
+        drop(first);
+        let evicted = pool.insert(4, page(4), false);
+        assert_eq!(evicted.iter().map(|node| node.id).collect::<Vec<_>>(), vec![1]);
+        assert!(pool.contains_key(2));
+        assert!(!pool.contains_key(3));
+        drop(second);
+    }
+
+    #[test]
+    fn test_reinserted_page_keeps_dirty_flag() {
+        let mut pool = BufferPool::new(4);
+        pool.insert(1, page(1), true);
+
+        let (page_1, dirty) = pool.remove(1).unwrap();
+        assert!(dirty);
+        pool.insert(1, latch::read(&page_1).clone(), false);
+        assert_eq!(pool.dirty_pages().len(), 0);
+
+        pool.insert(2, page(2), true);
+        pool.insert(2, page(2), false);
+        assert_eq!(pool.dirty_pages().len(), 1);
+
+        assert!(pool.is_dirty(2));
+
+        pool.mark_clean();
+        assert_eq!(pool.dirty_pages().len(), 0);
+        assert!(!pool.is_dirty(2));
+
+        pool.mark_dirty(1);
+        assert!(pool.is_dirty(1));
+    }
+}
@@ -0,0 +1,380 @@
+use btree::error::{DbError, Result};
+use btree::meta::META_PAGE_ID;
+use btree::node::NO_SIBLING;
+use btree::tree::BTree;
+use btree::wal::{self, WalOp};
+
+use std::collections::HashSet;
+use std::fmt;
+
+/// Outcome of an integrity check of a database file and its WAL, returned by check().
+///
+/// Problems are damage to the file that recovery cannot repair. Warnings describe a state recovery deals with,
+/// such as a torn WAL tail or operations that have not been checkpointed yet.
+#[derive(Clone, Debug, Default)]
+pub struct CheckReport {
+    /// Number of pages in the file, including the meta page.
+    pub pages: u32,
+    /// Pages reachable from the root.
+    pub reachable_pages: usize,
+    /// Pages on the free list, including the trunk pages holding it.
+    pub free_pages: usize,
+    pub leaves: usize,
+    /// Number of levels of the tree, a root leaf being one level.
+    pub depth: usize,
+    pub keys: u64,
+    /// Intact records in the WAL.
+    pub wal_records: usize,
+    pub problems: Vec<String>,
+    pub warnings: Vec<String>,
+}
+
+impl CheckReport {
+    /// Returns whether no problems were found. Warnings do not count as problems.
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+impl fmt::Display for CheckReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "pages: {} ({} in the tree, {} free)", self.pages, self.reachable_pages, self.free_pages)?;
+        writeln!(f, "tree: depth {}, {} leaves, {} keys", self.depth, self.leaves, self.keys)?;
+        writeln!(f, "wal: {} records", self.wal_records)?;
+
+        for warning in &self.warnings {
+            writeln!(f, "warning: {}", warning)?;
+        }
+        for problem in &self.problems {
+            writeln!(f, "problem: {}", problem)?;
+        }
+
+        match self.problems.len() {
+            0 => write!(f, "no problems found"),
+            count => write!(f, "{} problems found", count),
+        }
+    }
+}
+
+/// A node still to be checked, along with the range its keys must fall into: greater than lower and less than
+/// or equal to upper, following the separator invariant of the parent.
+struct Visit {
+    page_id: u32,
+    lower: Option<Vec<u8>>,
+    upper: Option<Vec<u8>>,
+    depth: usize,
+}
+
+/// Checks the tree and WAL of an opened, not yet recovered, database without changing either.
+///
+/// Every page reachable from the root is read and checked for key order, separator bounds, matching key,
+/// value and child counts, and leaves all being at the same depth. Reading a page already verifies its
+/// checksum and that its id matches its position in the file. Afterwards the leaf sibling links are compared
+/// with the order the leaves were found in, and every page must be either reachable or free. Finally the WAL
+/// is checked against the checkpoint recorded in the meta page.
+///
+/// Only I/O errors are returned as errors, any damage found is recorded in the report.
+pub fn check(tree: &BTree) -> Result<CheckReport> {
+    let pages = tree.pages();
+    let page_count = pages.page_count();
+    let mut report = CheckReport { pages: page_count, free_pages: pages.free_count(), ..CheckReport::default() };
+
+    let mut reachable = HashSet::new();
+    let mut leaves: Vec<(u32, u32, u32)> = Vec::new();
+    let mut leaf_depth = None;
+    let mut unreadable = false;
+    let mut stack = vec![Visit { page_id: tree.root(), lower: None, upper: None, depth: 1 }];
+
+    while let Some(visit) = stack.pop() {
+        let page_id = visit.page_id;
+
+        if page_id == META_PAGE_ID || page_id >= page_count {
+            report.problems.push(format!("page {} is referenced by the tree but is not a node page", page_id));
+            continue;
+        }
+        if !reachable.insert(page_id) {
+            report.problems.push(format!("page {} is reachable more than once", page_id));
+            continue;
+        }
+        if pages.is_free(page_id) {
+            report.problems.push(format!("page {} is in use but on the free list", page_id));
+        }
+
+        let node = match tree.node(page_id) {
+            Ok(node) => node,
+            Err(DbError::Corruption { .. }) => {
+                report.problems.push(format!("page {} cannot be read as a node", page_id));
+                unreadable = true;
+                continue;
+            },
+            Err(err) => return Err(err),
+        };
+
+        let num_keys = usize::from(node.num_keys);
+        let expected_children = if node.is_leaf() { 0 } else { num_keys + 1 };
+        let expected_vals = if node.is_leaf() { num_keys } else { 0 };
+
+        if node.keys.len() != num_keys || node.vals.len() != expected_vals || node.children.len() != expected_children {
+            report.problems.push(format!("page {} has num_keys {} but holds {} keys, {} values and {} children",
+                page_id, num_keys, node.keys.len(), node.vals.len(), node.children.len()));
+        }
+        if node.keys.windows(2).any(|pair| pair[0] >= pair[1]) {
+            report.problems.push(format!("page {} has keys out of order", page_id));
+        }
+
+        let below_lower = |key: &Vec<u8>| visit.lower.as_ref().is_some_and(|lower| key <= lower);
+        let above_upper = |key: &Vec<u8>| visit.upper.as_ref().is_some_and(|upper| key > upper);
+
+        if node.keys.iter().any(|key| below_lower(key) || above_upper(key)) {
+            report.problems.push(format!("page {} has keys outside the range given by its parent's separators", page_id));
+        }
+
+        if node.is_leaf() {
+            match leaf_depth {
+                None => leaf_depth = Some(visit.depth),
+                Some(depth) if depth != visit.depth => report.problems.push(format!(
+                    "leaf {} is at depth {} while other leaves are at depth {}", page_id, visit.depth, depth)),
+                Some(_) => (),
+            }
+
+            leaves.push((page_id, node.prev_leaf, node.next_leaf));
+            report.keys += num_keys as u64;
+            continue;
+        }
+
+        if num_keys == 0 {
+            report.problems.push(format!("internal page {} has no keys", page_id));
+        }
+
+        // Push the children right to left so they are checked, and the leaves found, in key order.
+        for (i, &child) in node.children.iter().enumerate().rev() {
+            stack.push(Visit {
+                page_id: child,
+                lower: if i == 0 { visit.lower.clone() } else { node.keys.get(i - 1).cloned() },
+                upper: node.keys.get(i).cloned().or_else(|| visit.upper.clone()),
+                depth: visit.depth + 1,
+            });
+        }
+    }
+
+    report.reachable_pages = reachable.len();
+    report.leaves = leaves.len();
+    report.depth = leaf_depth.unwrap_or(0);
+
+    // Sibling links can only be compared with the key order if every leaf was found.
+    if !unreadable {
+        for (i, &(page_id, prev_leaf, next_leaf)) in leaves.iter().enumerate() {
+            let expected_prev = if i == 0 { NO_SIBLING } else { leaves[i - 1].0 };
+            let expected_next = leaves.get(i + 1).map_or(NO_SIBLING, |next| next.0);
+
+            if prev_leaf != expected_prev || next_leaf != expected_next {
+                report.problems.push(format!("leaf {} links to {} and {} but its neighbours are {} and {}",
+                    page_id, prev_leaf, next_leaf, expected_prev, expected_next));
+            }
+        }
+    }
+
+    for page_id in 1..page_count {
+        if !reachable.contains(&page_id) && !pages.is_free(page_id) {
+            report.problems.push(format!("page {} is neither reachable from the root nor free", page_id));
+        }
+    }
+
+    check_wal(tree, &mut report)?;
+    Ok(report)
+}
+
+/// Checks that the WAL is intact and agrees with the checkpoint recorded in the meta page.
+fn check_wal(tree: &BTree, report: &mut CheckReport) -> Result<()> {
+    let records = tree.read_from_wal()?;
+    let intact: u64 = records.iter().map(|record| record.serialize().len() as u64).sum();
+    let checkpoint_lsn = tree.checkpoint_lsn();
+
+    report.wal_records = records.len();
+
+    if intact < tree.wal_len() {
+        report.warnings.push(format!("the WAL ends with {} bytes of torn or corrupt records, which recovery will discard",
+            tree.wal_len() - intact));
+    }
+
+    for record in records.iter().filter(|record| record.op == WalOp::Checkpoint && record.lsn > checkpoint_lsn) {
+        report.problems.push(format!("the WAL has a checkpoint at LSN {} but the data file was last checkpointed at LSN {}",
+            record.lsn, checkpoint_lsn));
+    }
+
+    let (replay, discarded) = wal::replayable(&records, checkpoint_lsn);
+    let pending = replay.len();
+
+    if discarded > 0 {
+        report.warnings.push(format!("the WAL ends with an uncommitted transaction of {} operations, which recovery will discard",
+            discarded));
+    }
+
+    if pending > 0 {
+        report.warnings.push(format!("the WAL holds {} operations that were not checkpointed, opening the database will replay them",
+            pending));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::check;
+    use btree::meta::{Meta, META_SIZE, NO_PAGE};
+    use btree::node::BTreeNode;
+    use btree::iter::Pages;
+    use btree::key::EncodeKey;
+    use btree::test_files::{create_files, remove_files};
+    use btree::tree::BTree;
+    use btree::wal::{WalOp, WalRecord};
+    use options::Options;
+    use std::fs::OpenOptions;
+    use std::io::{Read, Seek, SeekFrom, Write};
+    use std::ops::Bound;
+
+    fn create_tree(name: &str) -> (BTree, String, String) {
+        let (file_path, wal_path) = create_files(&format!("check_{}", name));
+        let tree = BTree::open(&file_path, &wal_path, &Options::default()).unwrap();
+        for i in 0..2000u32 {
+            tree.write(&i, &[1u8; 20]).unwrap();
+        }
+        for i in (0..2000u32).filter(|i| i % 3 == 0 || (500..1500).contains(i)) {
+            tree.delete(&i).unwrap();
+        }
+        tree.flush().unwrap();
+
+        (tree, file_path, wal_path)
+    }
+
+    #[test]
+    fn test_healthy_database() {
+        let (tree, file_path, wal_path) = create_tree("healthy");
+        tree.write(b"pending", b"value").unwrap();
+
+        let report = check(&tree).unwrap();
+        assert!(report.is_ok(), "{}", report);
+        let kept = (0..2000u32).filter(|i| i % 3 != 0 && !(500..1500).contains(i)).count();
+        assert_eq!(report.keys, kept as u64 + 1);
+        assert!(report.depth >= 2);
+        assert!(report.free_pages > 0);
+        assert_eq!(report.reachable_pages + report.free_pages + 1, report.pages as usize);
+        assert_eq!(report.warnings.len(), 1);
+
+        remove_files(&file_path, &wal_path);
+    }
+
+    #[test]
+    fn test_out_of_order_keys() {
+        let (tree, file_path, wal_path) = create_tree("order");
+        let mut snapshot = tree.snapshot();
+        let leaf_id = snapshot.find_leaf(Bound::Included(&1u32.encode_key()), false).unwrap();
+        let node = snapshot.page(leaf_id).unwrap().clone();
+
+        let mut keys = node.keys.clone();
+        keys.swap(0, 1);
+        let mut leaf = BTreeNode::new_from_params(leaf_id, 1, node.num_keys, keys, Vec::new(), node.vals.clone());
+        leaf.prev_leaf = node.prev_leaf;
+        leaf.next_leaf = node.next_leaf;
+        drop(snapshot);
+        drop(tree);
+
+        let mut file = OpenOptions::new().read(true).write(true).open(&file_path).unwrap();
+        leaf.write_node_to_file(&mut file, 4096).unwrap();
+
+        let tree = BTree::open(&file_path, &wal_path, &Options::default()).unwrap();
+        let report = check(&tree).unwrap();
+        assert!(!report.is_ok());
+        assert!(report.problems.iter().any(|problem| problem.contains("out of order")), "{}", report);
+
+        remove_files(&file_path, &wal_path);
+    }
+
+    #[test]
+    fn test_orphaned_pages() {
+        let (tree, file_path, wal_path) = create_tree("orphans");
+        let free_pages = tree.pages().free_count();
+        drop(tree);
+
+        // Forget the free list, leaving every free page unaccounted for.
+        let mut file = OpenOptions::new().read(true).write(true).open(&file_path).unwrap();
+        let mut buf = vec![0u8; META_SIZE];
+        file.read_exact(&mut buf).unwrap();
+        let mut meta = Meta::deserialize(&buf).unwrap();
+        meta.free_list_head = NO_PAGE;
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.write_all(&meta.serialize()).unwrap();
+
+        let tree = BTree::open(&file_path, &wal_path, &Options::default()).unwrap();
+        let report = check(&tree).unwrap();
+        assert_eq!(report.problems.len(), free_pages, "{}", report);
+        assert!(report.problems.iter().all(|problem| problem.contains("neither reachable")));
+
+        remove_files(&file_path, &wal_path);
+    }
+
+    #[test]
+    fn test_torn_wal() {
+        let (tree, file_path, wal_path) = create_tree("torn_wal");
+        tree.write(b"pending", b"value").unwrap();
+        drop(tree);
+
+        // Cut the last record short, as a crash in the middle of appending it would.
+        let wal = OpenOptions::new().write(true).open(&wal_path).unwrap();
+        let len = wal.metadata().unwrap().len();
+        wal.set_len(len - 3).unwrap();
+
+        let tree = BTree::open(&file_path, &wal_path, &Options::default()).unwrap();
+        let report = check(&tree).unwrap();
+        assert!(report.is_ok(), "{}", report);
+        assert_eq!(report.wal_records, 1);
+        assert_eq!(report.warnings.len(), 1, "{}", report);
+        assert!(report.warnings[0].contains("torn or corrupt"));
+
+        remove_files(&file_path, &wal_path);
+    }
+
+    #[test]
+    fn test_stale_checkpoint_lsn() {
+        let (tree, file_path, wal_path) = create_tree("stale_checkpoint");
+        drop(tree);
+
+        // Roll the meta page back to before the checkpoint the WAL records.
+        let mut file = OpenOptions::new().read(true).write(true).open(&file_path).unwrap();
+        let mut buf = vec![0u8; META_SIZE];
+        file.read_exact(&mut buf).unwrap();
+        let mut meta = Meta::deserialize(&buf).unwrap();
+        meta.checkpoint_lsn -= 1;
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.write_all(&meta.serialize()).unwrap();
+
+        let tree = BTree::open(&file_path, &wal_path, &Options::default()).unwrap();
+        let report = check(&tree).unwrap();
+        assert_eq!(report.problems.len(), 1, "{}", report);
+        assert!(report.problems[0].contains(&format!("checkpoint at LSN {}", meta.checkpoint_lsn + 1)));
+
+        remove_files(&file_path, &wal_path);
+    }
+
+    #[test]
+    fn test_uncommitted_transaction() {
+        let (tree, file_path, wal_path) = create_tree("uncommitted");
+        let lsn = tree.checkpoint_lsn();
+        drop(tree);
+
+        // A transaction whose Commit record never made it to the WAL.
+        let mut wal = OpenOptions::new().append(true).open(&wal_path).unwrap();
+        wal.write_all(&WalRecord::new(lsn + 1, WalOp::Begin, b"", b"").serialize()).unwrap();
+        wal.write_all(&WalRecord::new(lsn + 2, WalOp::Put, b"a", b"1").serialize()).unwrap();
+        wal.write_all(&WalRecord::new(lsn + 3, WalOp::Delete, b"b", b"").serialize()).unwrap();
+
+        let tree = BTree::open(&file_path, &wal_path, &Options::default()).unwrap();
+        let report = check(&tree).unwrap();
+        assert!(report.is_ok(), "{}", report);
+        assert_eq!(report.wal_records, 4);
+        assert_eq!(report.warnings.len(), 1, "{}", report);
+        assert!(report.warnings[0].contains("uncommitted transaction of 2 operations"));
+
+        remove_files(&file_path, &wal_path);
+    }
+}
@@ -0,0 +1,85 @@
+//! CRC-32C (Castagnoli) checksums used to detect torn or corrupted data on disk. 
+
+const POLYNOMIAL: u32 = 0x82F6_3B78;
+
+/// Lookup table with the CRC of every possible byte, built at compile time. 
+const TABLE: [u32; 256] = build_table();
+
+const fn build_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+
+        while bit < 8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ POLYNOMIAL } else { crc >> 1 };
+            bit += 1;
+        }
+
+        table[i] = crc;
+        i += 1;
+    }
+
+    table
+}
+
+/// Returns the CRC-32C checksum of the bytes.
+pub fn crc32c(bytes: &[u8]) -> u32 {
+    !update(!0u32, bytes)
+}
+
+/// Stores the checksum of a page in the 4 bytes at offset. The checksum covers the whole page, with the
+/// checksum itself counted as zero.
+pub fn seal_page(page: &mut [u8], offset: usize) {
+    let crc = page_checksum(page, offset);
+    page[offset..offset + 4].copy_from_slice(&crc.to_le_bytes());
+}
+
+/// Returns whether the checksum stored at offset matches the contents of the page.
+pub fn verify_page(page: &[u8], offset: usize) -> bool {
+    page.len() >= offset + 4 && page[offset..offset + 4] == page_checksum(page, offset).to_le_bytes()
+}
+
+fn page_checksum(page: &[u8], offset: usize) -> u32 {
+    let crc = update(!0u32, &page[..offset]);
+    let crc = update(crc, &[0u8; 4]);
+    !update(crc, &page[offset + 4..])
+}
+
+fn update(mut crc: u32, bytes: &[u8]) -> u32 {
+    for &byte in bytes {
+        crc = TABLE[((crc ^ u32::from(byte)) & 0xFF) as usize] ^ (crc >> 8);
+    }
+
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{crc32c, seal_page, verify_page};
+
+    #[test]
+    fn test_known_values() {
+        assert_eq!(crc32c(b""), 0);
+        assert_eq!(crc32c(b"123456789"), 0xE306_9283);
+        assert_eq!(crc32c(&[0u8; 32]), 0x8A91_36AA);
+    }
+
+    #[test]
+    fn test_sealed_page() {
+        let mut page = vec![7u8; 64];
+        seal_page(&mut page, 8);
+        assert!(verify_page(&page, 8));
+
+        // The checksum is the same as for the page with a zeroed checksum field.
+        let mut zeroed = page.clone();
+        zeroed[8..12].copy_from_slice(&[0; 4]);
+        assert_eq!(page[8..12], crc32c(&zeroed).to_le_bytes());
+
+        page[40] ^= 1;
+        assert!(!verify_page(&page, 8));
+        assert!(!verify_page(&page[..10], 8));
+    }
+}
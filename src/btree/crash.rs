@@ -0,0 +1,266 @@
+//! Crash injection for the recovery tests.
+//!
+//! Every write, truncation and sync of a DbFile is a point a crash can hit. Once armed on a thread, the injector
+//! counts those points and crashes at the one it was asked to: that write or sync fails, or with tear set the
+//! write only makes it halfway, and every write and sync after it fails too, as nothing runs after a crash.
+//!
+//! A crash of the process leaves the files as they were at that point. A power loss also takes away writes that
+//! were not synced yet, so the injector keeps each file as it was when it was last synced, and
+//! Run::lose_unsynced() puts those copies back. The disk may have made some of the unsynced writes and not
+//! others, so the tests lose those of every file on its own as well as those of all of them.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek};
+use std::path::{Path, PathBuf};
+
+thread_local! {
+    static INJECTOR: RefCell<Option<Injector>> = const { RefCell::new(None) };
+}
+
+struct Injector {
+    /// The point to crash at, counting from 1. None only counts the points.
+    crash_at: Option<u64>,
+    tear: bool,
+    points: u64,
+    crashed: bool,
+    /// Contents of every file written since it was last synced, as they were right after that sync.
+    synced: HashMap<PathBuf, Vec<u8>>,
+}
+
+/// What a run with the injector armed did to the files.
+pub(crate) struct Run {
+    /// Number of writes and syncs made, including the one crashed at.
+    pub(crate) points: u64,
+    pub(crate) crashed: bool,
+    synced: HashMap<PathBuf, Vec<u8>>,
+}
+
+impl Run {
+    /// Files written since they were last synced.
+    pub(crate) fn unsynced(&self) -> Vec<PathBuf> {
+        self.synced.keys().cloned().collect()
+    }
+
+    /// Puts the file back the way it was last synced, as a power loss taking away its unsynced writes would.
+    pub(crate) fn lose_unsynced(&self, path: &Path) {
+        fs::write(path, &self.synced[path]).unwrap();
+    }
+}
+
+/// Starts counting the writes and syncs made on this thread and crashes at the point crash_at, if there is one.
+pub(crate) fn arm(crash_at: Option<u64>, tear: bool) {
+    let injector = Injector { crash_at, tear, points: 0, crashed: false, synced: HashMap::new() };
+    INJECTOR.with(|cell| *cell.borrow_mut() = Some(injector));
+}
+
+/// Stops the injector and returns what happened since it was armed.
+pub(crate) fn disarm() -> Run {
+    let injector = INJECTOR.with(|cell| cell.borrow_mut().take()).expect("the crash injector is not armed");
+    Run { points: injector.points, crashed: injector.crashed, synced: injector.synced }
+}
+
+/// Called before len bytes are written to the file at path, or before it is truncated with a len of zero.
+/// Returns how many of the bytes to write.
+pub(crate) fn before_write(file: &File, path: &Path, len: usize) -> io::Result<usize> {
+    INJECTOR.with(|cell| {
+        let mut cell = cell.borrow_mut();
+        let injector = match *cell {
+            Some(ref mut injector) => injector,
+            None => return Ok(len),
+        };
+
+        if injector.crashed {
+            return Err(crashed());
+        }
+
+        if !injector.synced.contains_key(path) {
+            injector.synced.insert(path.to_path_buf(), contents(file)?);
+        }
+
+        injector.points += 1;
+        if injector.crash_at != Some(injector.points) {
+            return Ok(len);
+        }
+
+        injector.crashed = true;
+        match injector.tear && len > 1 {
+            true => Ok(len / 2),
+            false => Err(crashed()),
+        }
+    })
+}
+
+/// Called before the file at path is synced.
+pub(crate) fn before_sync(path: &Path) -> io::Result<()> {
+    INJECTOR.with(|cell| {
+        let mut cell = cell.borrow_mut();
+        let injector = match *cell {
+            Some(ref mut injector) => injector,
+            None => return Ok(()),
+        };
+
+        if injector.crashed {
+            return Err(crashed());
+        }
+
+        injector.points += 1;
+        if injector.crash_at == Some(injector.points) {
+            injector.crashed = true;
+            return Err(crashed());
+        }
+
+        injector.synced.remove(path);
+        Ok(())
+    })
+}
+
+fn contents(mut file: &File) -> io::Result<Vec<u8>> {
+    let position = file.stream_position()?;
+    let mut buf = Vec::new();
+
+    file.rewind()?;
+    file.read_to_end(&mut buf)?;
+    file.seek(io::SeekFrom::Start(position))?;
+    Ok(buf)
+}
+
+fn crashed() -> io::Error {
+    io::Error::other("crashed")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{arm, disarm, Run};
+    use btree::check;
+    use btree::test_files::{create_files, remove_files};
+    use btree::tree::BTree;
+    use options::Options;
+    use std::collections::BTreeMap;
+
+    const KEYS: u32 = 32;
+
+    #[derive(Clone, Copy, Debug)]
+    enum Op {
+        Put(u32, u8),
+        Delete(u32),
+        Flush,
+    }
+
+    /// Writes enough to split leaves and the root, takes checkpoints both when the WAL fills up and through
+    /// flush(), and evicts dirty pages from a buffer pool that only holds a few of them.
+    fn workload() -> Vec<Op> {
+        let mut ops: Vec<Op> = (0..24).map(|i| Op::Put(i, 1)).collect();
+        ops.push(Op::Flush);
+        ops.extend((0..24).step_by(3).map(Op::Delete));
+        ops.extend((12..KEYS).map(|i| Op::Put(i, 2)));
+        ops.push(Op::Flush);
+        ops
+    }
+
+    fn options() -> Options {
+        Options { page_size: 512, cache_size: 4, checkpoint_wal_size: Some(1024), ..Options::default() }
+    }
+
+    fn key(i: u32) -> Vec<u8> {
+        format!("key{:03}", i).into_bytes()
+    }
+
+    fn apply(state: &mut BTreeMap<u32, u8>, op: Op) {
+        match op {
+            Op::Put(i, val) => { state.insert(i, val); },
+            Op::Delete(i) => { state.remove(&i); },
+            Op::Flush => (),
+        }
+    }
+
+    /// Runs the workload on a new database until it is done or an operation fails. Returns what the run did to
+    /// the files, the state made of every acknowledged operation and the operation that failed, if one did.
+    fn run(file_path: &str, wal_path: &str, crash_at: Option<u64>, tear: bool) -> (Run, BTreeMap<u32, u8>, Option<Op>) {
+        remove_files(file_path, wal_path);
+        let tree = BTree::open(file_path, wal_path, &options()).unwrap();
+
+        let mut acked = BTreeMap::new();
+        let mut failed = None;
+        arm(crash_at, tear);
+
+        for op in workload() {
+            let result = match op {
+                Op::Put(i, val) => tree.write(&key(i)[..], &[val; 40]),
+                Op::Delete(i) => tree.delete(&key(i)[..]),
+                Op::Flush => tree.flush(),
+            };
+
+            match result {
+                Ok(()) => apply(&mut acked, op),
+                Err(_) => {
+                    failed = Some(op);
+                    break;
+                },
+            }
+        }
+
+        drop(tree);
+        (disarm(), acked, failed)
+    }
+
+    /// Reopens the database and checks that it holds every acknowledged operation. The operation the crash hit
+    /// may or may not have made it, but nothing else can show up.
+    fn check_recovered(file_path: &str, wal_path: &str, acked: &BTreeMap<u32, u8>, failed: Option<Op>, context: &str) {
+        let tree = BTree::open(file_path, wal_path, &Options::default()).unwrap_or_else(|err| panic!("{}: open failed: {}", context, err));
+        tree.recover().unwrap_or_else(|err| panic!("{}: recover failed: {}", context, err));
+
+        let mut found = BTreeMap::new();
+        for i in 0..KEYS {
+            if let Some(val) = tree.read(&key(i)[..]).unwrap() {
+                assert_eq!(val.len(), 40, "{}: value of key {}", context, i);
+                found.insert(i, val[0]);
+            }
+        }
+
+        let mut with_failed = acked.clone();
+        if let Some(op) = failed {
+            apply(&mut with_failed, op);
+        }
+        assert!(found == *acked || found == with_failed, "{}: found {:?}, acknowledged {:?}, failed {:?}", context,
+            found, acked, failed);
+
+        let report = check::check(&tree).unwrap();
+        assert!(report.is_ok(), "{}: {}", context, report);
+        assert_eq!(report.keys, found.len() as u64, "{}", context);
+    }
+
+    #[test]
+    fn test_crash_at_every_point() {
+        let (file_path, wal_path) = create_files("crash_every_point");
+
+        let (clean, acked, failed) = run(&file_path, &wal_path, None, false);
+        assert!(!clean.crashed && failed.is_none());
+        check_recovered(&file_path, &wal_path, &acked, None, "no crash");
+
+        for crash_at in 1..=clean.points {
+            for &tear in &[false, true] {
+                let (run_once, _, _) = run(&file_path, &wal_path, Some(crash_at), tear);
+
+                // Nothing lost, every unsynced write lost, and the unsynced writes of each file lost on their own.
+                let unsynced = run_once.unsynced();
+                let mut losses = vec![Vec::new(), unsynced.clone()];
+                losses.extend(unsynced.into_iter().map(|path| vec![path]));
+
+                for lost in losses {
+                    let context = format!("crash at {} of {}, tear {}, lost {:?}", crash_at, clean.points, tear, lost);
+                    let (run, acked, failed) = run(&file_path, &wal_path, Some(crash_at), tear);
+                    assert!(run.crashed && failed.is_some(), "{}", context);
+
+                    for path in &lost {
+                        run.lose_unsynced(path);
+                    }
+                    check_recovered(&file_path, &wal_path, &acked, failed, &context);
+                }
+            }
+        }
+
+        remove_files(&file_path, &wal_path);
+    }
+}
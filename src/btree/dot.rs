@@ -0,0 +1,166 @@
+use btree::dump::escape;
+use btree::error::{DbError, Result};
+use btree::node::NO_SIBLING;
+use btree::tree::BTree;
+
+use std::collections::{HashMap, HashSet, VecDeque};
+
+/// Keys longer than this many bytes are cut short in the graph, so large keys do not blow up the page records.
+const MAX_LABEL_KEY: usize = 16;
+
+/// Fill colours of pages that are dirty in the buffer pool, cached but clean, and only on disk.
+const DIRTY_COLOR: &str = "salmon";
+const CACHED_COLOR: &str = "lightblue";
+const DISK_COLOR: &str = "white";
+
+impl BTree {
+    /// Renders the tree as a Graphviz DOT graph, to be drawn with e.g. `dot -Tsvg`.
+    ///
+    /// Every node reachable from the root becomes a record showing its page id and keys, with an edge from the
+    /// slot between two keys to the child holding that range. Leaves are linked to their siblings by dashed
+    /// edges. Pages are coloured by their state in the buffer pool before the call: dirty pages in salmon,
+    /// cached pages in light blue and pages only on disk in white. Pages that cannot be read are drawn in red.
+    ///
+    /// Walking the tree reads its pages through the buffer pool, so drawing it may evict and write back pages.
+    pub fn to_dot(&self) -> Result<String> {
+        let mut lines = vec![
+            "digraph btree {".to_string(),
+            "    node [shape=record, style=filled];".to_string(),
+        ];
+        let mut edges = Vec::new();
+        let mut seen = HashSet::new();
+        let mut queue = VecDeque::new();
+        queue.push_back(self.root());
+
+        // Taken before the walk, which loads and evicts pages itself.
+        let colors: HashMap<u32, &str> = {
+            let pool = self.pager().pool();
+            (0..self.pages().page_count())
+                .filter(|&page_id| pool.contains_key(page_id))
+                .map(|page_id| (page_id, if pool.is_dirty(page_id) { DIRTY_COLOR } else { CACHED_COLOR }))
+                .collect()
+        };
+
+        while let Some(page_id) = queue.pop_front() {
+            if !seen.insert(page_id) {
+                continue;
+            }
+
+            let color = colors.get(&page_id).copied().unwrap_or(DISK_COLOR);
+
+            let node = match self.node(page_id) {
+                Ok(node) => node,
+                Err(DbError::Corruption { .. }) => {
+                    lines.push(format!("    page{} [label=\"page {}|corrupt\", fillcolor=red];", page_id, page_id));
+                    continue;
+                },
+                Err(err) => return Err(err),
+            };
+
+            let keys: Vec<String> = node.keys.iter().map(|key| label_key(key)).collect();
+            let slots = match node.is_leaf() {
+                true => keys.join("|"),
+                false => {
+                    // Children and keys alternate, each child slot being a port its edge starts from.
+                    let mut slots = vec!["<c0>".to_string()];
+                    for (i, key) in keys.iter().enumerate() {
+                        slots.push(key.clone());
+                        slots.push(format!("<c{}>", i + 1));
+                    }
+                    slots.join("|")
+                },
+            };
+            lines.push(format!("    page{} [label=\"{{page {}|{{{}}}}}\", fillcolor={}];", page_id, page_id, slots, color));
+
+            for (i, &child) in node.children.iter().enumerate() {
+                edges.push(format!("    page{}:c{} -> page{};", page_id, i, child));
+                queue.push_back(child);
+            }
+            if node.is_leaf() && node.next_leaf != NO_SIBLING {
+                edges.push(format!("    page{} -> page{} [style=dashed, constraint=false];", page_id, node.next_leaf));
+            }
+            if node.is_leaf() && node.prev_leaf != NO_SIBLING {
+                edges.push(format!("    page{} -> page{} [style=dotted, constraint=false];", page_id, node.prev_leaf));
+            }
+        }
+
+        lines.extend(edges);
+        lines.push("}".to_string());
+        Ok(lines.join("\n"))
+    }
+}
+
+/// Escapes the key for a record label, where braces, bars, angle brackets and quotes have a meaning of their own.
+fn label_key(key: &[u8]) -> String {
+    let escaped = escape(&key[..key.len().min(MAX_LABEL_KEY)]);
+    let mut label = String::new();
+
+    for c in escaped.chars() {
+        if "{}|<>\"\\ ".contains(c) {
+            label.push('\\');
+        }
+        label.push(c);
+    }
+    if key.len() > MAX_LABEL_KEY {
+        label.push_str("...");
+    }
+    label
+}
+
+#[cfg(test)]
+mod tests {
+    use btree::test_files::{create_files, remove_files};
+    use btree::tree::BTree;
+    use options::Options;
+
+    #[test]
+    fn test_to_dot() {
+        let (file_path, wal_path) = create_files("dot");
+
+        let tree = BTree::open(&file_path, &wal_path, &Options::default()).unwrap();
+        for i in 0..300u32 {
+            tree.write(format!("key{:03}", i).as_bytes(), &[7u8; 20]).unwrap();
+        }
+        tree.flush().unwrap();
+        tree.write(b"key000", b"changed").unwrap();
+
+        let dot = tree.to_dot().unwrap();
+        let root = tree.root();
+        assert!(dot.starts_with("digraph btree {"));
+        assert!(dot.ends_with("}"));
+        assert!(dot.contains(&format!("page{} [label=\"{{page {}|{{<c0>|", root, root)), "{}", dot);
+        assert!(dot.contains(&format!("page{}:c1 -> page", root)));
+        assert!(dot.contains("[style=dashed, constraint=false]"));
+
+        // Only the leaf holding the changed key is dirty, the pages touched on the way to it are cached.
+        assert_eq!(dot.matches("fillcolor=salmon").count(), 1);
+        assert!(dot.contains("\\\"key000\\\"|\\\"key001\\\""));
+        assert!(dot.lines().any(|line| line.contains(&format!("page{} [", root)) && line.contains("fillcolor=lightblue")));
+
+        remove_files(&file_path, &wal_path);
+    }
+
+    #[test]
+    fn test_colors_are_taken_before_the_walk() {
+        let (file_path, wal_path) = create_files("dot_colors");
+        let options = Options { page_size: 512, cache_size: 4, ..Options::default() };
+        {
+            let tree = BTree::open(&file_path, &wal_path, &options).unwrap();
+            for i in 0..300u32 {
+                tree.write(format!("key{:03}", i).as_bytes(), &[7u8; 20]).unwrap();
+            }
+            tree.flush().unwrap();
+        }
+
+        // Only the root and the last leaf are cached, and the walk evicts that leaf before it gets there.
+        let tree = BTree::open(&file_path, &wal_path, &options).unwrap();
+        tree.read(b"key299").unwrap();
+
+        let dot = tree.to_dot().unwrap();
+        let leaf = dot.lines().find(|line| line.contains("\\\"key299\\\"")).unwrap();
+        assert!(leaf.contains("fillcolor=lightblue"), "{}", leaf);
+        assert_eq!(dot.matches("fillcolor=lightblue").count(), 2);
+
+        remove_files(&file_path, &wal_path);
+    }
+}
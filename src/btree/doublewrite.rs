@@ -0,0 +1,364 @@
+use btree::checksum::crc32c;
+use btree::error::Result;
+use btree::file::DbFile;
+use btree::store::PageStore;
+
+use std::convert::TryInto;
+use std::ffi::OsString;
+use std::fs::OpenOptions;
+use std::io::{self, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+
+/// Marks the start of a batch in the double-write file.
+const BATCH_MAGIC: [u8; 4] = *b"DBLW";
+
+/// Bytes used by the header of a batch: magic (4), checksum (4), number of writes (4) and file length (8).
+const BATCH_HEADER_SIZE: usize = 20;
+
+/// Bytes used by the header of each write in a batch: offset (8) and length (4).
+const WRITE_HEADER_SIZE: usize = 12;
+
+/// Writes to the data file collected so they can be made together through the double-write file.
+///
+/// Anything that knows how to write itself to a file can write into it instead, every write is kept along
+/// with the position it was made at.
+#[derive(Default)]
+pub(crate) struct PageWrites {
+    writes: Vec<(u64, Vec<u8>)>,
+    position: u64,
+    /// Length to truncate the data file to once the writes are made.
+    len: Option<u64>,
+}
+
+impl PageWrites {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Truncates the data file to len bytes after the writes are made.
+    pub(crate) fn set_len(&mut self, len: u64) {
+        self.len = Some(len);
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.writes.is_empty() && self.len.is_none()
+    }
+}
+
+impl Write for PageWrites {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writes.push((self.position, buf.to_vec()));
+        self.position += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for PageWrites {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        match pos {
+            io::SeekFrom::Start(position) => {
+                self.position = position;
+                Ok(position)
+            },
+            _ => Err(io::Error::new(io::ErrorKind::Unsupported, "page writes can only seek from the start")),
+        }
+    }
+}
+
+/// Protects the data file against torn pages, pages half overwritten by a crash in the middle of a write.
+///
+/// Pages are updated in place, and the WAL only holds the keys and values written, so a torn page cannot be
+/// rebuilt from it. Every batch of writes to the data file is therefore written to the double-write file and
+/// synced first, and only then made in place. Once the data file is synced too, the double-write file is
+/// emptied. If a crash hits while the pages are written in place, the double-write file still holds an intact
+/// copy of all of them, and restore() puts them back when the database is opened again. If it hits while the
+/// batch itself is written, its checksum does not match and the data file was not touched yet.
+///
+/// A batch is laid out as
+///
+/// | magic (4) | checksum (4) | count (4) | file length (8) | count times: offset (8) | length (4) | bytes |
+///
+/// where the checksum covers everything after itself and a file length of zero leaves the length alone.
+///
+/// Stores that cannot tear a page, like a MemoryStore, go without a double-write file and have their pages
+/// written in place right away.
+pub(crate) struct DoubleWrite {
+    /// Missing for a read-only database without a double-write file, and for writes made in place.
+    file: Option<DbFile>,
+    sync: bool,
+}
+
+impl DoubleWrite {
+    /// Opens the double-write file at path, creating it unless read_only is set. Batches are only synced
+    /// when sync is set.
+    pub(crate) fn open<P: AsRef<Path>>(path: P, read_only: bool, sync: bool) -> Result<Self> {
+        let file = DbFile::open(path, OpenOptions::new()
+            .read(true)
+            .write(!read_only)
+            .create(!read_only)
+            .truncate(false));
+
+        match file {
+            Ok(file) => Ok(Self { file: Some(file), sync }),
+            Err(ref err) if read_only && err.kind() == io::ErrorKind::NotFound => Ok(Self { file: None, sync }),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Makes the writes in place, without a double-write file. Writes are only synced when sync is set.
+    pub(crate) fn in_place(sync: bool) -> Self {
+        Self { file: None, sync }
+    }
+
+    /// Makes the writes to the data file, going through the double-write file first if there is one.
+    pub(crate) fn write(&mut self, data: &mut dyn PageStore, writes: &PageWrites) -> Result<()> {
+        if writes.is_empty() {
+            return Ok(());
+        }
+
+        let file = match self.file {
+            Some(ref mut file) => file,
+            None => {
+                apply(data, &writes.writes, writes.len)?;
+                if self.sync {
+                    data.sync()?;
+                }
+                return Ok(());
+            },
+        };
+
+        file.set_len(0)?;
+        file.rewind()?;
+        file.write_all(&serialize(writes))?;
+        if self.sync {
+            file.sync_data()?;
+        }
+
+        apply(data, &writes.writes, writes.len)?;
+        if self.sync {
+            data.sync()?;
+        }
+
+        file.set_len(0)?;
+        Ok(())
+    }
+
+    /// Returns the writes of the batch left behind by a crash that do not match the data file, which are the
+    /// pages that were torn or not written yet. A batch that is cut short or fails its checksum is ignored.
+    pub(crate) fn damaged(&mut self, data: &mut dyn PageStore) -> Result<Vec<(u64, Vec<u8>)>> {
+        match self.read_batch()? {
+            Some(batch) => unmatched(data, batch.writes),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Puts back the pages of the batch left behind by a crash and empties the double-write file. Returns the
+    /// number of pages that had to be restored.
+    pub(crate) fn restore(&mut self, data: &mut dyn PageStore) -> Result<usize> {
+        let batch = match self.read_batch()? {
+            Some(batch) => batch,
+            None => return Ok(0),
+        };
+
+        let damaged = unmatched(data, batch.writes)?;
+        apply(data, &damaged, batch.len)?;
+        data.sync()?;
+
+        if let Some(ref mut file) = self.file {
+            file.set_len(0)?;
+        }
+
+        Ok(damaged.len())
+    }
+
+    fn read_batch(&mut self) -> Result<Option<PageWrites>> {
+        let mut buf = Vec::new();
+
+        if let Some(ref mut file) = self.file {
+            file.rewind()?;
+            file.read_to_end(&mut buf)?;
+        }
+
+        Ok(deserialize(&buf))
+    }
+}
+
+/// Path of the double-write file of the database at path, which is the path with ".dwb" appended.
+pub(crate) fn path_for(path: &Path) -> PathBuf {
+    let mut dwb_path = OsString::from(path.as_os_str());
+    dwb_path.push(".dwb");
+    PathBuf::from(dwb_path)
+}
+
+/// Returns the writes whose bytes differ from what the data file holds at their offset.
+fn unmatched(data: &mut dyn PageStore, writes: Vec<(u64, Vec<u8>)>) -> Result<Vec<(u64, Vec<u8>)>> {
+    let data_len = data.len()?;
+    let mut damaged = Vec::new();
+
+    for (offset, bytes) in writes {
+        let mut page = vec![0u8; bytes.len()];
+
+        if offset + bytes.len() as u64 <= data_len {
+            data.read_page(offset, &mut page)?;
+        }
+
+        if page != bytes {
+            damaged.push((offset, bytes));
+        }
+    }
+
+    Ok(damaged)
+}
+
+fn apply(data: &mut dyn PageStore, writes: &[(u64, Vec<u8>)], len: Option<u64>) -> Result<()> {
+    for &(offset, ref bytes) in writes {
+        data.write_page(offset, bytes)?;
+    }
+
+    if let Some(len) = len {
+        data.truncate(len)?;
+    }
+
+    Ok(())
+}
+
+/// Serializes the writes into a batch for the double-write file.
+pub(crate) fn serialize(writes: &PageWrites) -> Vec<u8> {
+    let mut buffer = Vec::new();
+
+    buffer.extend_from_slice(&BATCH_MAGIC);
+    buffer.extend_from_slice(&[0u8; 4]);
+    buffer.extend_from_slice(&(writes.writes.len() as u32).to_le_bytes());
+    buffer.extend_from_slice(&writes.len.unwrap_or(0).to_le_bytes());
+
+    for &(offset, ref bytes) in &writes.writes {
+        buffer.extend_from_slice(&offset.to_le_bytes());
+        buffer.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+        buffer.extend_from_slice(bytes);
+    }
+
+    let crc = crc32c(&buffer[8..]);
+    buffer[4..8].copy_from_slice(&crc.to_le_bytes());
+    buffer
+}
+
+fn deserialize(buf: &[u8]) -> Option<PageWrites> {
+    if buf.len() < BATCH_HEADER_SIZE || buf[0..4] != BATCH_MAGIC || crc32c(&buf[8..]) != read_u32(buf, 4) {
+        return None;
+    }
+
+    let count = read_u32(buf, 8);
+    let len = u64::from_le_bytes(buf[12..20].try_into().unwrap());
+    let mut writes = PageWrites { len: if len == 0 { None } else { Some(len) }, ..PageWrites::new() };
+    let mut offset = BATCH_HEADER_SIZE;
+
+    for _ in 0..count {
+        if offset + WRITE_HEADER_SIZE > buf.len() {
+            return None;
+        }
+
+        let position = u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap());
+        let size = read_u32(buf, offset + 8) as usize;
+        offset += WRITE_HEADER_SIZE;
+
+        let bytes = buf.get(offset..offset + size)?;
+        writes.writes.push((position, bytes.to_vec()));
+        offset += size;
+    }
+
+    Some(writes)
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use btree::test_files::{create_files, remove_files};
+    use std::fs;
+
+    fn open_files(name: &str) -> (DbFile, DoubleWrite, String, String) {
+        let (file_path, wal_path) = create_files(&format!("dwb_{}", name));
+        let data = DbFile::open(&file_path, OpenOptions::new().read(true).write(true).create(true).truncate(true)).unwrap();
+        let double_write = DoubleWrite::open(path_for(Path::new(&file_path)), false, true).unwrap();
+        (data, double_write, file_path, wal_path)
+    }
+
+    /// Leaves bytes in the double-write file, as a crash right after writing them would.
+    fn leave_batch(double_write: &mut DoubleWrite, bytes: &[u8]) {
+        let file = double_write.file.as_mut().unwrap();
+        file.rewind().unwrap();
+        file.write_all(bytes).unwrap();
+    }
+
+    fn page_writes(fill: u8) -> PageWrites {
+        let mut writes = PageWrites::new();
+        for page_id in 0..4u64 {
+            writes.seek(io::SeekFrom::Start(page_id * 512)).unwrap();
+            writes.write_all(&[fill + page_id as u8; 512]).unwrap();
+        }
+        writes
+    }
+
+    #[test]
+    fn test_write_empties_the_double_write_file() {
+        let (mut data, mut double_write, file_path, wal_path) = open_files("write");
+
+        double_write.write(&mut data, &page_writes(1)).unwrap();
+        assert_eq!(data.len().unwrap(), 2048);
+        assert_eq!(fs::metadata(path_for(Path::new(&file_path))).unwrap().len(), 0);
+        assert_eq!(double_write.restore(&mut data).unwrap(), 0);
+
+        remove_files(&file_path, &wal_path);
+    }
+
+    #[test]
+    fn test_torn_pages_are_restored() {
+        let (mut data, mut double_write, file_path, wal_path) = open_files("torn");
+        double_write.write(&mut data, &page_writes(1)).unwrap();
+
+        // A crash after the batch was synced, while its pages were being written in place: the first page
+        // made it, the second one is torn and the others still hold their old contents.
+        let mut batch = page_writes(10);
+        batch.set_len(1536);
+        leave_batch(&mut double_write, &serialize(&batch));
+        data.seek(io::SeekFrom::Start(0)).unwrap();
+        data.write_all(&[10u8; 512]).unwrap();
+        data.write_all(&[11u8; 200]).unwrap();
+
+        assert_eq!(double_write.damaged(&mut data).unwrap().len(), 3);
+        assert_eq!(double_write.restore(&mut data).unwrap(), 3);
+
+        let mut contents = Vec::new();
+        data.rewind().unwrap();
+        data.read_to_end(&mut contents).unwrap();
+        assert_eq!(contents.len(), 1536);
+        for (page_id, page) in contents.chunks(512).enumerate() {
+            assert!(page.iter().all(|&byte| byte == 10 + page_id as u8));
+        }
+
+        remove_files(&file_path, &wal_path);
+    }
+
+    #[test]
+    fn test_torn_batch_is_ignored() {
+        let (mut data, mut double_write, file_path, wal_path) = open_files("torn_batch");
+        double_write.write(&mut data, &page_writes(1)).unwrap();
+
+        // The crash hit while the batch itself was written, so the data file was never touched.
+        let batch = serialize(&page_writes(10));
+        leave_batch(&mut double_write, &batch[..batch.len() - 100]);
+
+        assert!(double_write.damaged(&mut data).unwrap().is_empty());
+        assert_eq!(double_write.restore(&mut data).unwrap(), 0);
+        assert_eq!(data.len().unwrap(), 2048);
+
+        remove_files(&file_path, &wal_path);
+    }
+}
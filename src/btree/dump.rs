@@ -0,0 +1,208 @@
+use btree::error::{DbError, Result};
+use btree::meta::{Meta, META_PAGE_ID};
+use btree::node::BTreeNode;
+use btree::tree::BTree;
+
+use std::ascii;
+use std::collections::{HashSet, VecDeque};
+
+/// How dump_page() and dump_tree() print pages.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DumpFormat {
+    /// One line per page followed by its entries, with keys and values shown as escaped byte strings.
+    Text,
+    /// One JSON object per page, with keys and values shown as hex strings.
+    Json,
+}
+
+/// What a page of the file holds, as far as the dump is concerned.
+enum Page {
+    Meta(Meta),
+    Free,
+    Node(BTreeNode),
+    /// A page that should hold a node but fails its checksum or cannot be decoded.
+    Corrupt,
+    /// A page id past the end of the file.
+    Missing,
+}
+
+/// Describes the page at page_id. Node pages are decoded through BTree::deserialize, the meta page and pages
+/// on the free list are described as such. Damaged pages are reported in the output rather than as errors.
+pub fn dump_page(tree: &BTree, page_id: u32, format: DumpFormat) -> Result<String> {
+    let meta = if page_id == META_PAGE_ID { Some(tree.read_meta()?) } else { None };
+    let pages = tree.pages();
+
+    let page = match meta {
+        Some(meta) => Page::Meta(meta),
+        None if page_id >= pages.page_count() => Page::Missing,
+        None if pages.is_free(page_id) => Page::Free,
+        None => match tree.node(page_id) {
+            Ok(node) => Page::Node(node),
+            Err(DbError::Corruption { .. }) => Page::Corrupt,
+            Err(err) => return Err(err),
+        },
+    };
+
+    Ok(match format {
+        DumpFormat::Text => page_text(page_id, &page),
+        DumpFormat::Json => page_json(page_id, &page),
+    })
+}
+
+/// Describes every node reachable from the root, level by level starting at the root, and each level left to right.
+pub fn dump_tree(tree: &BTree, format: DumpFormat) -> Result<String> {
+    let pages = tree.pages();
+    let mut levels: Vec<Vec<String>> = Vec::new();
+    let mut seen = HashSet::new();
+    let mut queue = VecDeque::new();
+    queue.push_back((tree.root(), 0));
+
+    while let Some((page_id, level)) = queue.pop_front() {
+        // A damaged tree may point at a page more than once, so each page is only dumped and followed once.
+        if !seen.insert(page_id) {
+            continue;
+        }
+
+        if levels.len() == level {
+            levels.push(Vec::new());
+        }
+        levels[level].push(dump_page(tree, page_id, format)?);
+
+        if page_id == META_PAGE_ID || page_id >= pages.page_count() || pages.is_free(page_id) {
+            continue;
+        }
+        if let Ok(node) = tree.node(page_id) {
+            for &child in &node.children {
+                queue.push_back((child, level + 1));
+            }
+        }
+    }
+
+    Ok(match format {
+        DumpFormat::Text => {
+            let lines: Vec<String> = levels.iter().enumerate()
+                .map(|(level, pages)| format!("level {}\n{}", level, pages.join("\n")))
+                .collect();
+            lines.join("\n")
+        },
+        DumpFormat::Json => {
+            let levels: Vec<String> = levels.iter().map(|pages| format!("[{}]", pages.join(","))).collect();
+            format!("{{\"root\":{},\"levels\":[{}]}}", tree.root(), levels.join(","))
+        },
+    })
+}
+
+fn page_text(page_id: u32, page: &Page) -> String {
+    match *page {
+        Page::Meta(ref meta) => format!(
+            "page {}: meta, version {}, page size {}, root {}, {} pages, free list {}, checkpoint lsn {}",
+            page_id, meta.version, meta.page_size, meta.root, meta.page_count, meta.free_list_head, meta.checkpoint_lsn),
+        Page::Free => format!("page {}: free", page_id),
+        Page::Corrupt => format!("page {}: corrupt", page_id),
+        Page::Missing => format!("page {}: past the end of the file", page_id),
+        Page::Node(ref node) if node.is_leaf() => {
+            let mut text = format!("page {}: leaf, {} keys, prev {}, next {}",
+                page_id, node.num_keys, node.prev_leaf, node.next_leaf);
+            for (key, val) in node.keys.iter().zip(&node.vals) {
+                text.push_str(&format!("\n  {} => {}", escape(key), escape(val)));
+            }
+            text
+        },
+        Page::Node(ref node) => {
+            let keys: Vec<String> = node.keys.iter().map(|key| escape(key)).collect();
+            let children: Vec<String> = node.children.iter().map(u32::to_string).collect();
+            format!("page {}: internal, {} keys\n  keys: {}\n  children: {}",
+                page_id, node.num_keys, keys.join(", "), children.join(", "))
+        },
+    }
+}
+
+fn page_json(page_id: u32, page: &Page) -> String {
+    match *page {
+        Page::Meta(ref meta) => format!(
+            "{{\"page\":{},\"type\":\"meta\",\"version\":{},\"page_size\":{},\"root\":{},\"page_count\":{},\"free_list_head\":{},\"checkpoint_lsn\":{}}}",
+            page_id, meta.version, meta.page_size, meta.root, meta.page_count, meta.free_list_head, meta.checkpoint_lsn),
+        Page::Free => format!("{{\"page\":{},\"type\":\"free\"}}", page_id),
+        Page::Corrupt => format!("{{\"page\":{},\"type\":\"corrupt\"}}", page_id),
+        Page::Missing => format!("{{\"page\":{},\"type\":\"missing\"}}", page_id),
+        Page::Node(ref node) => {
+            let keys: Vec<String> = node.keys.iter().map(|key| format!("\"{}\"", hex(key))).collect();
+            let entries = match node.is_leaf() {
+                true => {
+                    let vals: Vec<String> = node.vals.iter().map(|val| format!("\"{}\"", hex(val))).collect();
+                    format!("\"prev_leaf\":{},\"next_leaf\":{},\"keys\":[{}],\"vals\":[{}]",
+                        node.prev_leaf, node.next_leaf, keys.join(","), vals.join(","))
+                },
+                false => {
+                    let children: Vec<String> = node.children.iter().map(u32::to_string).collect();
+                    format!("\"keys\":[{}],\"children\":[{}]", keys.join(","), children.join(","))
+                },
+            };
+            format!("{{\"page\":{},\"type\":\"{}\",\"num_keys\":{},{}}}",
+                page_id, if node.is_leaf() { "leaf" } else { "internal" }, node.num_keys, entries)
+        },
+    }
+}
+
+/// Quotes the bytes, escaping anything that is not printable ASCII as in a Rust byte string.
+pub(crate) fn escape(bytes: &[u8]) -> String {
+    let escaped: String = bytes.iter().flat_map(|&byte| ascii::escape_default(byte)).map(char::from).collect();
+    format!("\"{}\"", escaped)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use options::Options;
+    use btree::test_files::{create_files, remove_files};
+
+    fn create_tree(name: &str, count: u32) -> (BTree, String, String) {
+        let (file_path, wal_path) = create_files(&format!("dump_{}", name));
+        let tree = BTree::open(&file_path, &wal_path, &Options::default()).unwrap();
+        for i in 0..count {
+            tree.write(format!("key{:04}", i).as_bytes(), b"v\x01").unwrap();
+        }
+        tree.flush().unwrap();
+
+        (tree, file_path, wal_path)
+    }
+
+    #[test]
+    fn test_dump_leaf_and_meta() {
+        let (tree, file_path, wal_path) = create_tree("leaf", 2);
+
+        let text = dump_page(&tree, 1, DumpFormat::Text).unwrap();
+        assert_eq!(text, "page 1: leaf, 2 keys, prev 0, next 0\n  \"key0000\" => \"v\\x01\"\n  \"key0001\" => \"v\\x01\"");
+
+        let json = dump_page(&tree, 1, DumpFormat::Json).unwrap();
+        assert_eq!(json, "{\"page\":1,\"type\":\"leaf\",\"num_keys\":2,\"prev_leaf\":0,\"next_leaf\":0,\
+            \"keys\":[\"6b657930303030\",\"6b657930303031\"],\"vals\":[\"7601\",\"7601\"]}");
+
+        let meta = dump_page(&tree, 0, DumpFormat::Text).unwrap();
+        assert!(meta.starts_with("page 0: meta, version 2, page size 4096, root 1, 2 pages"), "{}", meta);
+        assert_eq!(dump_page(&tree, 9, DumpFormat::Json).unwrap(), "{\"page\":9,\"type\":\"missing\"}");
+
+        remove_files(&file_path, &wal_path);
+    }
+
+    #[test]
+    fn test_dump_tree_level_by_level() {
+        let (tree, file_path, wal_path) = create_tree("levels", 1000);
+        let root = tree.root();
+
+        let text = dump_tree(&tree, DumpFormat::Text).unwrap();
+        assert!(text.starts_with(&format!("level 0\npage {}: internal", root)), "{}", text);
+        assert!(text.contains("\nlevel 1\n"));
+        assert_eq!(text.lines().filter(|line| line.starts_with("  \"key")).count(), 1000);
+
+        let json = dump_tree(&tree, DumpFormat::Json).unwrap();
+        assert!(json.starts_with(&format!("{{\"root\":{},\"levels\":[[{{\"page\":{},\"type\":\"internal\"", root, root)), "{}", json);
+        assert_eq!(json.matches("\"type\":\"leaf\"").count(), text.matches(": leaf,").count());
+
+        remove_files(&file_path, &wal_path);
+    }
+}
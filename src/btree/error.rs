@@ -0,0 +1,74 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+/// Errors returned by the database. The engine never panics or prints on failure, every problem is 
+/// handed back to the caller through one of these variants. 
+#[derive(Debug)]
+pub enum DbError {
+    /// Reading from or writing to the database file or WAL failed. 
+    Io(io::Error),
+    /// A page read from the disk does not hold a valid node. 
+    Corruption { page_id: u32 },
+    /// The file is not a database this version can open. 
+    InvalidDatabase(String),
+    /// The file was written in a format version this build cannot read.
+    UnsupportedVersion { found: u32, supported: u32 },
+    /// The options passed to Db::open() cannot be used. 
+    InvalidOptions(String),
+    /// The key and value together exceed the largest entry a page can hold. 
+    KeyTooLarge { size: usize, max: usize },
+    /// The key does not exist in the database. 
+    NotFound,
+    /// A node was asked for a field it does not have. 
+    InvalidField(String),
+    /// Another handle, in this process or another one, holds a lock on the database that conflicts with opening it.
+    Locked,
+    /// The database was opened read-only and the operation would change it.
+    ReadOnly,
+    /// The WAL holds operations that were not checkpointed, or the double-write file holds pages a crash left
+    /// torn, which a read-only handle cannot recover.
+    RecoveryNeeded { pending: usize },
+    /// A node grew past the page it is stored in, which the tree should never let happen.
+    PageOverflow { page_id: u32, size: usize, page_size: usize },
+}
+
+/// Result type used throughout the database. 
+pub type Result<T> = ::std::result::Result<T, DbError>;
+
+impl fmt::Display for DbError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DbError::Io(ref err) => write!(f, "I/O error: {}", err),
+            DbError::Corruption { page_id } => write!(f, "page {} is corrupted", page_id),
+            DbError::InvalidDatabase(ref reason) => write!(f, "invalid database file: {}", reason),
+            DbError::UnsupportedVersion { found, supported } =>
+                write!(f, "database format version {} is not supported, expected version {}", found, supported),
+            DbError::InvalidOptions(ref reason) => write!(f, "invalid options: {}", reason),
+            DbError::KeyTooLarge { size, max } => write!(f, "entry of {} bytes exceeds the maximum of {} bytes", size, max),
+            DbError::NotFound => write!(f, "key not found"),
+            DbError::InvalidField(ref field) => write!(f, "invalid node field: {}", field),
+            DbError::Locked => write!(f, "database is locked by another handle"),
+            DbError::ReadOnly => write!(f, "database is opened read-only"),
+            DbError::RecoveryNeeded { pending } =>
+                write!(f, "{} operations or pages have to be recovered, open the database read-write first", pending),
+            DbError::PageOverflow { page_id, size, page_size } =>
+                write!(f, "node {} takes up {} bytes, more than the page size of {}", page_id, size, page_size),
+        }
+    }
+}
+
+impl Error for DbError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            DbError::Io(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for DbError {
+    fn from(err: io::Error) -> Self {
+        DbError::Io(err)
+    }
+}
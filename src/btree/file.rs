@@ -0,0 +1,151 @@
+#[cfg(test)]
+use btree::crash;
+use btree::store::{LogStore, PageStore};
+
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{self, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+
+/// One of the files of a database: the data file, the WAL, the double-write file or the spill file.
+///
+/// Every write, truncation and sync of them goes through here, which is what lets the crash tests stop the
+/// I/O of a database at any of those points and look at what it leaves behind on disk. It is the file-backed
+/// PageStore and LogStore, a log being appended to if the file was opened for appending.
+pub struct DbFile {
+    file: File,
+    path: PathBuf,
+}
+
+impl DbFile {
+    pub fn open<P: AsRef<Path>>(path: P, options: &OpenOptions) -> io::Result<Self> {
+        let path = path.as_ref();
+        Ok(Self { file: options.open(path)?, path: path.to_path_buf() })
+    }
+
+    pub(crate) fn len(&self) -> io::Result<u64> {
+        Ok(self.file.metadata()?.len())
+    }
+
+    pub(crate) fn set_len(&self, len: u64) -> io::Result<()> {
+        self.before_write(0)?;
+        self.file.set_len(len)
+    }
+
+    pub(crate) fn sync_data(&self) -> io::Result<()> {
+        self.before_sync()?;
+        self.file.sync_data()
+    }
+
+    pub(crate) fn sync_all(&self) -> io::Result<()> {
+        self.before_sync()?;
+        self.file.sync_all()
+    }
+
+    pub(crate) fn try_lock(&self) -> Result<(), TryLockError> {
+        self.file.try_lock()
+    }
+
+    pub(crate) fn try_lock_shared(&self) -> Result<(), TryLockError> {
+        self.file.try_lock_shared()
+    }
+
+    /// Returns how many bytes of a write of len bytes to make. Outside of the crash tests that is all of them.
+    #[cfg(not(test))]
+    fn before_write(&self, len: usize) -> io::Result<usize> {
+        Ok(len)
+    }
+
+    #[cfg(test)]
+    fn before_write(&self, len: usize) -> io::Result<usize> {
+        crash::before_write(&self.file, &self.path, len)
+    }
+
+    #[cfg(not(test))]
+    fn before_sync(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    #[cfg(test)]
+    fn before_sync(&self) -> io::Result<()> {
+        crash::before_sync(&self.path)
+    }
+}
+
+impl Read for DbFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+
+impl Write for DbFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.before_write(buf.len())?;
+
+        if len < buf.len() {
+            self.file.write_all(&buf[..len])?;
+            return Err(io::Error::new(io::ErrorKind::WriteZero, format!("write to {} was cut short", self.path.display())));
+        }
+
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Seek for DbFile {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        self.file.seek(pos)
+    }
+}
+
+impl PageStore for DbFile {
+    fn read_page(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.seek(io::SeekFrom::Start(offset))?;
+        self.read_exact(buf)
+    }
+
+    fn write_page(&mut self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        self.seek(io::SeekFrom::Start(offset))?;
+        self.write_all(buf)
+    }
+
+    /// Syncs the length of the file along with its contents, as pages are added and dropped at the end.
+    fn sync(&mut self) -> io::Result<()> {
+        self.sync_all()
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        DbFile::len(self)
+    }
+
+    fn truncate(&mut self, len: u64) -> io::Result<()> {
+        self.set_len(len)
+    }
+}
+
+impl LogStore for DbFile {
+    fn append(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.write_all(buf)
+    }
+
+    fn read_all(&mut self) -> io::Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        self.rewind()?;
+        self.read_to_end(&mut buf)?;
+        Ok(buf)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.sync_data()
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        DbFile::len(self)
+    }
+
+    fn truncate(&mut self, len: u64) -> io::Result<()> {
+        self.set_len(len)
+    }
+}
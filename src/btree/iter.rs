@@ -0,0 +1,224 @@
+use btree::error::Result;
+use btree::node::{BTreeNode, NO_SIBLING};
+
+use std::ops::Bound;
+
+/// The pages a Range walks over, those of a snapshot of the tree.
+pub(crate) trait Pages {
+    /// Page id of the root node.
+    fn root(&self) -> u32;
+
+    fn page(&mut self, page_id: u32) -> Result<&BTreeNode>;
+
+    /// Descends from the root to the leaf that holds the key (or would hold it if it were inserted).
+    ///
+    /// Unbounded searches go to the leftmost leaf for an unbounded start, or the rightmost leaf for an unbounded end.
+    fn find_leaf(&mut self, key: Bound<&[u8]>, rightmost: bool) -> Result<u32> {
+        let mut offset = self.root();
+
+        loop {
+            let cur_node = self.page(offset)?;
+
+            if cur_node.is_leaf() {
+                return Ok(offset);
+            }
+
+            offset = match key {
+                Bound::Included(key) | Bound::Excluded(key) => cur_node.children[cur_node.search(key)],
+                Bound::Unbounded if rightmost => cur_node.children[cur_node.children.len() - 1],
+                Bound::Unbounded => cur_node.children[0],
+            };
+        }
+    }
+}
+
+impl<P: Pages + ?Sized> Pages for &mut P {
+    fn root(&self) -> u32 {
+        (**self).root()
+    }
+
+    fn page(&mut self, page_id: u32) -> Result<&BTreeNode> {
+        (**self).page(page_id)
+    }
+}
+
+/// Iterator over the key-value pairs of a range of keys, returned by BTree::range() and Snapshot::range(). 
+/// 
+/// Each end of the range keeps a cursor made of a leaf id and an index into that leaf. The cursors are only 
+/// positioned (by descending from the root) the first time that end is used, and afterwards move across leaves 
+/// through the prev/next sibling links stored in the leaf header. Every key handed out by one end tightens the 
+/// bound checked by the other end, so the two cursors never hand out the same key twice. 
+/// 
+/// Pages are read from the disk as the cursors reach them, so each item is a Result. The iterator ends after 
+/// returning an error. 
+pub struct Range<'a> {
+    tree: Box<dyn Pages + 'a>,
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+
+    /// Leaf and index of the next key returned by next(). 
+    front: Option<(u32, usize)>,
+    /// Leaf and index one past the next key returned by next_back(). 
+    back: Option<(u32, usize)>,
+    done: bool,
+}
+
+impl<'a> Range<'a> {
+    pub(crate) fn new(tree: Box<dyn Pages + 'a>, start: Bound<Vec<u8>>, end: Bound<Vec<u8>>) -> Self {
+        Self { tree, start, end, front: None, back: None, done: false }
+    }
+
+    /// Places the front cursor at the first key that is within the start bound. 
+    fn seek_front(&mut self) -> Result<(u32, usize)> {
+        let leaf_id = self.tree.find_leaf(as_slice(&self.start), false)?;
+        let node = self.tree.page(leaf_id)?;
+
+        let index = match self.start {
+            Bound::Included(ref key) => node.search(key),
+            Bound::Excluded(ref key) => {
+                let index = node.search(key);
+                match node.keys.get(index) {
+                    Some(found) if found == key => index + 1,
+                    _ => index,
+                }
+            },
+            Bound::Unbounded => 0,
+        };
+
+        Ok((leaf_id, index))
+    }
+
+    /// Places the back cursor one past the last key that is within the end bound. 
+    fn seek_back(&mut self) -> Result<(u32, usize)> {
+        let leaf_id = self.tree.find_leaf(as_slice(&self.end), true)?;
+        let node = self.tree.page(leaf_id)?;
+
+        let index = match self.end {
+            Bound::Included(ref key) => {
+                let index = node.search(key);
+                match node.keys.get(index) {
+                    Some(found) if found == key => index + 1,
+                    _ => index,
+                }
+            },
+            Bound::Excluded(ref key) => node.search(key),
+            Bound::Unbounded => node.keys.len(),
+        };
+
+        Ok((leaf_id, index))
+    }
+
+    /// Returns the next pair from the front of the range, or None once the range is exhausted. 
+    fn next_pair(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        let (mut leaf_id, mut index) = match self.front {
+            Some(cursor) => cursor,
+            None => self.seek_front()?,
+        };
+
+        loop {
+            let node = self.tree.page(leaf_id)?;
+
+            if index < node.keys.len() {
+                let key = node.keys[index].clone();
+
+                if !before_end(&key, &self.end) {
+                    return Ok(None);
+                }
+
+                let val = node.vals[index].clone();
+                self.front = Some((leaf_id, index + 1));
+                self.start = Bound::Excluded(key.clone());
+                return Ok(Some((key, val)));
+            }
+
+            if node.next_leaf == NO_SIBLING {
+                return Ok(None);
+            }
+
+            leaf_id = node.next_leaf;
+            index = 0;
+        }
+    }
+
+    /// Returns the next pair from the back of the range, or None once the range is exhausted. 
+    fn next_back_pair(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        let (mut leaf_id, mut index) = match self.back {
+            Some(cursor) => cursor,
+            None => self.seek_back()?,
+        };
+
+        loop {
+            let node = self.tree.page(leaf_id)?;
+
+            if index > 0 {
+                let key = node.keys[index - 1].clone();
+
+                if !after_start(&key, &self.start) {
+                    return Ok(None);
+                }
+
+                let val = node.vals[index - 1].clone();
+                self.back = Some((leaf_id, index - 1));
+                self.end = Bound::Excluded(key.clone());
+                return Ok(Some((key, val)));
+            }
+
+            if node.prev_leaf == NO_SIBLING {
+                return Ok(None);
+            }
+
+            leaf_id = node.prev_leaf;
+            index = self.tree.page(leaf_id)?.keys.len();
+        }
+    }
+}
+
+impl<'a> Iterator for Range<'a> {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let next = self.next_pair().transpose();
+        self.done = !matches!(next, Some(Ok(_)));
+        next
+    }
+}
+
+impl<'a> DoubleEndedIterator for Range<'a> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let next = self.next_back_pair().transpose();
+        self.done = !matches!(next, Some(Ok(_)));
+        next
+    }
+}
+
+fn as_slice(bound: &Bound<Vec<u8>>) -> Bound<&[u8]> {
+    match *bound {
+        Bound::Included(ref key) => Bound::Included(key),
+        Bound::Excluded(ref key) => Bound::Excluded(key),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+fn before_end(key: &[u8], end: &Bound<Vec<u8>>) -> bool {
+    match *end {
+        Bound::Included(ref end) => key <= &end[..],
+        Bound::Excluded(ref end) => key < &end[..],
+        Bound::Unbounded => true,
+    }
+}
+
+fn after_start(key: &[u8], start: &Bound<Vec<u8>>) -> bool {
+    match *start {
+        Bound::Included(ref start) => key >= &start[..],
+        Bound::Excluded(ref start) => key > &start[..],
+        Bound::Unbounded => true,
+    }
+}
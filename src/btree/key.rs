@@ -0,0 +1,208 @@
+/// Order-preserving encoding of typed keys into the byte strings stored in the B-Tree. 
+/// 
+/// Nodes compare keys with a plain byte-wise (memcmp) comparison, so every encoding here is chosen so that 
+/// `a < b` holds exactly when `a.encode_key() < b.encode_key()`. This keeps range queries working no matter 
+/// what type the user picks for their keys. 
+pub trait EncodeKey {
+    /// Appends the encoded key to the buffer. 
+    fn encode_into(&self, buf: &mut Vec<u8>);
+
+    /// Appends a self-delimiting version of the encoded key, used when the key is one element of a tuple. 
+    /// Fixed-width types are already self-delimiting, so by default this is the same as encode_into(). 
+    fn encode_part_into(&self, buf: &mut Vec<u8>) {
+        self.encode_into(buf);
+    }
+
+    /// Returns the encoded key as a new byte string. 
+    fn encode_key(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        self.encode_into(&mut buf);
+        buf
+    }
+}
+
+/// Writes the bytes terminated by 0x00 0x01, escaping each 0x00 as 0x00 0xFF. The terminator sorts below 
+/// any escaped or regular byte, so a shorter string still orders before every string it is a prefix of. 
+fn encode_escaped(bytes: &[u8], buf: &mut Vec<u8>) {
+    for &byte in bytes {
+        buf.push(byte);
+        if byte == 0x00 {
+            buf.push(0xFF);
+        }
+    }
+    buf.extend_from_slice(&[0x00, 0x01]);
+}
+
+impl<K: EncodeKey + ?Sized> EncodeKey for &K {
+    fn encode_into(&self, buf: &mut Vec<u8>) {
+        (**self).encode_into(buf);
+    }
+
+    fn encode_part_into(&self, buf: &mut Vec<u8>) {
+        (**self).encode_part_into(buf);
+    }
+}
+
+// Unsigned integers are stored big-endian so the most significant byte is compared first. 
+macro_rules! impl_unsigned {
+    ($($t:ty),*) => {$(
+        impl EncodeKey for $t {
+            fn encode_into(&self, buf: &mut Vec<u8>) {
+                buf.extend_from_slice(&self.to_be_bytes());
+            }
+        }
+    )*};
+}
+
+// Signed integers flip the sign bit so negative numbers sort before positive ones. 
+macro_rules! impl_signed {
+    ($($t:ty => $u:ty),*) => {$(
+        impl EncodeKey for $t {
+            fn encode_into(&self, buf: &mut Vec<u8>) {
+                let flipped = (*self as $u) ^ (1 << (<$u>::BITS - 1));
+                buf.extend_from_slice(&flipped.to_be_bytes());
+            }
+        }
+    )*};
+}
+
+// Floats flip the sign bit of positive numbers and every bit of negative numbers, which turns the IEEE 754 
+// bit pattern into an unsigned integer with the same order. NaNs sort after infinity (or before -infinity). 
+macro_rules! impl_float {
+    ($($t:ty => $u:ty),*) => {$(
+        impl EncodeKey for $t {
+            fn encode_into(&self, buf: &mut Vec<u8>) {
+                let bits = self.to_bits();
+                let sign = 1 << (<$u>::BITS - 1);
+                let flipped = if bits & sign == 0 { bits ^ sign } else { !bits };
+                buf.extend_from_slice(&flipped.to_be_bytes());
+            }
+        }
+    )*};
+}
+
+impl_unsigned!(u8, u16, u32, u64, u128, usize);
+impl_signed!(i8 => u8, i16 => u16, i32 => u32, i64 => u64, i128 => u128, isize => usize);
+impl_float!(f32 => u32, f64 => u64);
+
+impl EncodeKey for bool {
+    fn encode_into(&self, buf: &mut Vec<u8>) {
+        buf.push(*self as u8);
+    }
+}
+
+// Byte strings and UTF-8 strings are already ordered byte by byte, so they are stored as-is. 
+impl EncodeKey for [u8] {
+    fn encode_into(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(self);
+    }
+
+    fn encode_part_into(&self, buf: &mut Vec<u8>) {
+        encode_escaped(self, buf);
+    }
+}
+
+impl<const N: usize> EncodeKey for [u8; N] {
+    fn encode_into(&self, buf: &mut Vec<u8>) {
+        self[..].encode_into(buf);
+    }
+
+    fn encode_part_into(&self, buf: &mut Vec<u8>) {
+        self[..].encode_part_into(buf);
+    }
+}
+
+impl EncodeKey for Vec<u8> {
+    fn encode_into(&self, buf: &mut Vec<u8>) {
+        self[..].encode_into(buf);
+    }
+
+    fn encode_part_into(&self, buf: &mut Vec<u8>) {
+        self[..].encode_part_into(buf);
+    }
+}
+
+impl EncodeKey for str {
+    fn encode_into(&self, buf: &mut Vec<u8>) {
+        self.as_bytes().encode_into(buf);
+    }
+
+    fn encode_part_into(&self, buf: &mut Vec<u8>) {
+        self.as_bytes().encode_part_into(buf);
+    }
+}
+
+impl EncodeKey for String {
+    fn encode_into(&self, buf: &mut Vec<u8>) {
+        self.as_bytes().encode_into(buf);
+    }
+
+    fn encode_part_into(&self, buf: &mut Vec<u8>) {
+        self.as_bytes().encode_part_into(buf);
+    }
+}
+
+// Tuples concatenate the self-delimiting encoding of each element, ordering them element by element. 
+macro_rules! impl_tuple {
+    ($($name:ident),+) => {
+        impl<$($name: EncodeKey),+> EncodeKey for ($($name,)+) {
+            #[allow(non_snake_case)]
+            fn encode_into(&self, buf: &mut Vec<u8>) {
+                let ($(ref $name,)+) = *self;
+                $($name.encode_part_into(buf);)+
+            }
+        }
+    };
+}
+
+impl_tuple!(A);
+impl_tuple!(A, B);
+impl_tuple!(A, B, C);
+impl_tuple!(A, B, C, D);
+impl_tuple!(A, B, C, D, E);
+impl_tuple!(A, B, C, D, E, F);
+
+#[cfg(test)]
+mod tests {
+    use super::EncodeKey;
+    use std::fmt::Debug;
+
+    /// Asserts that the encoded keys are ordered the same way as the (already sorted) input values. 
+    fn assert_ordered<K: EncodeKey + Debug>(values: &[K]) {
+        for pair in values.windows(2) {
+            assert!(pair[0].encode_key() < pair[1].encode_key(), "{:?} should encode below {:?}", pair[0], pair[1]);
+        }
+    }
+
+    #[test]
+    fn test_integer_order() {
+        assert_ordered(&[0u8, 1, 127, 128, 255]);
+        assert_ordered(&[0u16, 1, 255, 256, 65535]);
+        assert_ordered(&[0u64, 1, 1 << 32, u64::MAX]);
+        assert_ordered(&[i8::MIN, -1, 0, 1, i8::MAX]);
+        assert_ordered(&[i32::MIN, -65536, -1, 0, 1, 65536, i32::MAX]);
+        assert_ordered(&[i128::MIN, -1, 0, i128::MAX]);
+        assert_eq!(258u16.encode_key(), vec![1, 2]);
+    }
+
+    #[test]
+    fn test_float_order() {
+        assert_ordered(&[f64::NEG_INFINITY, -1e10, -1.5, -0.0, 0.0, 1e-300, 1.5, f64::INFINITY]);
+        assert_ordered(&[f32::MIN, -1.0, 0.0, 1.0, f32::MAX]);
+    }
+
+    #[test]
+    fn test_string_order() {
+        assert_ordered(&["", "a", "a\0", "ab", "b", "é"]);
+        assert_eq!("abc".encode_key(), b"abc".to_vec());
+        assert_eq!(b"abc".encode_key(), vec![b'a', b'b', b'c']);
+    }
+
+    #[test]
+    fn test_tuple_order() {
+        assert_ordered(&[("a", 5u8), ("a", 255), ("a\0", 0), ("ab", 0), ("b", 0)]);
+        assert_ordered(&[(-1i32, "z"), (0, ""), (0, "a"), (1, "")]);
+        assert_ordered(&[(b"a".to_vec(), 255u8, 255u8), (b"a\0".to_vec(), 0, 0)]);
+        assert_ordered(&[(1u8, 2u8, "x"), (1, 3, ""), (2, 0, "")]);
+    }
+}
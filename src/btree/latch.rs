@@ -0,0 +1,27 @@
+use btree::node::BTreeNode;
+
+use std::sync::{Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+/// A node in the buffer pool along with its latch. Readers hold the latch shared and writers hold it exclusively
+/// while they use the node, and the buffer pool only evicts a page once nobody but the pool holds it.
+pub type PageLatch = Arc<RwLock<BTreeNode>>;
+
+/// Locks the mutex, even if a thread panicked while holding it.
+///
+/// Such a panic can leave the value half updated, as most of them are changed in several steps. The writer is
+/// the exception: the tree locks it with BTree::lock_writer() instead, which fails the log once it is poisoned,
+/// so a write that panicked halfway is never logged or checkpointed. The other locks are still used after a
+/// panic, which can only come from a bug there, and whatever it left half updated goes undetected.
+pub(crate) fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+/// Takes the latch shared. Like lock(), a poisoned latch is still used.
+pub(crate) fn read<T>(latch: &RwLock<T>) -> RwLockReadGuard<'_, T> {
+    latch.read().unwrap_or_else(|err| err.into_inner())
+}
+
+/// Takes the latch exclusively. Like lock(), a poisoned latch is still used.
+pub(crate) fn write<T>(latch: &RwLock<T>) -> RwLockWriteGuard<'_, T> {
+    latch.write().unwrap_or_else(|err| err.into_inner())
+}
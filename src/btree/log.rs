@@ -0,0 +1,336 @@
+use btree::error::{DbError, Result};
+use btree::latch::lock;
+use btree::store::LogStore;
+use btree::wal::WalRecord;
+use options::SyncMode;
+
+use std::io;
+use std::mem;
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// The WAL and the records waiting to be written to it, shared by every writer of the tree.
+///
+/// Writers append their records to a shared buffer while they hold the writer lock, and wait for them to be
+/// written with commit() once they let go of it. The first writer to wait becomes the leader: it takes every
+/// record buffered so far, writes them with a single write_all(), syncs the WAL once if the sync mode asks
+/// for it and then wakes every writer whose records were part of the group. Writers arriving while the
+/// leader is busy keep buffering, and the next one to find the WAL idle writes their group. So with many
+/// writers at once, each fsync covers the commits of all of them.
+///
+/// Once writing or syncing the WAL fails, nobody can tell which records made it to the disk, so every
+/// commit from then on fails too and the database has to be reopened, which recovers what is in the WAL. The
+/// tree marks the log failed the same way when a write fails after it started changing the tree.
+pub(crate) struct Log {
+    file: Mutex<Box<dyn LogStore>>,
+    state: Mutex<LogState>,
+    /// Signalled whenever a leader is done with its group.
+    done: Condvar,
+    sync_mode: SyncMode,
+}
+
+struct LogState {
+    /// Serialized records appended since the last group was taken.
+    pending: Vec<u8>,
+    /// Number of commits in pending.
+    pending_commits: u64,
+    /// LSN of the last record appended.
+    appended_lsn: u64,
+    /// LSN of the last record written to the WAL file.
+    written_lsn: u64,
+    /// LSN of the last record known to be on disk.
+    synced_lsn: u64,
+    /// Whether a leader is writing a group right now.
+    busy: bool,
+    failed: bool,
+    last_sync: Instant,
+    stats: LogStats,
+}
+
+/// Counters describing the groups written to the WAL, copied into Stats.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct LogStats {
+    pub(crate) syncs: u64,
+    pub(crate) groups: u64,
+    pub(crate) commits: u64,
+    pub(crate) largest_group: u64,
+    pub(crate) commit_wait: Duration,
+    pub(crate) longest_commit_wait: Duration,
+}
+
+impl Log {
+    /// Wraps the WAL, whose records up to lsn are already on disk.
+    pub(crate) fn new(file: Box<dyn LogStore>, sync_mode: SyncMode, lsn: u64) -> Self {
+        let state = LogState {
+            pending: Vec::new(),
+            pending_commits: 0,
+            appended_lsn: lsn,
+            written_lsn: lsn,
+            synced_lsn: lsn,
+            busy: false,
+            failed: false,
+            last_sync: Instant::now(),
+            stats: LogStats::default(),
+        };
+
+        Self { file: Mutex::new(file), state: Mutex::new(state), done: Condvar::new(), sync_mode }
+    }
+
+    /// Buffers the serialized records of one commit, the last of which has the given LSN. They are written
+    /// to the WAL by the next group, see commit().
+    pub(crate) fn append(&self, records: &[u8], lsn: u64) {
+        let mut state = lock(&self.state);
+        state.pending.extend_from_slice(records);
+        state.pending_commits += 1;
+        state.appended_lsn = lsn;
+    }
+
+    /// Returns an error if the log failed, after which no write may change the tree.
+    pub(crate) fn check(&self) -> Result<()> {
+        match lock(&self.state).failed {
+            true => Err(failed()),
+            false => Ok(()),
+        }
+    }
+
+    /// Marks the log failed, for a write that failed after it started changing the tree, and returns the error
+    /// every commit waiting now or later fails with.
+    pub(crate) fn fail(&self) -> DbError {
+        lock(&self.state).failed = true;
+        self.done.notify_all();
+        failed()
+    }
+
+    /// Waits until the records up to lsn are written to the WAL, and synced if the sync mode is Always. The
+    /// time spent waiting counts towards the commit latency.
+    pub(crate) fn commit(&self, lsn: u64) -> Result<()> {
+        let start = Instant::now();
+        let result = self.wait_for(lsn, self.sync_mode == SyncMode::Always);
+
+        let wait = start.elapsed();
+        let mut state = lock(&self.state);
+        state.stats.commit_wait += wait;
+        state.stats.longest_commit_wait = state.stats.longest_commit_wait.max(wait);
+        result
+    }
+
+    /// Writes every buffered record to the WAL and syncs it, unless the sync mode is Never.
+    pub(crate) fn flush(&self) -> Result<()> {
+        let lsn = lock(&self.state).appended_lsn;
+        self.wait_for(lsn, self.sync_mode != SyncMode::Never)
+    }
+
+    /// Truncates the WAL down to the given records, which end with lsn, and syncs it unless the sync mode
+    /// is Never. Buffered records are dropped, so the caller has to flush() first and keep writers out.
+    pub(crate) fn reset(&self, records: &[u8], lsn: u64) -> Result<()> {
+        let mut state = self.idle(lock(&self.state));
+        let mut file = lock(&self.file);
+
+        file.truncate(0)?;
+        file.append(records)?;
+
+        if !records.is_empty() && self.sync_mode != SyncMode::Never {
+            file.sync()?;
+            state.stats.syncs += 1;
+            state.last_sync = Instant::now();
+        }
+
+        state.pending.clear();
+        state.pending_commits = 0;
+        state.appended_lsn = lsn;
+        state.written_lsn = lsn;
+        state.synced_lsn = lsn;
+        Ok(())
+    }
+
+    /// Reads every intact record from the WAL in the order they were written.
+    ///
+    /// Reading stops at the first record that is cut short, fails its checksum or does not have a larger LSN
+    /// than the record before it. Anything from that point on is a torn or corrupt tail left behind by a crash.
+    pub(crate) fn read_records(&self) -> Result<Vec<WalRecord>> {
+        let buf = lock(&self.file).read_all()?;
+
+        let mut records: Vec<WalRecord> = Vec::new();
+        let mut offset = 0;
+
+        while let Some((record, len)) = WalRecord::deserialize(&buf[offset..]) {
+            if records.last().is_some_and(|last| record.lsn <= last.lsn) {
+                break;
+            }
+
+            offset += len;
+            records.push(record);
+        }
+
+        Ok(records)
+    }
+
+    pub(crate) fn stats(&self) -> LogStats {
+        lock(&self.state).stats
+    }
+
+    /// Waits until the records up to lsn are written, and synced when sync is set. The first waiter to find
+    /// the WAL idle writes the group holding everything buffered so far on behalf of all of them.
+    fn wait_for(&self, lsn: u64, sync: bool) -> Result<()> {
+        let mut state = lock(&self.state);
+
+        loop {
+            if state.failed {
+                return Err(failed());
+            }
+            if state.written_lsn >= lsn && (!sync || state.synced_lsn >= lsn) {
+                return Ok(());
+            }
+            if state.busy {
+                state = self.done.wait(state).unwrap_or_else(|err| err.into_inner());
+                continue;
+            }
+
+            state = self.lead(state, sync)?;
+        }
+    }
+
+    /// Writes the buffered records as one group and syncs them if asked to or if the sync mode is due for it.
+    fn lead<'a>(&'a self, mut state: MutexGuard<'a, LogState>, sync: bool) -> Result<MutexGuard<'a, LogState>> {
+        let sync = sync || match self.sync_mode {
+            SyncMode::Always => true,
+            SyncMode::GroupCommit { interval } => state.last_sync.elapsed() >= interval,
+            SyncMode::OnFlush | SyncMode::Never => false,
+        };
+
+        let group = mem::take(&mut state.pending);
+        let commits = mem::replace(&mut state.pending_commits, 0);
+        let lsn = state.appended_lsn;
+        state.busy = true;
+        drop(state);
+
+        let result = self.write_group(&group, sync);
+
+        let mut state = lock(&self.state);
+        state.busy = false;
+        self.done.notify_all();
+
+        if let Err(err) = result {
+            state.failed = true;
+            return Err(err);
+        }
+
+        state.written_lsn = lsn;
+        if sync {
+            state.synced_lsn = lsn;
+            state.last_sync = Instant::now();
+            state.stats.syncs += 1;
+        }
+        if commits > 0 {
+            state.stats.groups += 1;
+            state.stats.commits += commits;
+            state.stats.largest_group = state.stats.largest_group.max(commits);
+        }
+
+        Ok(state)
+    }
+
+    fn write_group(&self, group: &[u8], sync: bool) -> Result<()> {
+        let mut file = lock(&self.file);
+        file.append(group)?;
+
+        if sync {
+            file.sync()?;
+        }
+
+        Ok(())
+    }
+
+    /// Waits until no leader is writing a group.
+    fn idle<'a>(&'a self, mut state: MutexGuard<'a, LogState>) -> MutexGuard<'a, LogState> {
+        while state.busy {
+            state = self.done.wait(state).unwrap_or_else(|err| err.into_inner());
+        }
+
+        state
+    }
+}
+
+fn failed() -> DbError {
+    DbError::Io(io::Error::other("an earlier write failed, the database has to be reopened"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Log;
+    use btree::file::DbFile;
+    use btree::wal::{WalOp, WalRecord};
+    use btree::test_files::{create_files, remove_files};
+    use options::SyncMode;
+    use std::fs::OpenOptions;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    fn open_log(name: &str, sync_mode: SyncMode) -> (Log, String, String) {
+        let (file_path, wal_path) = create_files(&format!("log_{}", name));
+        let file = DbFile::open(&wal_path, OpenOptions::new().read(true).append(true).create(true)).unwrap();
+        (Log::new(Box::new(file), sync_mode, 0), file_path, wal_path)
+    }
+
+    fn record(lsn: u64) -> Vec<u8> {
+        WalRecord::new(lsn, WalOp::Put, &lsn.to_be_bytes(), b"value").serialize()
+    }
+
+    #[test]
+    fn test_buffered_commits_share_a_group() {
+        let (log, file_path, wal_path) = open_log("group", SyncMode::Always);
+
+        for lsn in 1..=3 {
+            log.append(&record(lsn), lsn);
+        }
+        log.commit(3).unwrap();
+
+        // The earlier commits were written and synced by the same group.
+        log.commit(1).unwrap();
+        log.commit(2).unwrap();
+
+        let stats = log.stats();
+        assert_eq!((stats.groups, stats.commits, stats.largest_group, stats.syncs), (1, 3, 3, 1));
+        assert_eq!(log.read_records().unwrap().len(), 3);
+
+        log.reset(&[], 3).unwrap();
+        assert!(log.read_records().unwrap().is_empty());
+
+        remove_files(&file_path, &wal_path);
+    }
+
+    #[test]
+    fn test_concurrent_commits() {
+        let (log, file_path, wal_path) = open_log("concurrent", SyncMode::Always);
+        let log = Arc::new(log);
+        let appended = Arc::new(Mutex::new(0u64));
+
+        let handles: Vec<_> = (0..8).map(|_| {
+            let log = log.clone();
+            let appended = appended.clone();
+            thread::spawn(move || {
+                for _ in 0..50 {
+                    // LSNs are handed out and appended in order, as under the writer lock of the tree.
+                    let lsn = {
+                        let mut appended = appended.lock().unwrap();
+                        *appended += 1;
+                        log.append(&record(*appended), *appended);
+                        *appended
+                    };
+                    log.commit(lsn).unwrap();
+                }
+            })
+        }).collect();
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        let stats = log.stats();
+        assert_eq!(stats.commits, 400);
+        assert!(stats.groups <= 400 && stats.syncs == stats.groups);
+        assert_eq!(log.read_records().unwrap().len(), 400);
+
+        remove_files(&file_path, &wal_path);
+    }
+}
@@ -0,0 +1,162 @@
+use btree::checksum::{seal_page, verify_page};
+use btree::error::{DbError, Result};
+use options::{MIN_PAGE_SIZE, MAX_PAGE_SIZE};
+
+use std::convert::TryInto;
+
+/// The meta page is always stored in the first page of the file. Page 0 is never used by a node.
+pub const META_PAGE_ID: u32 = 0;
+
+/// Identifies a file as a RustDB database.
+pub const MAGIC: [u8; 8] = *b"RUSTDB\0\0";
+
+/// Version of the on-disk format written by this build. Files with any other version are refused.
+pub const FORMAT_VERSION: u32 = 2;
+
+/// Bytes used by the fields of the meta page: magic (8), version (4), checksum (4), page size (4), root (4),
+/// page count (4), free-list head (4) and checkpoint LSN (8). The rest of the page is zero.
+pub const META_SIZE: usize = 40;
+
+/// Position of the checksum, which covers the META_SIZE bytes of fields.
+const CHECKSUM_OFFSET: usize = 12;
+
+/// Page id stored in the free-list head when there are no free pages.
+pub const NO_PAGE: u32 = 0;
+
+/// Header of the database file, describing where the tree starts and how the file is laid out.
+///
+/// On disk the meta page is laid out as
+///
+/// | magic (8) | version (4) | checksum (4) | page size (4) | root (4) | page count (4) | free-list head (4) | checkpoint lsn (8) |
+///
+/// and is rewritten by every checkpoint.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Meta {
+    /// Format version the file was written in, always FORMAT_VERSION once deserialized.
+    pub version: u32,
+    pub page_size: u32,
+    /// Page id of the root node.
+    pub root: u32,
+    /// Number of pages in the file, including the meta page.
+    pub page_count: u32,
+    /// First page of the list of free pages, or NO_PAGE.
+    pub free_list_head: u32,
+    /// LSN of the last change written to the data file by a checkpoint.
+    pub checkpoint_lsn: u64,
+}
+
+impl Meta {
+    /// Meta page of a new database holding a single empty leaf as its root, right after the meta page.
+    pub fn new(page_size: usize) -> Self {
+        Self {
+            version: FORMAT_VERSION,
+            page_size: page_size as u32,
+            root: 1,
+            page_count: 2,
+            free_list_head: NO_PAGE,
+            checkpoint_lsn: 0,
+        }
+    }
+
+    /// Serializes the meta page into a buffer of page_size bytes.
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut buffer = Vec::with_capacity(self.page_size as usize);
+
+        buffer.extend_from_slice(&MAGIC);
+        buffer.extend_from_slice(&self.version.to_le_bytes());
+        buffer.extend_from_slice(&[0u8; 4]);
+        buffer.extend_from_slice(&self.page_size.to_le_bytes());
+        buffer.extend_from_slice(&self.root.to_le_bytes());
+        buffer.extend_from_slice(&self.page_count.to_le_bytes());
+        buffer.extend_from_slice(&self.free_list_head.to_le_bytes());
+        buffer.extend_from_slice(&self.checkpoint_lsn.to_le_bytes());
+        seal_page(&mut buffer, CHECKSUM_OFFSET);
+
+        buffer.resize(self.page_size as usize, 0);
+        buffer
+    }
+
+    /// Deserializes the meta page from the start of the buffer.
+    ///
+    /// Files that do not start with the magic number are refused with an InvalidDatabase error, files written
+    /// in another format version with an UnsupportedVersion error. A meta page that fails its checksum or does
+    /// not describe a valid layout is reported as corruption of page 0.
+    pub fn deserialize(buf: &[u8]) -> Result<Meta> {
+        if buf.len() < META_SIZE || buf[0..8] != MAGIC {
+            return Err(DbError::InvalidDatabase("the file is not a RustDB database".to_string()));
+        }
+
+        let version = read_u32(buf, 8);
+
+        if version != FORMAT_VERSION {
+            return Err(DbError::UnsupportedVersion { found: version, supported: FORMAT_VERSION });
+        }
+
+        if !verify_page(&buf[..META_SIZE], CHECKSUM_OFFSET) {
+            return Err(DbError::Corruption { page_id: META_PAGE_ID });
+        }
+
+        let meta = Meta {
+            version,
+            page_size: read_u32(buf, 16),
+            root: read_u32(buf, 20),
+            page_count: read_u32(buf, 24),
+            free_list_head: read_u32(buf, 28),
+            checkpoint_lsn: u64::from_le_bytes(buf[32..40].try_into().unwrap()),
+        };
+
+        let page_size = meta.page_size as usize;
+        let valid_page_size = page_size.is_power_of_two() && (MIN_PAGE_SIZE..=MAX_PAGE_SIZE).contains(&page_size);
+        let valid_root = meta.root != META_PAGE_ID && meta.root < meta.page_count;
+
+        if !valid_page_size || !valid_root || meta.free_list_head >= meta.page_count {
+            return Err(DbError::Corruption { page_id: META_PAGE_ID });
+        }
+
+        Ok(meta)
+    }
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let meta = Meta { version: FORMAT_VERSION, page_size: 1024, root: 7, page_count: 12, free_list_head: 3, checkpoint_lsn: 99 };
+        let buf = meta.serialize();
+
+        assert_eq!(buf.len(), 1024);
+        assert_eq!(Meta::deserialize(&buf).unwrap(), meta);
+    }
+
+    #[test]
+    fn test_foreign_and_newer_files_are_refused() {
+        let mut buf = Meta::new(4096).serialize();
+
+        buf[8] = 9;
+        assert!(matches!(Meta::deserialize(&buf), Err(DbError::UnsupportedVersion { found: 9, supported: FORMAT_VERSION })));
+
+        buf[0] = b'X';
+        assert!(matches!(Meta::deserialize(&buf), Err(DbError::InvalidDatabase(_))));
+        assert!(matches!(Meta::deserialize(&[]), Err(DbError::InvalidDatabase(_))));
+    }
+
+    #[test]
+    fn test_invalid_layout_is_corruption() {
+        let mut meta = Meta::new(4096);
+        meta.root = 5;
+        assert!(matches!(Meta::deserialize(&meta.serialize()), Err(DbError::Corruption { page_id: 0 })));
+
+        let mut buf = Meta::new(4096).serialize();
+        buf[16..20].copy_from_slice(&1000u32.to_le_bytes());
+        assert!(matches!(Meta::deserialize(&buf), Err(DbError::Corruption { page_id: 0 })));
+
+        seal_page(&mut buf[..META_SIZE], CHECKSUM_OFFSET);
+        assert!(matches!(Meta::deserialize(&buf), Err(DbError::Corruption { page_id: 0 })));
+    }
+}
@@ -1,3 +1,28 @@
 pub mod tree;
 pub mod node;
-pub mod cache;
\ No newline at end of file
+pub mod cache;
+pub mod key;
+pub mod iter;
+pub mod wal;
+pub mod checksum;
+pub mod error;
+pub mod meta;
+pub mod alloc;
+pub mod stats;
+pub mod check;
+pub mod dump;
+pub mod dot;
+pub mod txn;
+pub mod batch;
+pub mod snapshot;
+pub mod latch;
+pub mod pager;
+pub mod log;
+pub mod doublewrite;
+pub mod file;
+pub mod spill;
+pub mod store;
+#[cfg(test)]
+mod crash;
+#[cfg(test)]
+pub(crate) mod test_files;
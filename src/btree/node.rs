@@ -1,48 +1,69 @@
+use btree::checksum::seal_page;
+use btree::error::{DbError, Result};
+
 use std::io::{self, Write, Seek};
-use std::fs::File;
+use std::convert::TryInto;
+
+/// Default size of a single page on disk. Every node is serialized into exactly one page.
+pub const PAGE_SIZE: usize = 4096;
+
+/// Bytes used by the fixed part of a serialized node: checksum (4), id (4), leaf flag (1), num_keys (2) and the
+/// ids of the previous (4) and next (4) leaf.
+pub const NODE_HEADER_SIZE: usize = 19;
+
+/// Sibling id stored when a leaf is the first or last leaf. Page 0 holds the meta page, which is never a node. 
+pub const NO_SIBLING: u32 = 0;
+
+/// Largest key + value pair (including their length prefixes) that can be stored in a page of the given size.
+/// Keeping entries below a quarter of the usable page guarantees both halves of a split node fit into a page.
+pub fn max_entry_size(page_size: usize) -> usize {
+    (page_size - NODE_HEADER_SIZE) / 4
+}
 
 /// The structure of the a single node (page) within the overall B-Tree. 
-/// Contains either the key-value stores (if leaf node) or key ranges with locations to the child nodes. 
+/// Contains either the key-value stores (if leaf node) or key ranges with locations to the child nodes.
+#[derive(Clone)]
 pub struct BTreeNode {
     pub id: u32, 
     pub leaf: u8, 
     pub num_keys: u16, 
 
-    pub keys: Vec<u16>, 
+    /// Links to the neighbouring leaves so range scans can walk the leaves in key order. Unused by internal nodes. 
+    pub prev_leaf: u32, 
+    pub next_leaf: u32, 
+
+    pub keys: Vec<Vec<u8>>, 
     pub children: Vec<u32>,
-    pub vals: Vec<u16>, 
+    pub vals: Vec<Vec<u8>>, 
 }
 
 /// Enum to list different value-types a field in Node can contain. Used in BTree's get_node_info() method. 
 pub enum NodeInfo {
     U16(u16),
     U32(u32),
-    Bool(bool),
 }
 
 impl NodeInfo {
-    pub fn as_u32(&self) -> u32 {
+    pub fn as_u32(&self) -> Result<u32> {
         if let NodeInfo::U32(val) = *self {
-            val
+            Ok(val)
         } else {
-            panic!("Expected a u32 value")
+            Err(DbError::InvalidField("expected a u32 value".to_string()))
         }
     }
 
-    pub fn as_u16(&self) -> u16 {
+    pub fn as_u16(&self) -> Result<u16> {
         if let NodeInfo::U16(val) = *self {
-            val
+            Ok(val)
         } else {
-            panic!("Expected a u16 value")
+            Err(DbError::InvalidField("expected a u16 value".to_string()))
         }
     }
+}
 
-    pub fn as_bool(&self) -> bool {
-        if let NodeInfo::Bool(bool) = *self {
-            bool
-        } else {
-            panic!("Expected a boolean value")
-        }
+impl Default for BTreeNode {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -52,6 +73,8 @@ impl BTreeNode {
         Self {
             id: 0,
             num_keys: 0,
+            prev_leaf: NO_SIBLING,
+            next_leaf: NO_SIBLING,
             keys: Vec::new(),
             children: Vec::new(),
             vals: Vec::new(), 
@@ -60,97 +83,141 @@ impl BTreeNode {
     }
 
     /// Create a new node given all possible parameters. 
-    pub fn new_from_params(id:u32, leaf:u8, num_keys:u16, keys: Vec<u16>, 
-                                children: Vec<u32>, vals: Vec<u16> ) -> Self {
+    pub fn new_from_params(id:u32, leaf:u8, num_keys:u16, keys: Vec<Vec<u8>>, 
+                                children: Vec<u32>, vals: Vec<Vec<u8>> ) -> Self {
         Self {
             id,
             leaf,
             num_keys,
+            prev_leaf: NO_SIBLING,
+            next_leaf: NO_SIBLING,
             keys,
             children,
             vals, 
         }
     }
 
-    ///         
+    /// Returns the value of a single field of the node, or None if the field does not exist. The index is 
+    /// only used by the child id fields and refers to the position of the child. 
     pub fn get_field_info(&self, field: &str, index: usize) -> Option<NodeInfo> {
         match field {
-            "is_leaf"        => Some(NodeInfo::Bool(self.is_leaf())),
-            "has_siblings"   => Some(NodeInfo::Bool(self.children.len() > 1)),
             "num_keys"       => Some(NodeInfo::U16(self.num_keys)),
+            "size"           => self.size().try_into().ok().map(NodeInfo::U16),
             "node_id"        => Some(NodeInfo::U32(self.id)),
-            "child_id"       => Some(NodeInfo::U32(self.children[index])),
-            "left_child_id"  => Some(NodeInfo::U32(self.children[index-1])),
-            "right_child_id" => Some(NodeInfo::U32(self.children[index+1])),
+            "child_id"       => self.children.get(index).map(|&id| NodeInfo::U32(id)),
+            "left_child_id"  => index.checked_sub(1).and_then(|i| self.children.get(i)).map(|&id| NodeInfo::U32(id)),
+            "right_child_id" => self.children.get(index + 1).map(|&id| NodeInfo::U32(id)),
             _ => None, 
         }
     }
 
 
 
-    /// Persists the current node to the disk. Borrows the file from the B-Tree itself and 
-    /// calculates the file position from using the node's id and the fact that a node 
-    /// is limited to be at most 4096 bytes. 
-    pub fn write_node_to_file(&self, file: &mut File) {
-        let offset: u64 = (4096*(self.id)).into();
-        let buffer = self.serialize();
-        file.seek(io::SeekFrom::Start(offset)); 
-        file.write_all(&buffer);
+    /// Persists the current node to the disk, or to anything else that can be written and seeked. Calculates 
+    /// the file position from using the node's id and the fact that a node is limited to be at most one page. 
+    pub fn write_node_to_file<W: Write + Seek>(&self, file: &mut W, page_size: usize) -> Result<()> {
+        let offset: u64 = (page_size as u64) * u64::from(self.id);
+        let buffer = self.serialize(page_size)?;
+        file.seek(io::SeekFrom::Start(offset))?; 
+        file.write_all(&buffer)?;
+        Ok(())
     }
 
     /// Serializes the node into a sequence of bytes since the database is persisted as a binary file. 
-    pub fn serialize(&self) -> Vec<u8> {
-        let mut buffer = Vec::new();
+    /// 
+    /// Keys and values are variable-length byte strings, so each one is written with a u16 length prefix. 
+    /// Internal nodes store num_keys + 1 child ids after the keys. Returns a PageOverflow error for a node that
+    /// does not fit into a page.
+    pub fn serialize(&self, page_size: usize) -> Result<Vec<u8>> {
+        let mut buffer = Vec::with_capacity(page_size);
 
-        buffer.write_all(&self.id.to_le_bytes()).unwrap();
-        buffer.write_all(&self.leaf.to_le_bytes()).unwrap();
-        buffer.write_all(&self.num_keys.to_le_bytes()).unwrap();
+        // Placeholder for the checksum, which is filled in once the whole page is written.
+        buffer.extend_from_slice(&[0u8; 4]);
+        buffer.extend_from_slice(&self.id.to_le_bytes());
+        buffer.extend_from_slice(&self.leaf.to_le_bytes());
+        buffer.extend_from_slice(&self.num_keys.to_le_bytes());
+        buffer.extend_from_slice(&self.prev_leaf.to_le_bytes());
+        buffer.extend_from_slice(&self.next_leaf.to_le_bytes());
 
         for key in &self.keys {
-            let bytes = key.to_le_bytes();
-            buffer.write_all(&bytes).unwrap();
+            write_bytes(&mut buffer, key);
         }
 
         match self.leaf == 1 {
             true => {
                 for val in &self.vals {
-                    let bytes = val.to_le_bytes();
-                    buffer.write_all(&bytes).unwrap();
+                    write_bytes(&mut buffer, val);
                 }        
             }, 
 
             false => {
                 for child in &self.children {
-                    let bytes = child.to_le_bytes();
-                    buffer.write_all(&bytes).unwrap();
+                    buffer.extend_from_slice(&child.to_le_bytes());
                 }        
 
             }
         }
 
-        let padding_len = 4096 - buffer.len();
-        buffer.extend_from_slice(&vec![0u8; padding_len]);  
+        if buffer.len() > page_size {
+            return Err(DbError::PageOverflow { page_id: self.id, size: buffer.len(), page_size });
+        }
+
+        buffer.resize(page_size, 0);
+        seal_page(&mut buffer, 0);  
 
-        buffer      
+        Ok(buffer)
     }
 
-    /// Given an input key, searches through the node's key array for the key and returns the index if found. 
-    /// If the key isn't found, returns the index of the first value greater than the key. 
+    /// Number of bytes the node takes up once serialized, not counting the padding to the page size. 
+    pub fn size(&self) -> usize {
+        let keys: usize = self.keys.iter().map(|key| 2 + key.len()).sum();
+
+        match self.leaf == 1 {
+            true => {
+                let vals: usize = self.vals.iter().map(|val| 2 + val.len()).sum();
+                NODE_HEADER_SIZE + keys + vals
+            },
+            false => NODE_HEADER_SIZE + keys + 4 * self.children.len(),
+        }
+    }
+
+    /// A node is full once its serialized form no longer fits into a single page. 
+    pub fn is_full(&self, page_size: usize) -> bool {
+        self.size() > page_size
+    }
+
+    /// Returns the index to split the node at so that both halves take up roughly the same number of bytes. 
     /// 
-    /// Idea is that either key[i] pairs with val[i] OR 
-    /// The child node at children[0] points to the nodes storing all keys less than key[i]. 
-    pub fn search(&self, key: u16) -> usize {
-        if self.keys.len() == 0 {
-            return 0
+    /// For leaves the key at the index is the first key of the new right node. For internal nodes it is
+    /// the key that moves up into the parent, so at least one key is kept on each side. 
+    pub fn split_index(&self) -> usize {
+        let entry_size = |i: usize| match self.leaf == 1 {
+            true => 4 + self.keys[i].len() + self.vals[i].len(),
+            false => 6 + self.keys[i].len(),
+        };
+
+        let half = (self.size() - NODE_HEADER_SIZE) / 2;
+        let mut index = 0;
+        let mut used = 0;
+
+        while index < self.keys.len() && used + entry_size(index) <= half {
+            used += entry_size(index);
+            index += 1;
         }
 
-        for i in 0..self.keys.len() {
-            if self.keys[i] >= key {
-                return i;
-            } 
+        match self.leaf == 1 {
+            true => index.clamp(1, self.keys.len() - 1),
+            false => index.clamp(1, self.keys.len() - 2),
         }
+    }
 
-        self.keys.len()
+    /// Given an input key, searches through the node's key array for the key and returns the index if found. 
+    /// If the key isn't found, returns the index of the first value greater than the key. 
+    /// 
+    /// Keys are compared lexicographically as byte strings. Idea is that either key[i] pairs with val[i] OR 
+    /// the child node at children[i] points to the nodes storing all keys less than or equal to key[i]. 
+    pub fn search(&self, key: &[u8]) -> usize {
+        self.keys.partition_point(|k| k.as_slice() < key)
     }
 
     pub fn is_leaf(&self) -> bool {
@@ -164,3 +231,55 @@ impl BTreeNode {
     }
 
 }
+
+/// Appends a byte string to the buffer prefixed with its length. 
+fn write_bytes(buffer: &mut Vec<u8>, bytes: &[u8]) {
+    let len = bytes.len() as u16;
+    buffer.extend_from_slice(&len.to_le_bytes());
+    buffer.extend_from_slice(bytes);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BTreeNode, PAGE_SIZE};
+    use btree::error::DbError;
+    use btree::checksum::verify_page;
+    use std::convert::TryInto;
+
+    #[test]
+    fn test_search_is_lexicographic() {
+        let mut node = BTreeNode::new();
+        node.keys = vec![b"a".to_vec(), b"ab".to_vec(), b"b".to_vec(), b"ba".to_vec()];
+
+        assert_eq!(node.search(b""), 0);
+        assert_eq!(node.search(b"ab"), 1);
+        assert_eq!(node.search(b"aa"), 1);
+        assert_eq!(node.search(b"b\x00"), 3);
+        assert_eq!(node.search(b"c"), 4);
+    }
+
+    #[test]
+    fn test_serialize_length_prefixed() {
+        let node = BTreeNode::new_from_params(3, 1, 2, vec![b"k".to_vec(), b"key".to_vec()],
+                                              Vec::new(), vec![b"vv".to_vec(), Vec::new()]);
+        let buf = node.serialize(PAGE_SIZE).unwrap();
+
+        assert_eq!(buf.len(), PAGE_SIZE);
+        assert_eq!(node.size(), 19 + 3 + 5 + 4 + 2);
+        assert!(verify_page(&buf, 0));
+        assert_eq!(u32::from_le_bytes(buf[4..8].try_into().unwrap()), 3);
+        assert_eq!(u16::from_le_bytes(buf[19..21].try_into().unwrap()), 1);
+        assert_eq!(&buf[21..22], b"k");
+        assert_eq!(u16::from_le_bytes(buf[22..24].try_into().unwrap()), 3);
+        assert_eq!(&buf[24..27], b"key");
+        assert_eq!(&buf[29..31], b"vv");
+    }
+
+    #[test]
+    fn test_serialize_rejects_oversized_node() {
+        let node = BTreeNode::new_from_params(5, 1, 1, vec![b"key".to_vec()], Vec::new(), vec![vec![0u8; 600]]);
+
+        assert!(node.serialize(1024).is_ok());
+        assert!(matches!(node.serialize(512), Err(DbError::PageOverflow { page_id: 5, size: 626, page_size: 512 })));
+    }
+}
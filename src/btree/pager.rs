@@ -0,0 +1,225 @@
+use btree::cache::BufferPool;
+use btree::checksum::verify_page;
+use btree::doublewrite::{DoubleWrite, PageWrites};
+use btree::error::{DbError, Result};
+use btree::latch::{self, lock, PageLatch};
+use btree::node::BTreeNode;
+use btree::spill::Spill;
+use btree::stats::Stats;
+use btree::store::PageStore;
+use btree::tree::BTree;
+
+use std::io;
+use std::sync::{Mutex, MutexGuard, RwLock, RwLockWriteGuard};
+
+/// The data file, or whichever PageStore holds the pages, and the buffer pool in front of it, shared by the
+/// tree and its snapshots.
+///
+/// Readers share the buffer pool while they find their pages in it, see BufferPool. Loading a page and evicting
+/// one both happen with the buffer pool locked exclusively, so a page is never read from the file while a newer
+/// copy of it is on its way there. The pool is always locked before the spill file, that
+/// before the double-write file and that before the data file, and nobody waits for a page latch while holding
+/// any of them.
+///
+/// The data file only changes when a checkpoint writes the dirty pages, through the double-write file, see
+/// DoubleWrite. Dirty pages evicted before then go to the spill file, see Spill.
+pub(crate) struct Pager {
+    file: Mutex<Box<dyn PageStore>>,
+    double_write: Mutex<DoubleWrite>,
+    /// Missing for a read-only tree, which never has dirty pages.
+    spill: Mutex<Option<Spill>>,
+    pool: RwLock<BufferPool>,
+    stats: Mutex<Stats>,
+    page_size: usize,
+}
+
+impl Pager {
+    pub(crate) fn new(file: Box<dyn PageStore>, double_write: DoubleWrite, spill: Option<Spill>, page_size: usize,
+                      cache_size: usize) -> Self {
+        Self {
+            file: Mutex::new(file),
+            double_write: Mutex::new(double_write),
+            spill: Mutex::new(spill),
+            pool: RwLock::new(BufferPool::new(cache_size)),
+            stats: Mutex::new(Stats::default()),
+            page_size,
+        }
+    }
+
+    /// Returns the page behind its latch, loading it into the buffer pool first if it is not cached. A page
+    /// read back from the spill file goes back into the pool as a dirty page.
+    pub(crate) fn fetch(&self, page_id: u32) -> Result<PageLatch> {
+        if let Some(page) = latch::read(&self.pool).get(page_id) {
+            return Ok(page);
+        }
+
+        let mut pool = self.pool();
+
+        // Another thread may have loaded the page while the pool was not locked.
+        if let Some(page) = pool.get(page_id) {
+            return Ok(page);
+        }
+
+        let spilled = match *self.spill() {
+            Some(ref mut spill) => {
+                let node = spill.read(page_id)?;
+                spill.remove(page_id);
+                node
+            },
+            None => None,
+        };
+
+        let evicted = match spilled {
+            Some(node) => pool.insert(page_id, node, true),
+            None => pool.insert(page_id, self.read_node_from_file(page_id)?, false),
+        };
+        self.write_back(evicted)?;
+
+        pool.get(page_id).ok_or(DbError::Corruption { page_id })
+    }
+
+    /// Puts a changed node into the buffer pool, where it stays dirty until it is flushed or evicted. Used for
+    /// pages the writer did not latch on its way down, which readers cannot reach through a latched parent.
+    pub(crate) fn publish(&self, node: BTreeNode) -> Result<()> {
+        let page_id = node.id;
+        let cached = latch::read(&self.pool).get(page_id);
+
+        match cached {
+            Some(page) => {
+                *latch::write(&page) = node;
+                self.pool().mark_dirty(page_id);
+                Ok(())
+            },
+            None => {
+                let mut pool = self.pool();
+
+                // The page may have been evicted again since the change read it, leaving an older copy spilled.
+                if let Some(ref mut spill) = *self.spill() {
+                    spill.remove(page_id);
+                }
+
+                let evicted = pool.insert(page_id, node, true);
+                self.write_back(evicted)
+            },
+        }
+    }
+
+    /// Adds every dirty page to writes, the spilled ones included. The pages stay dirty until mark_clean() is
+    /// called once the writes are made.
+    pub(crate) fn stage_dirty_pages(&self, writes: &mut PageWrites) -> Result<()> {
+        let dirty = self.pool().dirty_pages();
+        let mut spill = self.spill();
+
+        for page in &dirty {
+            latch::read(page).write_node_to_file(writes, self.page_size)?;
+        }
+
+        let spilled = match *spill {
+            Some(ref mut spill) => {
+                spill.stage(writes)?;
+                spill.len()
+            },
+            None => 0,
+        };
+
+        lock(&self.stats).pages_written += (dirty.len() + spilled) as u64;
+        Ok(())
+    }
+
+    /// Marks every dirty page as clean and empties the spill file, once a checkpoint has written them.
+    pub(crate) fn mark_clean(&self) -> Result<()> {
+        self.pool().mark_clean();
+
+        match *self.spill() {
+            Some(ref mut spill) => spill.clear(),
+            None => Ok(()),
+        }
+    }
+
+    /// Number of pages that changed since the last checkpoint, in the buffer pool or spilled.
+    pub(crate) fn dirty_count(&self) -> usize {
+        let dirty = self.pool().dirty_pages().len();
+        dirty + self.spill().as_ref().map_or(0, Spill::len)
+    }
+
+    /// Drops a freed page from the buffer pool and the spill file.
+    pub(crate) fn discard(&self, page_id: u32) {
+        self.pool().remove(page_id);
+
+        if let Some(ref mut spill) = *self.spill() {
+            spill.remove(page_id);
+        }
+    }
+
+    /// Makes the writes to the data file through the double-write file.
+    pub(crate) fn write_pages(&self, writes: &PageWrites) -> Result<()> {
+        let mut double_write = lock(&self.double_write);
+        double_write.write(&mut **self.file(), writes)
+    }
+
+    /// Counts the pages put back from the double-write file when the database was opened.
+    pub(crate) fn count_restored(&self, pages: usize) {
+        lock(&self.stats).pages_restored += pages as u64;
+    }
+
+    /// Locks the buffer pool exclusively.
+    pub(crate) fn pool(&self) -> RwLockWriteGuard<'_, BufferPool> {
+        latch::write(&self.pool)
+    }
+
+    pub(crate) fn file(&self) -> MutexGuard<'_, Box<dyn PageStore>> {
+        lock(&self.file)
+    }
+
+    pub(crate) fn stats(&self) -> Stats {
+        *lock(&self.stats)
+    }
+
+    /// Writes the dirty pages evicted from the buffer pool to the spill file. The caller still holds the pool,
+    /// so nobody can load one of them before it is written.
+    fn write_back(&self, evicted: Vec<BTreeNode>) -> Result<()> {
+        if evicted.is_empty() {
+            return Ok(());
+        }
+
+        let mut spill = self.spill();
+        let spill = spill.as_mut().ok_or_else(|| io::Error::other("a read-only tree cannot evict dirty pages"))?;
+
+        for node in &evicted {
+            spill.write(node)?;
+        }
+
+        lock(&self.stats).pages_spilled += evicted.len() as u64;
+        Ok(())
+    }
+
+    fn spill(&self) -> MutexGuard<'_, Option<Spill>> {
+        lock(&self.spill)
+    }
+
+    /// Loads and deserializes node from the disk into memory from the starting position specified.
+    /// Pages that are missing or fail their checksum are reported as corruption of that page.
+    fn read_node_from_file(&self, node_id: u32) -> Result<BTreeNode> {
+        let mut buf = vec![0u8; self.page_size];
+
+        // A page past the end of the file was never written, which means the tree points at garbage.
+        match self.file().read_page(self.page_size as u64 * u64::from(node_id), &mut buf) {
+            Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => return Err(DbError::Corruption { page_id: node_id }),
+            result => result?,
+        }
+
+        let intact = verify_page(&buf, 0);
+
+        {
+            let mut stats = lock(&self.stats);
+            stats.pages_read += 1;
+
+            if !intact {
+                stats.checksum_failures += 1;
+                return Err(DbError::Corruption { page_id: node_id });
+            }
+        }
+
+        BTree::deserialize(&buf, node_id)
+    }
+}
@@ -0,0 +1,363 @@
+extern crate linked_hash_map;
+
+use btree::error::{DbError, Result};
+use btree::iter::{Pages, Range};
+use btree::key::EncodeKey;
+use btree::latch::{self, lock};
+use btree::node::BTreeNode;
+use btree::pager::Pager;
+
+use self::linked_hash_map::LinkedHashMap;
+
+use std::collections::{BTreeMap, HashMap};
+use std::mem;
+use std::ops::{Bound, RangeBounds};
+use std::sync::{Arc, Mutex};
+
+/// Old versions of pages, kept for as long as a snapshot may still read them.
+///
+/// Every snapshot gets the next epoch when it is taken. Before the tree publishes a change to a page, freeing it
+/// included, it hands the page as it was to preserve(), which keeps a copy tagged with the current epoch unless
+/// the live snapshots are already covered by an earlier copy. A snapshot then reads the first copy of a page
+/// tagged with its own epoch or a later one, which is the page as it was before the first change made after the
+/// snapshot was taken. Pages without such a copy have not changed since, so the snapshot reads them through the
+/// buffer pool of the tree.
+///
+/// A write or a transaction can change the tree several times, so the pages it changed are also kept until
+/// it is done. A snapshot taken in the middle of it gets copies of those and the root the write started from,
+/// and does not see any part of the write.
+#[derive(Default)]
+pub(crate) struct VersionStore {
+    epoch: u64,
+    /// Epochs of the live snapshots, with the number of snapshots sharing each one.
+    live: BTreeMap<u64, usize>,
+    /// Copies of each page, ordered by the epoch they were made in.
+    versions: HashMap<u32, Vec<(u64, BTreeNode)>>,
+    /// The root the write in progress started from, None between writes.
+    write_root: Option<u32>,
+    /// The pages changed by the write in progress, as they were before it.
+    written: HashMap<u32, BTreeNode>,
+}
+
+impl VersionStore {
+    /// Registers a new snapshot of the tree with the given root and returns its epoch and the root it reads.
+    fn register(&mut self, root: u32) -> (u64, u32) {
+        self.epoch += 1;
+        *self.live.entry(self.epoch).or_insert(0) += 1;
+
+        let written = mem::take(&mut self.written);
+        for node in written.values() {
+            self.keep(node);
+        }
+        self.written = written;
+
+        (self.epoch, self.write_root.unwrap_or(root))
+    }
+
+    /// Starts a write on the tree with the given root. Called with the writer lock held.
+    pub(crate) fn begin_write(&mut self, root: u32) {
+        self.write_root = Some(root);
+    }
+
+    /// Drops the pages kept for the write in progress once every change of it has been published.
+    pub(crate) fn end_write(&mut self) {
+        self.write_root = None;
+        self.written.clear();
+    }
+
+    /// Takes the node as it was before the change being published, for the live snapshots and the ones taken
+    /// before the write in progress is done.
+    pub(crate) fn preserve(&mut self, node: BTreeNode) {
+        self.keep(&node);
+
+        if self.write_root.is_some() {
+            self.written.entry(node.id).or_insert(node);
+        }
+    }
+
+    /// Keeps a copy of the node as it is now, if a live snapshot will need it once the node changes.
+    fn keep(&mut self, node: &BTreeNode) {
+        let newest = match self.live.keys().next_back() {
+            Some(&newest) => newest,
+            None => return,
+        };
+
+        let versions = self.versions.entry(node.id).or_default();
+
+        if versions.last().is_none_or(|&(saved, _)| saved < newest) {
+            versions.push((self.epoch, node.clone()));
+        }
+    }
+
+    /// Returns the page as the snapshot taken in epoch saw it, if it has changed since.
+    fn version(&self, page_id: u32, epoch: u64) -> Option<&BTreeNode> {
+        self.versions.get(&page_id)?.iter().find(|&&(saved, _)| saved >= epoch).map(|(_, node)| node)
+    }
+
+    /// Unregisters a snapshot and drops every copy no live snapshot reads anymore.
+    fn release(&mut self, epoch: u64) {
+        if let Some(count) = self.live.get_mut(&epoch) {
+            *count -= 1;
+
+            if *count == 0 {
+                self.live.remove(&epoch);
+            }
+        }
+
+        let live = &self.live;
+
+        // A copy is read by the snapshots taken after the copy before it was made, up to the epoch it was made in.
+        for versions in self.versions.values_mut() {
+            let mut previous = 0;
+
+            versions.retain(|&(saved, _)| {
+                let needed = live.range(previous + 1..=saved).next().is_some();
+                previous = saved;
+                needed
+            });
+        }
+
+        self.versions.retain(|_, versions| !versions.is_empty());
+    }
+
+    #[cfg(test)]
+    fn version_count(&self) -> usize {
+        self.versions.values().map(Vec::len).sum()
+    }
+}
+
+/// A read-only view of the database as it was when the snapshot was taken, returned by BTree::snapshot().
+///
+/// The snapshot does not borrow the database, so writes and deletes can go on while it is used, from this
+/// thread or another one. It reads the pages that changed since it was taken from the copies kept in the
+/// version store, and every other page through the buffer pool of the tree. Dropping the snapshot releases
+/// the copies only it was reading.
+pub struct Snapshot {
+    pager: Arc<Pager>,
+    root: u32,
+    epoch: u64,
+    versions: Arc<Mutex<VersionStore>>,
+    /// Pages read by this snapshot, least recently used first. They never change, so none of them is ever dirty.
+    pages: LinkedHashMap<u32, BTreeNode>,
+    cache_size: usize,
+}
+
+impl Snapshot {
+    /// Takes a snapshot of the tree with the given root. Only the pages changed by a write in progress are
+    /// copied: the pages read through the buffer pool include the dirty ones, and every change published from
+    /// now on leaves a copy in the version store.
+    pub(crate) fn new(pager: Arc<Pager>, root: u32, cache_size: usize, versions: Arc<Mutex<VersionStore>>) -> Self {
+        let (epoch, root) = lock(&versions).register(root);
+
+        Self { pager, root, epoch, versions, pages: LinkedHashMap::new(), cache_size }
+    }
+
+    /// Returns the value stored for key when the snapshot was taken, if any.
+    pub fn get<K: EncodeKey + ?Sized>(&mut self, key: &K) -> Result<Option<Vec<u8>>> {
+        let key = key.encode_key();
+        let leaf_id = self.find_leaf(Bound::Included(&key), false)?;
+        let node = self.page(leaf_id)?;
+        let index = node.search(&key);
+
+        match node.keys.get(index) {
+            Some(found) if *found == key => Ok(Some(node.vals[index].clone())),
+            _ => Ok(None),
+        }
+    }
+
+    /// Returns an iterator over the key-value pairs within the range as they were when the snapshot was taken.
+    /// Works the same way as BTree::range().
+    pub fn range<K: EncodeKey + ?Sized, R: RangeBounds<K>>(&mut self, range: R) -> Range<'_> {
+        let encode = |bound: Bound<&K>| match bound {
+            Bound::Included(key) => Bound::Included(key.encode_key()),
+            Bound::Excluded(key) => Bound::Excluded(key.encode_key()),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+
+        let start = encode(range.start_bound());
+        let end = encode(range.end_bound());
+        Range::new(Box::new(self), start, end)
+    }
+
+    /// Reads the page from the version store, or the buffer pool of the tree if it has not changed since the
+    /// snapshot was taken.
+    fn read_node(&self, page_id: u32) -> Result<BTreeNode> {
+        if let Some(node) = lock(&self.versions).version(page_id, self.epoch) {
+            return Ok(node.clone());
+        }
+
+        let node = latch::read(&*self.pager.fetch(page_id)?).clone();
+
+        // A writer keeps a copy of the page before changing it, so if the page changed while it was being
+        // read, the copy is in the version store by now.
+        match lock(&self.versions).version(page_id, self.epoch) {
+            Some(version) => Ok(version.clone()),
+            None => Ok(node),
+        }
+    }
+}
+
+impl Pages for Snapshot {
+    fn root(&self) -> u32 {
+        self.root
+    }
+
+    fn page(&mut self, page_id: u32) -> Result<&BTreeNode> {
+        if !self.pages.contains_key(&page_id) {
+            let node = self.read_node(page_id)?;
+            self.pages.insert(page_id, node);
+
+            while self.pages.len() > self.cache_size {
+                self.pages.pop_front();
+            }
+        }
+
+        self.pages.get_refresh(&page_id).map(|node| &*node).ok_or(DbError::Corruption { page_id })
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        lock(&self.versions).release(self.epoch);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::VersionStore;
+    use btree::latch::lock;
+    use btree::node::BTreeNode;
+    use btree::test_files::{create_files, remove_files};
+    use btree::tree::BTree;
+    use options::Options;
+    use std::sync::Arc;
+    use std::thread;
+
+    fn page(id: u32, key: &[u8]) -> BTreeNode {
+        BTreeNode::new_from_params(id, 1, 1, vec![key.to_vec()], Vec::new(), vec![Vec::new()])
+    }
+
+    #[test]
+    fn test_versions_follow_snapshots() {
+        let mut store = VersionStore::default();
+
+        // Nothing is kept while there are no snapshots.
+        store.preserve(page(1, b"a"));
+        assert_eq!(store.version_count(), 0);
+
+        let first = store.register(0).0;
+        store.preserve(page(1, b"b"));
+        store.preserve(page(1, b"c"));
+        let second = store.register(0).0;
+        store.preserve(page(1, b"d"));
+
+        assert_eq!(store.version(1, first).unwrap().keys[0], b"b");
+        assert_eq!(store.version(1, second).unwrap().keys[0], b"d");
+        assert!(store.version(2, first).is_none());
+
+        store.release(first);
+        assert_eq!(store.version_count(), 1);
+        assert_eq!(store.version(1, second).unwrap().keys[0], b"d");
+
+        store.release(second);
+        assert_eq!(store.version_count(), 0);
+    }
+
+    #[test]
+    fn test_snapshot_skips_the_write_in_progress() {
+        let mut store = VersionStore::default();
+
+        store.begin_write(1);
+        store.preserve(page(1, b"a"));
+        store.preserve(page(2, b"b"));
+
+        // The root changed and both pages were published before the snapshot was taken.
+        let (epoch, root) = store.register(3);
+        assert_eq!(root, 1);
+        store.preserve(page(2, b"c"));
+        assert_eq!(store.version(1, epoch).unwrap().keys[0], b"a");
+        assert_eq!(store.version(2, epoch).unwrap().keys[0], b"b");
+        store.end_write();
+
+        assert_eq!(store.register(3).1, 3);
+        store.release(epoch);
+        assert_eq!(store.version_count(), 0);
+    }
+
+    #[test]
+    fn test_snapshot_sees_the_tree_as_it_was() {
+        let (file_path, wal_path) = create_files("snapshot");
+
+        // A small buffer pool makes the tree write back and re-read pages while it changes.
+        let options = Options { page_size: 512, cache_size: 8, ..Options::default() };
+        let tree = BTree::open(&file_path, &wal_path, &options).unwrap();
+        for i in 0..500u32 {
+            tree.write(&i, b"old").unwrap();
+        }
+        tree.flush().unwrap();
+        tree.write(&1000u32, b"dirty").unwrap();
+
+        let mut snapshot = tree.snapshot();
+
+        for i in (0..500u32).filter(|i| i % 2 == 0) {
+            tree.delete(&i).unwrap();
+        }
+        for i in 500..1000u32 {
+            tree.write(&i, b"new").unwrap();
+        }
+        tree.write(&1u32, b"new").unwrap();
+        tree.checkpoint().unwrap();
+
+        assert_eq!(snapshot.get(&0u32).unwrap(), Some(b"old".to_vec()));
+        assert_eq!(snapshot.get(&1u32).unwrap(), Some(b"old".to_vec()));
+        assert_eq!(snapshot.get(&1000u32).unwrap(), Some(b"dirty".to_vec()));
+        assert_eq!(snapshot.get(&700u32).unwrap(), None);
+        assert_eq!(snapshot.range(0u32..).count(), 501);
+        assert_eq!(snapshot.range(..500u32).rev().map(|pair| pair.unwrap().1).filter(|val| val == b"old").count(), 500);
+
+        assert_eq!(tree.read(&0u32).unwrap(), None);
+        assert_eq!(tree.read(&1u32).unwrap(), Some(b"new".to_vec()));
+        assert_eq!(tree.range(0u32..).count(), 751);
+
+        let versions = tree.versions.clone();
+        assert!(lock(&versions).version_count() > 0);
+        drop(snapshot);
+        assert_eq!(lock(&versions).version_count(), 0);
+
+        remove_files(&file_path, &wal_path);
+    }
+
+    #[test]
+    fn test_snapshots_see_whole_transactions() {
+        // A small page and buffer pool make each transaction split, merge and evict pages.
+        let options = Options { page_size: 512, cache_size: 8, ..Options::default() };
+        let tree = Arc::new(BTree::open_in_memory(&options).unwrap());
+        for i in 0..500u32 {
+            tree.write(&i, b"old").unwrap();
+        }
+
+        let writer = {
+            let tree = tree.clone();
+            thread::spawn(move || {
+                // Each transaction moves 20 keys, so every snapshot holds 500 keys.
+                for round in 0..100u32 {
+                    let mut txn = tree.begin();
+                    for i in round * 20..round * 20 + 20 {
+                        txn.delete(&i).unwrap();
+                        txn.put(&(i + 500), b"new").unwrap();
+                    }
+                    txn.commit().unwrap();
+                }
+            })
+        };
+
+        while !writer.is_finished() {
+            let mut snapshot = tree.snapshot();
+            assert_eq!(snapshot.range(0u32..).count(), 500);
+            assert_eq!(tree.range(0u32..).count(), 500);
+        }
+
+        writer.join().unwrap();
+        assert_eq!(tree.range(2000u32..).count(), 500);
+    }
+}
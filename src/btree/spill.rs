@@ -0,0 +1,132 @@
+use btree::checksum::verify_page;
+use btree::doublewrite::PageWrites;
+use btree::error::{DbError, Result};
+use btree::file::DbFile;
+use btree::node::BTreeNode;
+use btree::store::PageStore;
+use btree::tree::BTree;
+
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Seek, Write};
+use std::path::{Path, PathBuf};
+
+/// Pages evicted from the buffer pool while they were dirty, kept out of the data file until the next checkpoint.
+///
+/// Recovery replays the WAL on top of the tree the data file holds, so the data file has to hold the tree
+/// exactly as the last checkpoint left it. An evicted page written in place would mix a later state of one
+/// part of the tree into it, such as one half of a split without the other. Evicted dirty pages are written to
+/// the spill file next to the data file instead, read back from there, and written to the data file by the
+/// next checkpoint along with the other dirty pages.
+///
+/// Nothing in the spill file is needed after a crash, since replaying the WAL makes the same changes again,
+/// so it is never synced. It is emptied when the database is opened and removed when it is closed. A tree
+/// opened on other stores than files spills into memory instead.
+pub(crate) struct Spill {
+    store: Box<dyn PageStore>,
+    /// The spill file, removed once the tree is dropped. Missing when spilling into memory.
+    path: Option<PathBuf>,
+    page_size: usize,
+    /// Position of each spilled page in the file, counted in pages.
+    slots: HashMap<u32, u64>,
+    next_slot: u64,
+}
+
+impl Spill {
+    /// Creates an empty spill file for the database at path, replacing one left behind by a crash.
+    pub(crate) fn create(path: &Path, page_size: usize) -> Result<Self> {
+        let path = path_for(path);
+        let file = DbFile::open(&path, OpenOptions::new().read(true).write(true).create(true).truncate(true))?;
+        let mut spill = Spill::new(Box::new(file), page_size);
+        spill.path = Some(path);
+        Ok(spill)
+    }
+
+    /// Spills into the given store, which has to be empty.
+    pub(crate) fn new(store: Box<dyn PageStore>, page_size: usize) -> Self {
+        Self { store, path: None, page_size, slots: HashMap::new(), next_slot: 0 }
+    }
+
+    /// Writes the node to its slot, taking a new one if the page was not spilled before.
+    pub(crate) fn write(&mut self, node: &BTreeNode) -> Result<()> {
+        let next_slot = &mut self.next_slot;
+        let slot = *self.slots.entry(node.id).or_insert_with(|| {
+            *next_slot += 1;
+            *next_slot - 1
+        });
+
+        self.store.write_page(slot * self.page_size as u64, &node.serialize(self.page_size)?)?;
+        Ok(())
+    }
+
+    /// Returns the spilled copy of the page, or None if the page was not spilled.
+    pub(crate) fn read(&mut self, page_id: u32) -> Result<Option<BTreeNode>> {
+        let buf = match self.read_page(page_id)? {
+            Some(buf) => buf,
+            None => return Ok(None),
+        };
+
+        if !verify_page(&buf, 0) {
+            return Err(DbError::Corruption { page_id });
+        }
+
+        BTree::deserialize(&buf, page_id).map(Some)
+    }
+
+    /// Forgets the page, once it was freed or is dirty in the buffer pool again.
+    pub(crate) fn remove(&mut self, page_id: u32) {
+        self.slots.remove(&page_id);
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    /// Adds every spilled page to writes, so the checkpoint writes them to the data file.
+    pub(crate) fn stage(&mut self, writes: &mut PageWrites) -> Result<()> {
+        let page_ids: Vec<u32> = self.slots.keys().cloned().collect();
+
+        for page_id in page_ids {
+            let buf = self.read_page(page_id)?.ok_or(DbError::Corruption { page_id })?;
+            writes.seek(io::SeekFrom::Start(self.page_size as u64 * u64::from(page_id)))?;
+            writes.write_all(&buf)?;
+        }
+
+        Ok(())
+    }
+
+    /// Drops every spilled page, once a checkpoint has written them to the data file.
+    pub(crate) fn clear(&mut self) -> Result<()> {
+        self.slots.clear();
+        self.next_slot = 0;
+        self.store.truncate(0)?;
+        Ok(())
+    }
+
+    fn read_page(&mut self, page_id: u32) -> Result<Option<Vec<u8>>> {
+        let slot = match self.slots.get(&page_id) {
+            Some(&slot) => slot,
+            None => return Ok(None),
+        };
+
+        let mut buf = vec![0u8; self.page_size];
+        self.store.read_page(slot * self.page_size as u64, &mut buf)?;
+        Ok(Some(buf))
+    }
+}
+
+impl Drop for Spill {
+    fn drop(&mut self) {
+        if let Some(ref path) = self.path {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+/// Path of the spill file of the database at path, which is the path with ".spill" appended.
+fn path_for(path: &Path) -> PathBuf {
+    let mut spill_path = OsString::from(path.as_os_str());
+    spill_path.push(".spill");
+    PathBuf::from(spill_path)
+}
@@ -0,0 +1,46 @@
+use std::time::Duration;
+
+/// Counters describing the work done by a database since it was opened, returned by BTree::stats().
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// Node pages read from the data file.
+    pub pages_read: u64,
+    /// Node pages written to the data file by checkpoints.
+    pub pages_written: u64,
+    /// Dirty pages evicted from the buffer pool, written to the spill file until the next checkpoint.
+    pub pages_spilled: u64,
+    /// Pages read from the data file whose checksum did not match their contents.
+    pub checksum_failures: u64,
+    /// Pages that were torn or left unwritten by a crash, put back from the double-write file on open.
+    pub pages_restored: u64,
+    /// Times the WAL was fsynced, by commits or checkpoints depending on the sync mode.
+    pub wal_syncs: u64,
+    /// Groups of commits written to the WAL together, each with a single write and at most one fsync.
+    pub wal_groups: u64,
+    /// Commits written to the WAL as part of a group.
+    pub wal_commits: u64,
+    /// Number of commits in the largest group.
+    pub largest_wal_group: u64,
+    /// Time writes, deletes, batches and transactions spent waiting for the WAL to commit them, added up.
+    pub commit_wait: Duration,
+    /// Longest time a single commit waited for the WAL.
+    pub longest_commit_wait: Duration,
+}
+
+impl Stats {
+    /// Average number of commits written to the WAL by one group.
+    pub fn average_wal_group(&self) -> f64 {
+        match self.wal_groups {
+            0 => 0.0,
+            groups => self.wal_commits as f64 / groups as f64,
+        }
+    }
+
+    /// Average time a commit waited for the WAL.
+    pub fn average_commit_wait(&self) -> Duration {
+        match self.wal_commits {
+            0 => Duration::ZERO,
+            commits => self.commit_wait.div_f64(commits as f64),
+        }
+    }
+}
@@ -0,0 +1,175 @@
+use btree::latch::lock;
+
+use std::io;
+use std::sync::{Arc, Mutex};
+
+/// Where the pages of a database are kept, the data file for a database opened with BTree::open().
+///
+/// Pages are written whole, at offsets that are a multiple of the page size, starting with the meta page at
+/// offset 0. They are read whole too, except for the meta page when the database is opened: the page size is
+/// not known yet, so only its start is read, which may be less than a page. Nothing written has to survive a
+/// crash until sync() returns. Pages are written in place,
+/// so a store whose writes can be torn by a crash needs to protect them, the way the double-write file does
+/// for the data file.
+pub trait PageStore: Send {
+    /// Fills buf with the bytes at offset. Returns an UnexpectedEof error if the store ends before buf is full.
+    fn read_page(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()>;
+
+    /// Writes buf at offset, growing the store if it ends before that.
+    fn write_page(&mut self, offset: u64, buf: &[u8]) -> io::Result<()>;
+
+    /// Makes every write and truncation made so far durable.
+    fn sync(&mut self) -> io::Result<()>;
+
+    /// Number of bytes in the store.
+    fn len(&self) -> io::Result<u64>;
+
+    fn is_empty(&self) -> io::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Cuts the store down to len bytes, or grows it with zeros.
+    fn truncate(&mut self, len: u64) -> io::Result<()>;
+}
+
+/// Where the WAL of a database is kept, the WAL file for a database opened with BTree::open().
+///
+/// Records are only ever appended, read back all at once by recovery and dropped by truncating the log after
+/// a checkpoint. A crash may leave the last append cut short, which the checksums of the records detect.
+pub trait LogStore: Send {
+    /// Writes buf at the end of the log.
+    fn append(&mut self, buf: &[u8]) -> io::Result<()>;
+
+    /// Returns everything in the log.
+    fn read_all(&mut self) -> io::Result<Vec<u8>>;
+
+    /// Makes every append and truncation made so far durable.
+    fn sync(&mut self) -> io::Result<()>;
+
+    /// Number of bytes in the log.
+    fn len(&self) -> io::Result<u64>;
+
+    fn is_empty(&self) -> io::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Cuts the log down to len bytes.
+    fn truncate(&mut self, len: u64) -> io::Result<()>;
+}
+
+/// Keeps the pages or the WAL of a database in memory, for databases that never touch the disk.
+///
+/// Clones share the same bytes, so a tree can be opened again on the stores of one that was dropped, the way a
+/// database is opened again on its files. Syncing does nothing, and nothing is left once the last clone is gone.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryStore {
+    bytes: Arc<Mutex<Vec<u8>>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl PageStore for MemoryStore {
+    fn read_page(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let bytes = lock(&self.bytes);
+        let start = offset as usize;
+
+        match bytes.get(start..start + buf.len()) {
+            Some(page) => {
+                buf.copy_from_slice(page);
+                Ok(())
+            },
+            None => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "read past the end of the store")),
+        }
+    }
+
+    fn write_page(&mut self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        let mut bytes = lock(&self.bytes);
+        let start = offset as usize;
+
+        if bytes.len() < start + buf.len() {
+            bytes.resize(start + buf.len(), 0);
+        }
+
+        bytes[start..start + buf.len()].copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        Ok(lock(&self.bytes).len() as u64)
+    }
+
+    fn truncate(&mut self, len: u64) -> io::Result<()> {
+        lock(&self.bytes).resize(len as usize, 0);
+        Ok(())
+    }
+}
+
+impl LogStore for MemoryStore {
+    fn append(&mut self, buf: &[u8]) -> io::Result<()> {
+        lock(&self.bytes).extend_from_slice(buf);
+        Ok(())
+    }
+
+    fn read_all(&mut self) -> io::Result<Vec<u8>> {
+        Ok(lock(&self.bytes).clone())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        Ok(lock(&self.bytes).len() as u64)
+    }
+
+    fn truncate(&mut self, len: u64) -> io::Result<()> {
+        lock(&self.bytes).truncate(len as usize);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LogStore, MemoryStore, PageStore};
+    use std::io;
+
+    #[test]
+    fn test_pages_round_trip() {
+        let mut store = MemoryStore::new();
+        store.write_page(512, &[7u8; 512]).unwrap();
+        assert_eq!(PageStore::len(&store).unwrap(), 1024);
+
+        let mut buf = [1u8; 512];
+        store.read_page(0, &mut buf).unwrap();
+        assert_eq!(buf, [0u8; 512]);
+        store.read_page(512, &mut buf).unwrap();
+        assert_eq!(buf, [7u8; 512]);
+
+        let err = store.read_page(1024, &mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+
+        PageStore::truncate(&mut store, 512).unwrap();
+        assert!(store.read_page(512, &mut buf).is_err());
+    }
+
+    #[test]
+    fn test_clones_share_the_log() {
+        let mut log = MemoryStore::new();
+        let mut reopened = log.clone();
+
+        log.append(b"abc").unwrap();
+        log.append(b"def").unwrap();
+        assert_eq!(reopened.read_all().unwrap(), b"abcdef");
+
+        LogStore::truncate(&mut reopened, 2).unwrap();
+        assert_eq!(log.read_all().unwrap(), b"ab");
+    }
+}
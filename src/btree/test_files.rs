@@ -0,0 +1,26 @@
+//! Temporary files for the tests that open a database on disk.
+//!
+//! Every test names its own files, so tests running at the same time never share a database.
+
+use btree::doublewrite;
+
+use std::env;
+use std::fs;
+use std::path::Path;
+
+/// Returns the paths of the data file and the WAL of a database named after the test, in the temp directory.
+/// Files left behind by an earlier run are removed. The WAL sits where Db::open() looks for it by default.
+pub(crate) fn create_files(name: &str) -> (String, String) {
+    let file_path = env::temp_dir().join(format!("rust_db_{}.bin", name)).to_str().unwrap().to_string();
+    let wal_path = format!("{}.wal", file_path);
+
+    remove_files(&file_path, &wal_path);
+    (file_path, wal_path)
+}
+
+/// Removes the data file, the WAL and the double-write file of a test database.
+pub(crate) fn remove_files(file_path: &str, wal_path: &str) {
+    let _ = fs::remove_file(file_path);
+    let _ = fs::remove_file(wal_path);
+    let _ = fs::remove_file(doublewrite::path_for(Path::new(file_path)));
+}
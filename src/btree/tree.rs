@@ -1,618 +1,2218 @@
-const BRANCHING_FACTOR: u16 = 5;
-const LEVELS: usize = 3;
-const MAX_PAGE_BYTES: u16 = 4096;
-const MIN_PAGE_BYTES: u16 = 4096;
-
-
 extern crate linked_hash_map;
 
-use btree::cache::LRUCache;
-use btree::node::{NodeInfo, BTreeNode};
 
-use std::collections::HashMap;
-use std::fs::File;
+use btree::alloc::PageAllocator;
+use btree::batch::WriteBatch;
+use btree::doublewrite::{self, DoubleWrite, PageWrites};
+use btree::error::{DbError, Result};
+use btree::file::DbFile;
+use btree::key::EncodeKey;
+use btree::iter::Range;
+use btree::latch::{lock, read, write, PageLatch};
+use btree::log::Log;
+use btree::pager::Pager;
+use btree::stats::Stats;
+use btree::meta::{Meta, FORMAT_VERSION, META_PAGE_ID, META_SIZE};
+use btree::snapshot::{Snapshot, VersionStore};
+use btree::spill::Spill;
+use btree::store::{LogStore, MemoryStore, PageStore};
+use btree::txn::Transaction;
+use btree::wal::{self, WalOp, WalRecord};
+use btree::node::{self, NodeInfo, BTreeNode, NODE_HEADER_SIZE, NO_SIBLING};
+use options::{Options, SyncMode};
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::TryLockError;
 use std::fs::OpenOptions;
-use std::hash::Hash;
-use std::io::{self, Read, Write, Seek};
+use std::path::Path;
+use std::io::{self, Write, Seek};
+use std::mem;
+use std::ops::{Bound, RangeBounds};
 use std::convert::TryInto;
-
-/// Main database structure that holds the cache for easy access and the file for disk reads/writes. 
+use std::sync::{Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::{Duration, Instant};
+
+/// Ids of the two nodes a node was split into, the separator key for the parent and the newly created node. 
+type SplitResult = (Option<Vec<u32>>, Option<Vec<u8>>, BTreeNode);
+
+/// Main database structure that holds the buffer pool for easy access and the file for disk reads/writes.
+///
+/// The tree can be shared between threads. Every page in the buffer pool has a read/write latch, and reads,
+/// writes and deletes descend the tree with latch coupling: the latch of a child is taken before the latch
+/// of its parent is let go of. Readers take shared latches, so any number of them run at once. Writers take
+/// turns through the writer lock and hold exclusive latches only on the part of their path the change can
+/// reach, so readers keep going everywhere else in the tree while a write is made.
 pub struct BTree {
-    file : File,
-    wal : File, 
-    cache : LRUCache,
-    dirty_pages : HashMap<u32, BTreeNode>, 
-    num_nodes : u32
+    /// The data file and the buffer pool, shared with the snapshots.
+    pager : Arc<Pager>,
+    /// The free list and the LSNs. A writer holds it for the whole of its change, so changes are made one at a time.
+    writer : Mutex<Writer>,
+    /// The WAL, written in groups shared by the writers waiting for it.
+    log : Log,
+    /// Page id of the root node, kept in the meta page. Its lock is the latch above the root page, held by
+    /// readers until they have latched the root page and by writers for as long as the root may change.
+    root : RwLock<u32>,
+    page_size : usize,
+    checkpoint_wal_size : Option<u64>,
+    checkpoint_dirty_pages : Option<usize>,
+    checkpoint_interval : Option<Duration>,
+    cache_size : usize,
+    read_only : bool,
+    /// Old versions of the pages that changed since a live snapshot was taken, shared with the snapshots.
+    pub(crate) versions : Arc<Mutex<VersionStore>>
+}
+
+/// The parts of the tree only writers use.
+struct Writer {
+    pages : PageAllocator,
+    next_lsn : u64,
+    /// Bytes currently held by the WAL, used to decide when to checkpoint.
+    wal_size : u64,
+    /// LSN of the last change written to the data file by a checkpoint.
+    checkpoint_lsn : u64,
+    last_checkpoint : Instant,
+}
+
+/// The pages read and changed by a single insert or remove, which works on copies of them.
+///
+/// The writer holds exclusive latches on the part of the path the change can reach. Changed pages are only
+/// put into the buffer pool by publish(), right before those latches are released, so readers see each page
+/// either as it was before the change or after it.
+struct Change<'a> {
+    tree : &'a BTree,
+    pages : &'a mut PageAllocator,
+    /// Page id of the root node, updated when the root is split or shrunk.
+    root : u32,
+    /// The latch on the root id, held for as long as the change may replace the root.
+    root_latch : Option<RwLockWriteGuard<'a, u32>>,
+    /// The latched pages, from the highest page the change can reach down to the leaf.
+    latched : Vec<(u32, RwLockWriteGuard<'a, BTreeNode>)>,
+    /// Copies of the pages read so far, holding the changes made to them.
+    nodes : HashMap<u32, BTreeNode>,
+    /// Ids of the changed pages, in the order they were first changed.
+    changed : Vec<u32>,
+    freed : Vec<u32>,
+    /// The pages changed or freed, as they were before the change, handed to the version store on publish().
+    originals : HashMap<u32, BTreeNode>,
 }
 
 impl BTree{
-    /// Creates new BTree by opening the file on disk as well as creating new buffers 
-    /// for the write-ahead log (WAL), and cache. 
-    pub fn new(file_path: &str, wal_path: &str) -> io::Result<BTree> {
-        let file = OpenOptions::new()
+    /// Same as new() but the page size, cache size and whether to create a missing database are taken from the options.
+    ///
+    /// A new database starts out as the meta page followed by a single empty leaf, the root. An existing database
+    /// must start with a valid meta page, whose page size replaces the one in the options.
+    ///
+    /// The data file is locked for as long as the tree or one of its snapshots is around: exclusively, or shared
+    /// when options.read_only is set. Returns a Locked error if another handle holds a conflicting lock.
+    ///
+    /// Pages a crash left torn in the middle of a write are put back from the double-write file next to the data
+    /// file, before anything else is read. A read-only tree cannot do that, so it returns a RecoveryNeeded error.
+    pub fn open<P: AsRef<Path>, Q: AsRef<Path>>(file_path: P, wal_path: Q, options: &Options) -> Result<BTree> {
+        options.validate()?;
+
+        let mut file = DbFile::open(&file_path, OpenOptions::new()
             .read(true)
-            .write(true)
-            .create(true) // <--------- this
-            .open(file_path)?;
+            .write(!options.read_only)
+            .create(options.create_if_missing && !options.read_only)
+            .truncate(false))?;
+
+        BTree::lock_file(&file, options.read_only)?;
 
-        let wal = OpenOptions::new()
+        let wal = DbFile::open(wal_path, OpenOptions::new()
             .read(true)
-            .append(true)
-            .open(wal_path)?;
+            .append(!options.read_only)
+            .create(!options.read_only))?;
+
+        let sync = options.sync_mode != SyncMode::Never;
+        let mut double_write = DoubleWrite::open(doublewrite::path_for(file_path.as_ref()), options.read_only, sync)?;
+        let mut restored = 0;
+
+        if file.len()? > 0 {
+            restored = BTree::restore_pages(&mut file, &mut double_write, options.read_only)?;
+        }
+
+        let meta = BTree::load_or_create(&mut file, options.page_size)?;
 
-        let cache = LRUCache::new();
-        let dirty_pages = HashMap::new();
-        let metadata = file.metadata()?;
-        let num_nodes:u32 = (metadata.len()/4096).try_into().expect(
-            "Conversion error: u64 to u32. There are more than 
-            4294967295 nodes meaning the file on disk was externally modified.
-            Please pass in a valid database file.");
+        let spill = match options.read_only {
+            true => None,
+            false => Some(Spill::create(file_path.as_ref(), meta.page_size as usize)?),
+        };
 
-        Ok(Self { file, wal, cache, dirty_pages, num_nodes})   
+        let tree = BTree::assemble(Box::new(file), Box::new(wal), meta, double_write, spill, options)?;
+        tree.pager.count_restored(restored);
+        Ok(tree)
     }
 
-    /// Encodes the input key into a number to handle different input types. This way we can mantain integer values for
-    /// all keys and keep the ability to perform quick range queries regardless of the user entegers an integer or some 
-    /// other form of data for the key. 
-    fn encode_key(&self, key: &str){
+    /// Same as open() but the pages and the WAL are kept in the given stores instead of files. An empty page
+    /// store gets a new database.
+    ///
+    /// Pages are written in place, without a double-write file, and the stores are not locked, so they must not
+    /// be shared with another tree that writes to them. Dirty pages evicted from the buffer pool are kept in memory.
+    pub fn open_with_stores(mut pages: Box<dyn PageStore>, log: Box<dyn LogStore>, options: &Options) -> Result<BTree> {
+        options.validate()?;
+
+        let meta = BTree::load_or_create(&mut *pages, options.page_size)?;
+        let double_write = DoubleWrite::in_place(options.sync_mode != SyncMode::Never);
+
+        let spill = match options.read_only {
+            true => None,
+            false => Some(Spill::new(Box::new(MemoryStore::new()), meta.page_size as usize)),
+        };
 
+        BTree::assemble(pages, log, meta, double_write, spill, options)
     }
 
-    /// Searches the B-Tree for the page based on the node id passed by the user. First checks cache and dirty pages buffer
-    /// for most recently accessed pages to avoid performing additional I/O operations. If not found, the system will search
-    /// on the disk and newly accessed pages from the disk get moved into the cache. 
-    fn get(&mut self, key: u32) -> &BTreeNode {
-        if self.cache.contains_key(key){
-            return self.cache.get(key);
-        } else if self.dirty_pages.contains_key(&key) {
-            return self.dirty_pages.get(&key).unwrap();
-        } else {
-            let node = self.read_node_from_file(key.try_into().unwrap()).unwrap();
-            self.cache.insert(key, node);
-            return self.cache.get(key);
+    /// Creates a new, empty database that is kept in memory and gone once the tree is dropped.
+    pub fn open_in_memory(options: &Options) -> Result<BTree> {
+        BTree::open_with_stores(Box::new(MemoryStore::new()), Box::new(MemoryStore::new()), options)
+    }
+
+    /// Builds the tree on top of the opened stores, whose meta page is meta.
+    fn assemble(mut file: Box<dyn PageStore>, wal: Box<dyn LogStore>, meta: Meta, double_write: DoubleWrite,
+                spill: Option<Spill>, options: &Options) -> Result<BTree> {
+        let wal_size = wal.len()?;
+        let page_size = meta.page_size as usize;
+        let pages = PageAllocator::load(&mut *file, page_size, meta.free_list_head, meta.page_count)?;
+
+        let writer = Writer {
+            pages,
+            next_lsn: meta.checkpoint_lsn + 1,
+            wal_size,
+            checkpoint_lsn: meta.checkpoint_lsn,
+            last_checkpoint: Instant::now(),
+        };
+
+        Ok(Self {
+            pager: Arc::new(Pager::new(file, double_write, spill, page_size, options.cache_size)),
+            writer: Mutex::new(writer),
+            log: Log::new(wal, options.sync_mode, meta.checkpoint_lsn),
+            root: RwLock::new(meta.root),
+            page_size,
+            checkpoint_wal_size: options.checkpoint_wal_size,
+            checkpoint_dirty_pages: options.checkpoint_dirty_pages,
+            checkpoint_interval: options.checkpoint_interval,
+            cache_size: options.cache_size,
+            read_only: options.read_only,
+            versions: Arc::new(Mutex::new(VersionStore::default())),
+        })
+    }
+
+    /// Takes an advisory lock on the data file, shared for a read-only handle and exclusive otherwise. The lock
+    /// is released by the OS once the file is closed, including when the process dies.
+    fn lock_file(file: &DbFile, read_only: bool) -> Result<()> {
+        let locked = match read_only {
+            true => file.try_lock_shared(),
+            false => file.try_lock(),
+        };
+
+        match locked {
+            Ok(()) => Ok(()),
+            Err(TryLockError::WouldBlock) => Err(DbError::Locked),
+            Err(TryLockError::Error(err)) => Err(DbError::Io(err)),
         }
     }
 
-    /// Same as the get() method but returns a mutable reference to the page/node for write() method. 
-    fn get_mut(&mut self, key: u32) -> &mut BTreeNode {
-        if self.cache.contains_key(key){
-            return self.cache.get_mut(key).unwrap();
-        } else if self.dirty_pages.contains_key(&key) {
-            return self.dirty_pages.get_mut(&key).unwrap();
-        } else {
-            let node = self.read_node_from_file(key.try_into().unwrap()).unwrap();
-            self.cache.insert(key, node);
-            return self.cache.get_mut(key).unwrap();
+    /// Puts back the pages of the last batch written to the data file that a crash left torn or unwritten, and
+    /// returns how many there were.
+    fn restore_pages(file: &mut dyn PageStore, double_write: &mut DoubleWrite, read_only: bool) -> Result<usize> {
+        if !read_only {
+            return double_write.restore(file);
+        }
+
+        match double_write.damaged(file)?.len() {
+            0 => Ok(0),
+            pending => Err(DbError::RecoveryNeeded { pending }),
         }
     }
 
-    //Remove
-    fn get_object(&mut self, key: u32) -> BTreeNode {
-        if self.cache.contains_key(key) {
-            return self.cache.remove(key);
-        } else if self.dirty_pages.contains_key(&key) {
-            return self.dirty_pages.remove(&key).unwrap();
-        } else {
-            let node = self.read_node_from_file(key.try_into().unwrap()).unwrap();
-            return node;           
+    /// Returns a ReadOnly error if the tree was opened read-only.
+    fn check_writable(&self) -> Result<()> {
+        match self.read_only {
+            true => Err(DbError::ReadOnly),
+            false => Ok(()),
         }
     }
 
-    /// Returns a map containing information about a node depending on requested fields. 
-    /// 
-    /// This method is preferable to calling .get() since the latter returns a reference, meaning that the entire BTree 
-    /// class was locked until the node reference was released. This class will return information about a node to the user
-    /// and release the node reference so other class methods can be called. Additionally, by passing in a parameter of fields
-    /// needed from the start, the user can avoid having to re-search every time an additional field is needed. 
-    fn get_node_info(&mut self, node_id: u32, fields: Vec<&str>, index: Option<usize>) -> HashMap<String, NodeInfo> {
-        let node = self.get(node_id);
-        let mut node_info = HashMap::<String, NodeInfo>::new();
- 
-        for field in fields {
-            if let Some(field_value) = node.get_field_info(field, index.unwrap()) {
-                node_info.insert(field.to_string(), field_value);
-            } else {
-                println!("Invalid field: {}", field);
-            }        
+    /// Returns the meta page of the store, writing a new database into it first if it is empty.
+    fn load_or_create(file: &mut dyn PageStore, page_size: usize) -> Result<Meta> {
+        match file.is_empty()? {
+            true => BTree::create_database(file, page_size),
+            false => BTree::load_meta(file),
+        }
+    }
+
+    /// Writes the meta page and an empty root leaf into an empty file and returns the meta page.
+    fn create_database(file: &mut dyn PageStore, page_size: usize) -> Result<Meta> {
+        let meta = Meta::new(page_size);
+        let mut root = BTreeNode::new();
+        root.id = meta.root;
+
+        file.write_page(0, &meta.serialize())?;
+        file.write_page(page_size as u64 * u64::from(root.id), &root.serialize(page_size)?)?;
+        file.sync()?;
+        Ok(meta)
+    }
+
+    /// Reads and validates the meta page at the start of the file. Only the first META_SIZE bytes are read, since
+    /// the page size is stored in them, see PageStore.
+    fn load_meta(file: &mut dyn PageStore) -> Result<Meta> {
+        let mut buf = vec![0u8; file.len()?.min(META_SIZE as u64) as usize];
+        file.read_page(0, &mut buf)?;
+        Meta::deserialize(&buf)
+    }
+
+    /// Encodes the input key into a byte string to handle different input types. The encoding preserves the order of
+    /// the original keys when compared byte by byte, so we keep the ability to perform quick range queries regardless
+    /// of whether the user enters an integer, a string, or a tuple of those for the key.
+    fn encode_key<K: EncodeKey + ?Sized>(&self, key: &K) -> Vec<u8> {
+        key.encode_key()
+    }
+
+    /// Returns a copy of the node stored at page_id, read through the buffer pool. First checks the buffer pool
+    /// for most recently accessed pages to avoid performing additional I/O operations. If not found, the page is
+    /// read from the disk and moved into the buffer pool.
+    pub(crate) fn node(&self, page_id: u32) -> Result<BTreeNode> {
+        Ok(read(&*self.pager.fetch(page_id)?).clone())
+    }
+
+    /// Returns the counters collected since the database was opened.
+    pub fn stats(&self) -> Stats {
+        let log = self.log.stats();
+
+        Stats {
+            wal_syncs: log.syncs,
+            wal_groups: log.groups,
+            wal_commits: log.commits,
+            largest_wal_group: log.largest_group,
+            commit_wait: log.commit_wait,
+            longest_commit_wait: log.longest_commit_wait,
+            ..self.pager.stats()
         }
+    }
+
+    /// Page id of the root node.
+    pub(crate) fn root(&self) -> u32 {
+        *read(&self.root)
+    }
+
+    /// Returns the meta page as it is currently stored on disk, which only changes with each checkpoint.
+    pub(crate) fn read_meta(&self) -> Result<Meta> {
+        BTree::load_meta(&mut **self.pager.file())
+    }
 
-        node_info
+    pub(crate) fn pager(&self) -> &Pager {
+        &self.pager
     }
 
-    /// Loads and deserializes node from the disk into memory from the starting position specified. 
-    fn read_node_from_file(&mut self, offset: usize) -> Option<BTreeNode> {
-        let mut buf = [0u8; 4096]; //
-        self.file.seek(io::SeekFrom::Start(offset.try_into().unwrap())); 
-        self.file.read_exact(&mut buf);
-        let node = self.deserialize(& buf);
-        Some(node)
-    }    
+    /// Returns a copy of the page allocator as it is now.
+    pub(crate) fn pages(&self) -> PageAllocator {
+        lock(&self.writer).pages.clone()
+    }
+
+    /// Locks the writer. A writer that panicked may have left its counters, the page allocator or the tree half
+    /// changed, so the log is marked failed instead and every write returns an error until the database is reopened.
+    fn lock_writer(&self) -> Result<MutexGuard<'_, Writer>> {
+        self.writer.lock().map_err(|_| self.log.fail())
+    }
 
-    /// Appends write request information to the write-ahead log (WAL). Because appending to a file is 
-    /// much quicker than overriding a portion of an existing file, the WAL acts as a countermeasure in case 
-    /// the system crashes before it can flush any changes to the disk. Upon re-starting, if there 
-    /// are any writes still within the WAL, those requests will be re-executed. 
-    fn write_to_wal(&mut self, key: u16, val:u16){
-        self.wal.write_all(&key.to_le_bytes()).unwrap();
-        self.wal.write_all(&val.to_le_bytes()).unwrap();
+    /// LSN of the last change written to the data file by a checkpoint.
+    pub(crate) fn checkpoint_lsn(&self) -> u64 {
+        lock(&self.writer).checkpoint_lsn
+    }
 
-        println!("Wrote key and val {} {} to WAL", key, val);
-    }   
+    /// Number of bytes in the WAL.
+    pub(crate) fn wal_len(&self) -> u64 {
+        lock(&self.writer).wal_size
+    }
 
-    /// Deserializes the sequence of bytes retreived from the disk into BTreeNode so that the 
-    /// data is usable by the application. 
-    fn deserialize(&self, buf: &[u8; 4096]) -> BTreeNode {
-        let id = u32::from_le_bytes(buf[0..4].try_into().unwrap());
-        let leaf = buf[4];
-        let num_keys = u16::from_le_bytes(buf[5..7].try_into().unwrap());
+    /// Deserializes the sequence of bytes retreived from the disk into BTreeNode so that the
+    /// data is usable by the application.
+    ///
+    /// Returns a Corruption error if the page does not describe a valid node stored at that page.
+    pub(crate) fn deserialize(buf: &[u8], page_id: u32) -> Result<BTreeNode> {
+        let corruption = DbError::Corruption { page_id };
+
+        let id = read_u32(buf, 4);
+        let leaf = buf[8];
+        let num_keys = u16::from_le_bytes([buf[9], buf[10]]);
+        let prev_leaf = read_u32(buf, 11);
+        let next_leaf = read_u32(buf, 15);
+
+        if id != page_id || leaf > 1 {
+            return Err(corruption);
+        }
 
         let mut keys = Vec::new();
         let mut children = Vec::new();
         let mut vals = Vec::new();
+        let mut offset: usize = NODE_HEADER_SIZE;
 
-        for i in 0..num_keys {
-            let offset: usize = (7+i*2).into();
-            let key = u16::from_le_bytes(buf[offset..offset+2].try_into().unwrap());
-            keys.push(key);
+        for _ in 0..num_keys {
+            keys.push(read_bytes(buf, &mut offset).ok_or(DbError::Corruption { page_id })?);
         }
 
         match leaf == 1 {
             true => {
-                for i in 0..num_keys {
-                    let offset: usize = (7+num_keys*2+i*2).into();
-                    let val = u16::from_le_bytes(buf[offset..offset+2].try_into().unwrap());
-                    vals.push(val);
+                for _ in 0..num_keys {
+                    vals.push(read_bytes(buf, &mut offset).ok_or(DbError::Corruption { page_id })?);
                 }
             }
             false => {
-                for i in 0..num_keys {
-                    let offset: usize = (7+num_keys*2+i*4).into();
-                    let child = u32::from_le_bytes(buf[offset..offset+4].try_into().unwrap());
-                    children.push(child);
-                }        
+                // Internal nodes always have one more child than keys.
+                for _ in 0..=num_keys {
+                    if offset + 4 > buf.len() {
+                        return Err(corruption);
+                    }
+
+                    children.push(read_u32(buf, offset));
+                    offset += 4;
+                }
             }
         }
 
-        let node = BTreeNode::new_from_params(id, leaf, num_keys, keys, children, vals);
+        let mut node = BTreeNode::new_from_params(id, leaf, num_keys, keys, children, vals);
+        node.prev_leaf = prev_leaf;
+        node.next_leaf = next_leaf;
 
-        node
-    }       
+        Ok(node)
+    }
 
-    /// Writes the key-value pair to the WAL and the appropriate B-Tree Node and stores those changes in the dirty pages buffer. 
-    /// If the node becomes full, the tree will call the rebalance () function to split the node into two and update the parent 
-    /// node. 
-    pub fn write(&mut self, key: u16, val: u16) {
-        self.write_to_wal(key, val);
+    /// Writes the key-value pair to the WAL and the appropriate B-Tree Node and stores those changes in the dirty pages buffer.
+    /// If the node becomes full, the tree will call the rebalance () function to split the node into two and update the parent
+    /// node.
+    ///
+    /// Keys and values are arbitrary byte strings, but a single entry has to fit within a quarter of a page
+    /// so that splitting a full node always leaves two halves that fit into a page. If the write fails after
+    /// it started changing the tree, the database has to be reopened, see log_write().
+    pub fn write<K: EncodeKey + ?Sized>(&self, key: &K, val: &[u8]) -> Result<()> {
+        let key = &self.encode_key(key)[..];
+        self.check_writable()?;
+        self.check_entry_size(key, val)?;
+
+        let lsn = {
+            let mut writer = self.lock_writer()?;
+            let record = writer.record(WalOp::Put, key, val);
+            self.log_write(&mut writer, record, |writer| self.insert(writer, key, val))?
+        };
 
-        //Load the root node from the file. The root will always be at the start so the offset is 0. 
-        let mut offset: u32 = 0;
-        let mut stack = vec![];
+        self.log.commit(lsn)
+    }
 
-        loop {
-            stack.push(offset);
-            let cur_node = self.get_mut(offset);
-            let index:usize = cur_node.search(key);
+    /// Returns a KeyTooLarge error if the key-value pair does not fit within a quarter of a page.
+    pub(crate) fn check_entry_size(&self, key: &[u8], val: &[u8]) -> Result<()> {
+        let size = 4 + key.len() + val.len();
+        let max = node::max_entry_size(self.page_size);
 
-            if cur_node.leaf == 1{
-                //No longer just borrowing the object, we need owernship as the obejct moves from cache to buffer. 
-                let mut removed_node = self.get_object(offset);
-                let numk : usize = removed_node.num_keys.into();
-                
-                if numk > index && removed_node.keys[index] == key {
-                    //Update the key if it already exists. Check that index is valid before trying to acess it. 
-                    removed_node.vals[index] = val;
-                } else {
-                    removed_node.keys.insert(index.try_into().unwrap(), key);
-                    removed_node.vals.insert(index.try_into().unwrap(), val);
-                    removed_node.num_keys += 1;
-                }
+        if size > max {
+            return Err(DbError::KeyTooLarge { size, max });
+        }
 
-                let num_keys = removed_node.num_keys;
-                self.dirty_pages.insert(offset, removed_node);
+        Ok(())
+    }
 
-                if num_keys == BRANCHING_FACTOR {
-                    self.handle_overflow(stack);
-                }
+    /// Applies every operation of the batch as one unit. The operations are applied in key order, so consecutive
+    /// keys find their leaf already in the buffer pool, then the batch is logged as a single WAL record, synced
+    /// at most once. Returns a KeyTooLarge error before changing anything if one of the entries is too large.
+    ///
+    /// Recovery replays the batch whole or not at all. If applying it fails halfway, the operations applied so
+    /// far stay visible until the database is reopened, and every write until then fails, see log_write().
+    pub fn apply_batch(&self, mut batch: WriteBatch) -> Result<()> {
+        self.check_writable()?;
 
-                break;
-            } else {
-                offset = cur_node.children[index];
+        if batch.is_empty() {
+            return Ok(());
+        }
+
+        for (key, val) in batch.ops() {
+            if let Some(ref val) = *val {
+                self.check_entry_size(key, val)?;
             }
         }
+
+        batch.sort();
+        let lsn = {
+            let mut writer = self.lock_writer()?;
+            let record = writer.record(WalOp::Batch, &[], &batch.serialize());
+            self.log_write(&mut writer, record, |writer| self.apply_ops(writer, batch.ops()))?
+        };
+
+        self.log.commit(lsn)
     }
 
-    /// Flushes all the modified nodes to the disk then clears the WAL and dirty pages buffer. 
-    pub fn flush(&mut self){
-        for node in self.dirty_pages.values_mut() {
-            node.write_node_to_file(&mut self.file);
+    /// Applies the puts (Some) and deletes (None) to the tree without logging them.
+    fn apply_ops(&self, writer: &mut Writer, ops: &[(Vec<u8>, Option<Vec<u8>>)]) -> Result<()> {
+        for (key, val) in ops {
+            match *val {
+                Some(ref val) => self.insert(writer, key, val)?,
+                None => self.remove(writer, key)?,
+            }
         }
 
-        self.reset_wal();
-        self.dirty_pages.clear();
-        println!("Successfully flushed changes to disk");
+        Ok(())
     }
 
-    /// Reset WAL is called upon flushing all changed nodes to the disk. Because the changes have been persisted,
-    /// there is no longer a need to keep track of the writes we have made. 
-    fn reset_wal(&mut self) {
-        self.wal.set_len(0);
-        self.wal.rewind();
+    /// Takes a snapshot of the tree as it is now. The snapshot stays the same while the tree changes, see Snapshot.
+    pub fn snapshot(&self) -> Snapshot {
+        // A change that can replace the root holds its latch until the change is published, and every other
+        // change is published under the lock of the version store, which the snapshot registers under. A write
+        // in progress is taken care of by the version store.
+        let root = read(&self.root);
+        Snapshot::new(self.pager.clone(), *root, self.cache_size, self.versions.clone())
     }
 
-    fn read_from_wal(&mut self, offset: u64) -> (u16, u16) {
-        let mut buf = [0u8; 4]; //
-        self.wal.seek(io::SeekFrom::Start(offset)); 
-        self.wal.read_exact(&mut buf);
-        let key = u16::from_le_bytes(buf[0..2].try_into().unwrap());
-        let val = u16::from_le_bytes(buf[2..4].try_into().unwrap());
-
-        println!("Recovered operation write {} {}", key, val);
-        (key, val)
+    /// Starts a transaction. Its writes are kept in the transaction until it commits, see Transaction.
+    pub fn begin(&self) -> Transaction<'_> {
+        Transaction::new(self)
     }
 
-    /// Recover is called upon startup. Checks the WAL in case the database had crashed previously. 
-    /// If WAL is not empty, then recovers the lost changes by executing all operations written to the WAL. 
-    pub fn recover(&mut self) {
-        let wal_len = self.wal.metadata().unwrap().len() ;
-        println!("Length of WAL is {}", wal_len);
-        
-        if wal_len == 0 {
-            return 
-        } else {
-            let mut offset = 0;
+    /// Applies the writes of a transaction to the tree and logs them between a Begin and a Commit record, then
+    /// waits for the WAL to commit them. A value of None deletes the key. The records of the transaction are
+    /// written to the WAL together, and recovery only replays the writes once the Commit record made it there.
+    /// If applying them fails halfway, nothing is logged and the database has to be reopened, see log_write().
+    pub(crate) fn commit(&self, writes: &BTreeMap<Vec<u8>, Option<Vec<u8>>>) -> Result<()> {
+        self.check_writable()?;
+
+        if writes.is_empty() {
+            return Ok(());
+        }
 
-            loop {
+        let lsn = {
+            let mut writer = self.lock_writer()?;
+            let records = self.transaction_records(&mut writer, writes);
 
-                if offset + 4 > wal_len {
-                    break;
+            self.log_write(&mut writer, records, |writer| {
+                for (key, val) in writes {
+                    match *val {
+                        Some(ref val) => self.insert(writer, key, val)?,
+                        None => self.remove(writer, key)?,
+                    }
                 }
 
-                let (key, val) = self.read_from_wal(offset);
-                self.write(key, val);
-                offset = offset + 4;
-            }
+                Ok(())
+            })?
+        };
 
-            self.reset_wal();
+        self.log.commit(lsn)
+    }
+
+    /// Appends write request information to the write-ahead log (WAL). Because appending to a file is
+    /// much quicker than overriding a portion of an existing file, the WAL acts as a countermeasure in case
+    /// the system crashes before it can flush any changes to the disk. Upon re-starting, if there
+    /// are any writes still within the WAL, those requests will be re-executed.
+    ///
+    /// Each request is stored as WalRecords tagged with the next log sequence numbers (LSN), and the LSN of the
+    /// last one is returned. The write is applied to the tree first and its records are only buffered once it
+    /// went through, then the caller waits for them to be written with Log::commit() once it lets go of the
+    /// writer. So a write that fails halfway never reaches the WAL, but the pages it already changed stay
+    /// visible. The log is marked failed in that case: every later write fails and the database has to be
+    /// reopened, which recovers the tree from the WAL without the failed write.
+    ///
+    /// The same goes for an error from the checkpoint taken after the write, except that the checkpoint
+    /// flushed the records of the write first, so the write comes back once the database is reopened.
+    fn log_write<F>(&self, writer: &mut Writer, records: Vec<u8>, apply: F) -> Result<u64>
+        where F: FnOnce(&mut Writer) -> Result<()> {
+        let lsn = writer.next_lsn - 1;
+        self.log.check()?;
+
+        lock(&self.versions).begin_write(self.root());
+        let applied = apply(writer);
+        lock(&self.versions).end_write();
+
+        let result = applied.and_then(|()| {
+            self.log.append(&records, lsn);
+            self.maybe_checkpoint(writer)
+        });
+
+        if result.is_err() {
+            let _ = self.log.fail();
         }
+
+        result.map(|()| lsn)
     }
 
-    /// Rebalances the B-tree when any node reaches 4096 bytes. 
-    /// 
-    /// The full node gets split into two and the parent node is updated to include
-    /// a reference to the new child node created and the key value where it begins. 
-    /// 
-    /// If the parent is also full, this process is repeated until all nodes are less than 4096 bytes. 
-    fn handle_overflow (&mut self, mut stack: Vec<u32>) {
-        let mut split_nodes: Option<Vec<u32>> = None;
-        let mut insert_key:Option<u16> = None;
+    /// Serializes the records of a transaction in one piece, ending with its Commit record.
+    fn transaction_records(&self, writer: &mut Writer, writes: &BTreeMap<Vec<u8>, Option<Vec<u8>>>) -> Vec<u8> {
+        let mut records = writer.record(WalOp::Begin, &[], &[]);
 
-        loop { 
-        
-            if stack.is_empty() {
-                if !split_nodes.is_none() {
-                    self.create_new_root(split_nodes, insert_key);        
-                }
-                break;        
-            } 
+        for (key, val) in writes {
+            match *val {
+                Some(ref val) => records.extend(writer.record(WalOp::Put, key, val)),
+                None => records.extend(writer.record(WalOp::Delete, key, &[])),
+            }
+        }
+
+        records.extend(writer.record(WalOp::Commit, &[], &[]));
+        records
+    }
 
-            let offset = stack.pop().unwrap();
-            let mut cur_node = self.get_object(offset);
+    /// Inserts the key-value pair into the leaf it belongs to without logging it. Used by write()
+    /// and when replaying the WAL.
+    fn insert(&self, writer: &mut Writer, key: &[u8], val: &[u8]) -> Result<()> {
+        self.modify(writer, key, BTree::insert_is_safe, |change| change.insert(key, val))
+    }
 
-            let modified = self.update_parent_node(&mut cur_node, insert_key, split_nodes);
+    /// A page is safe for an insert if it still has room for the largest entry, which is also more than the
+    /// separator and child id a split below it can add. Such a page does not split, so nothing above it changes.
+    fn insert_is_safe(&self, node: &BTreeNode, _is_root: bool) -> bool {
+        node.size() + node::max_entry_size(self.page_size) + 2 <= self.page_size
+    }
 
-            //Check if the current node has reached capacity. 
-            if cur_node.num_keys == BRANCHING_FACTOR {
-                let (split_nodes_cpy, 
-                    insert_key_cpy, 
-                    new_node) = self.split(&mut cur_node);
+    /// Latches the path from the root down to the leaf for key, runs f on the latched pages and publishes the
+    /// pages it changed before the latches are released.
+    ///
+    /// The exclusive latches are taken top down, each one while the latches above it are still held. Once a page
+    /// is safe, meaning the change below cannot spread up to its parent, the latches above it and the latch on
+    /// the root id are released, so readers only wait for the pages the change can reach. Only writers change
+    /// the tree and they take turns, so the path found before latching it stays the same.
+    fn modify<F>(&self, writer: &mut Writer, key: &[u8], safe: fn(&BTree, &BTreeNode, bool) -> bool, f: F) -> Result<()>
+        where F: FnOnce(&mut Change) -> Result<()> {
+        let path = self.path(key)?;
+        let mut root_latch = Some(write(&self.root));
+        let mut latched = Vec::new();
+
+        for (i, &(page_id, ref page)) in path.iter().enumerate() {
+            let node = write(page);
+
+            if safe(self, &node, i == 0) {
+                root_latch = None;
+                latched.clear();
+            }
 
-                split_nodes = split_nodes_cpy;
-                insert_key = insert_key_cpy;
+            latched.push((page_id, node));
+        }
 
-                self.dirty_pages.insert(cur_node.id, cur_node);
-                self.dirty_pages.insert(new_node.id, new_node);
-            } else {
+        let mut change = Change::new(self, &mut writer.pages, path[0].0, root_latch, latched);
+        f(&mut change)?;
+        change.publish()
+    }
 
-                if modified {
-                    self.dirty_pages.insert(cur_node.id, cur_node); 
+    /// Returns the pages on the way from the root down to the leaf for key.
+    fn path(&self, key: &[u8]) -> Result<Vec<(u32, PageLatch)>> {
+        let mut page_id = self.root();
+        let mut path = Vec::new();
+
+        loop {
+            let page = self.pager.fetch(page_id)?;
+            let child = {
+                let node = read(&page);
+                match node.is_leaf() {
+                    true => None,
+                    false => Some(node.children[node.search(key)]),
                 }
+            };
+            path.push((page_id, page));
 
-               break;
+            match child {
+                Some(child) => page_id = child,
+                None => return Ok(path),
             }
         }
     }
 
-    fn create_new_root(&mut self, split_nodes : Option<Vec<u32>>, insert_key : Option<u16>) -> () {
-        let unwrapped_split_nodes = split_nodes.unwrap();
-        let mut new_root = BTreeNode::new();
-        new_root.leaf = 0;
-        new_root.num_keys = 1;
-        new_root.keys.push(insert_key.unwrap());
-        new_root.children.push(unwrapped_split_nodes[0]);
-        new_root.children.push(unwrapped_split_nodes[1]);
+    /// Flushes all the modified nodes to the disk by taking a checkpoint.
+    pub fn flush(&self) -> Result<()> {
+        self.checkpoint()
+    }
+
+    /// Writes every dirty page to the data file and fsyncs it, then truncates the WAL down to a single checkpoint
+    /// record holding the LSN of the last change written. Recovery only has to replay the records logged after it.
+    /// The pages stay in the buffer pool as clean pages.
+    ///
+    /// The steps are ordered so a crash at any point leaves a database recovery can repair: the WAL is made
+    /// durable first, then the pages are written through the double-write file and the data file is fsynced,
+    /// and only then is the WAL truncated. With SyncMode::Never the order is kept but nothing is fsynced.
+    pub fn checkpoint(&self) -> Result<()> {
+        self.check_writable()?;
+        let mut writer = self.lock_writer()?;
+        self.write_checkpoint(&mut writer)
+    }
 
-        //swap ids so that new_root is at start of file. 
-        new_root.id = 0;
-        self.dirty_pages.insert(0, new_root);
+    fn write_checkpoint(&self, writer: &mut Writer) -> Result<()> {
+        self.log.flush()?;
+        writer.checkpoint_lsn = writer.next_lsn - 1;
+
+        // The pages, the free list and the meta page go through the double-write file as one batch, so a crash
+        // while they are written leaves either none or all of them.
+        let mut writes = PageWrites::new();
+        self.pager.stage_dirty_pages(&mut writes)?;
+        let free_list_head = writer.pages.write(&mut writes, self.page_size)?;
+        self.write_meta(&mut writes, writer, free_list_head)?;
+        writes.set_len(u64::from(writer.pages.page_count()) * self.page_size as u64);
+        self.pager.write_pages(&writes)?;
+        self.pager.mark_clean()?;
+
+        let record = WalRecord::new(writer.checkpoint_lsn, WalOp::Checkpoint, &[], &[]).serialize();
+        self.log.reset(&record, writer.checkpoint_lsn)?;
+
+        writer.wal_size = record.len() as u64;
+        writer.last_checkpoint = Instant::now();
+        Ok(())
     }
 
-    fn update_parent_node(&mut self, node : &mut BTreeNode, insert_key : Option<u16>, split_nodes : Option<Vec<u32>>) -> bool {
-        let modified = !split_nodes.is_none();
+    /// Writes the meta page describing the current root, page count, free list and checkpoint LSN.
+    fn write_meta<W: Write + Seek>(&self, file: &mut W, writer: &Writer, free_list_head: u32) -> Result<()> {
+        let meta = Meta {
+            version: FORMAT_VERSION,
+            page_size: self.page_size as u32,
+            root: self.root(),
+            page_count: writer.pages.page_count(),
+            free_list_head,
+            checkpoint_lsn: writer.checkpoint_lsn,
+        };
+
+        file.seek(io::SeekFrom::Start(u64::from(META_PAGE_ID)))?;
+        file.write_all(&meta.serialize())?;
+        Ok(())
+    }
 
-        if modified {
-            let index = node.search(insert_key.unwrap());
-            node.keys.insert(index, insert_key.unwrap());
-            node.children.insert(index+1, split_nodes.unwrap()[1]);
-            node.num_keys +=1; 
+    /// Takes a checkpoint once the WAL, the number of dirty pages or the time since the last checkpoint
+    /// goes past the limits set in the options. Called after every write and delete.
+    fn maybe_checkpoint(&self, writer: &mut Writer) -> Result<()> {
+        let wal_full = self.checkpoint_wal_size.is_some_and(|max| writer.wal_size >= max);
+        let too_dirty = self.checkpoint_dirty_pages.is_some_and(|max| self.pager.dirty_count() >= max);
+        let expired = self.checkpoint_interval.is_some_and(|interval| writer.last_checkpoint.elapsed() >= interval);
 
+        if wal_full || too_dirty || expired {
+            self.write_checkpoint(writer)?;
         }
-        modified
+
+        Ok(())
     }
 
-    fn split(&mut self, cur_node: &mut BTreeNode) -> (Option<Vec<u32>>, Option<u16>, BTreeNode) {
-        let is_leaf = cur_node.leaf == 1;
-        let split_index: usize = (BRANCHING_FACTOR/2).try_into().unwrap();
-        cur_node.num_keys = BRANCHING_FACTOR/2;
-    
-        // Split keys, children, and values
-        let (new_node_keys, new_node_children, new_node_vals) = {
-            let (keys, children, vals) = (&mut cur_node.keys, &mut cur_node.children, &mut cur_node.vals);
-            (
-                keys.split_off(split_index),
-                if is_leaf { Vec::new() } else { children.split_off(split_index) },
-                if is_leaf { vals.split_off(split_index) } else { Vec::new() },
-            )
-        };
-    
-        // Create a new node
-        let new_node_id = self.num_nodes;
-        let new_node = BTreeNode::new_from_params(
-            new_node_id,
-            if is_leaf {1} else {0},
-            new_node_keys.len().try_into().unwrap(),
-            new_node_keys,
-            new_node_children,
-            new_node_vals,
-        );
-    
-        // Update node IDs
-        self.num_nodes += 1;
-        if cur_node.id == 0 {
-            cur_node.id = self.num_nodes;
-            self.num_nodes += 1;
-        }
-    
-        // Prepare return values
-        let split_nodes = Some(vec![cur_node.id, new_node_id]);
-        let insert_key = Some(new_node.keys[0] - 1);
-    
-        (split_nodes, insert_key, new_node)
+    /// Reads every intact record from the WAL in the order they were written, see Log::read_records().
+    pub(crate) fn read_from_wal(&self) -> Result<Vec<WalRecord>> {
+        self.log.read_records()
     }
 
-    /// Searches the B-Tree for the specified key and returns the value found. 
-    pub fn read(&mut self, key: u16) -> Option<u16> {
-        let mut offset: u32 = 0;
+    /// Recover is called upon startup. Checks the WAL in case the database had crashed previously.
+    /// If WAL is not empty, then recovers the lost changes by replaying the operations logged after the last
+    /// checkpoint, and checkpoints them so the WAL can be cleared. Returns the number of operations replayed.
+    ///
+    /// A read-only tree cannot replay anything, so it returns a RecoveryNeeded error if the WAL holds operations
+    /// that were not checkpointed and leaves the WAL as it is.
+    pub fn recover(&self) -> Result<usize> {
+        let mut writer = self.lock_writer()?;
+        let records = self.log.read_records()?;
+
+        let last_lsn = match records.last() {
+            Some(record) => record.lsn,
+            None if self.read_only => return Ok(0),
+            None => {
+                self.log.reset(&[], writer.checkpoint_lsn)?;
+                writer.wal_size = 0;
+                return Ok(0);
+            }
+        };
 
-        loop {
+        // The meta page may hold a later checkpoint than the WAL if a crash hit before the WAL was truncated.
+        writer.checkpoint_lsn = records.iter()
+            .filter(|record| record.op == WalOp::Checkpoint)
+            .map(|record| record.lsn)
+            .fold(writer.checkpoint_lsn, u64::max);
 
-            let cur_node: &BTreeNode = self.get(offset);
+        // Transactions without a Commit record are thrown away, the checkpoint below drops them from the WAL.
+        let (replay, _) = wal::replayable(&records, writer.checkpoint_lsn);
+        let mut replayed = 0;
 
-            let index: usize = cur_node.search(key);
+        if self.read_only {
+            return match replay.len() {
+                0 => Ok(0),
+                pending => Err(DbError::RecoveryNeeded { pending }),
+            };
+        }
 
-            if cur_node.leaf == 1{
-                match cur_node.keys[index] == key {
-                    true => return Some(cur_node.vals[index]),
-                    false => return None,
-                }
-            } else {
-                offset = cur_node.children[index];
+        for record in &replay {
+            match record.op {
+                WalOp::Put => self.insert(&mut writer, &record.key, &record.val)?,
+                WalOp::Batch => {
+                    let batch = WriteBatch::deserialize(&record.val).ok_or_else(|| DbError::InvalidDatabase(
+                        format!("the WAL record at LSN {} does not hold a valid batch", record.lsn)))?;
+                    self.apply_ops(&mut writer, batch.ops())?;
+                    replayed += batch.len();
+                    continue;
+                },
+                _ => self.remove(&mut writer, &record.key)?,
             }
+
+            replayed += 1;
         }
+
+        writer.next_lsn = writer.next_lsn.max(last_lsn + 1);
+        self.write_checkpoint(&mut writer)?;
+        Ok(replayed)
     }
 
-    /// Searches the B-Tree for the specified key and removes the key-value pair if found. 
-    pub fn delete(&mut self, key: u16) ->  () {
-        //Look to locate the deleted key in the leaf nodes.
-        // Delete the key and its associated value if the key is discovered in a leaf node.
-        // One of the following steps should be taken if the node underflows (number of keys is less than half the maximum allowed):
-        //     Get a key by borrowing it from a sibling node if it contains more keys than the required minimum.
-        //     If the minimal number of keys is met by all of the sibling nodes, merge the underflow node with one of its siblings and modify the parent node as necessary.
-        // Remove all references to the deleted leaf node from the internal nodes of the tree.
-        // Remove the old root node and update the new one if the root node is empty.
+    /// Returns an iterator over the key-value pairs within the range, in key order. The iterator can also be
+    /// reversed with rev() to scan from the end of the range.
+    ///
+    /// Keys are returned in their encoded form. The scan only descends the tree once for each end of the range
+    /// and then follows the sibling links between leaves. Pages are loaded lazily, so I/O errors are returned
+    /// by the iterator itself. The iterator reads a snapshot taken when range() is called, so writes made while
+    /// it is in use do not show up in it.
+    pub fn range<K: EncodeKey + ?Sized, R: RangeBounds<K>>(&self, range: R) -> Range<'_> {
+        let encode = |bound: Bound<&K>| match bound {
+            Bound::Included(key) => Bound::Included(key.encode_key()),
+            Bound::Excluded(key) => Bound::Excluded(key.encode_key()),
+            Bound::Unbounded => Bound::Unbounded,
+        };
 
-        //The root will always be at the start of the file so the offset is 0. 
-        let mut offset: u32 = 0;
-        let mut stack = vec![];
+        let start = encode(range.start_bound());
+        let end = encode(range.end_bound());
+        Range::new(Box::new(self.snapshot()), start, end)
+    }
 
-        loop {
-            let cur_node = self.get_mut(offset);
+    /// Searches the B-Tree for the specified key and returns the value found.
+    pub fn read<K: EncodeKey + ?Sized>(&self, key: &K) -> Result<Option<Vec<u8>>> {
+        let key = &self.encode_key(key)[..];
+        self.lookup(key)
+    }
 
-            let index: usize = cur_node.search(key);
+    /// Searches the B-Tree for the already encoded key.
+    ///
+    /// Shared latches are coupled on the way down: the child's latch is taken before the parent's is released,
+    /// so a writer cannot change the child between the reader leaving the parent and arriving at the child.
+    pub(crate) fn lookup(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let root = read(&self.root);
+        let page = self.pager.fetch(*root)?;
+        let cur_node = read(&page);
+        drop(root);
+
+        self.lookup_from(cur_node, key)
+    }
 
-            stack.push((offset, index));
+    fn lookup_from(&self, cur_node: RwLockReadGuard<'_, BTreeNode>, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let index: usize = cur_node.search(key);
 
-            if cur_node.leaf == 1{
-                if cur_node.keys[index] == key {
-                    cur_node.keys.remove(index);
-                    cur_node.vals.remove(index);
-                    cur_node.num_keys -= 1;
-
-                    if cur_node.num_keys < MIN_PAGE_BYTES {
-                        self.handle_underflow(stack);
-                        break;
-                    }
-                }   
-            } else {
-                offset = cur_node.children[index];
+        if cur_node.leaf == 1 {
+            return match cur_node.keys.get(index) {
+                Some(found) if found.as_slice() == key => Ok(Some(cur_node.vals[index].clone())),
+                _ => Ok(None),
+            };
+        }
+
+        let page = self.pager.fetch(cur_node.children[index])?;
+        let child = read(&page);
+        drop(cur_node);
+
+        self.lookup_from(child, key)
+    }
+
+    /// Searches the B-Tree for the specified key and removes the key-value pair if found. Returns a NotFound
+    /// error if the key does not exist, in which case nothing is written to the WAL.
+    ///
+    /// If the leaf underflows (takes up less than a quarter of a page) it borrows entries from a sibling or is merged
+    /// into one, which may in turn cause the parent to underflow. See handle_underflow(). If the delete fails
+    /// after it started changing the tree, the database has to be reopened, see log_write().
+    pub fn delete<K: EncodeKey + ?Sized>(&self, key: &K) -> Result<()> {
+        let key = &self.encode_key(key)[..];
+        self.check_writable()?;
+
+        let lsn = {
+            let mut writer = self.lock_writer()?;
+
+            if self.lookup(key)?.is_none() {
+                return Err(DbError::NotFound);
             }
+
+            let record = writer.record(WalOp::Delete, key, &[]);
+            self.log_write(&mut writer, record, |writer| self.remove(writer, key))?
+        };
+
+        self.log.commit(lsn)
+    }
+
+    /// Removes the key from its leaf without logging it. Used by delete() and when replaying the WAL.
+    fn remove(&self, writer: &mut Writer, key: &[u8]) -> Result<()> {
+        self.modify(writer, key, BTree::remove_is_safe, |change| change.remove(key))
+    }
+
+    /// A page is safe for a remove if it keeps at least a quarter of a page after losing the largest entry, or
+    /// separator and child id, so it does not underflow. Shifting entries between two children of an internal
+    /// page replaces the separator between them, which can be longer, so an internal page also needs room for
+    /// the largest separator or it may have to split. The root cannot underflow, but an internal root with a
+    /// single key is replaced by its child once that key goes.
+    fn remove_is_safe(&self, node: &BTreeNode, is_root: bool) -> bool {
+        let has_room = node.is_leaf() || self.insert_is_safe(node, is_root);
+
+        match is_root {
+            true => node.is_leaf() || (node.num_keys > 1 && has_room),
+            false => has_room && node.size() >= self.min_page_bytes() + node::max_entry_size(self.page_size) + 2,
         }
+    }
+
+    /// Smallest number of bytes a node other than the root has to hold.
+    fn min_page_bytes(&self) -> usize {
+        self.page_size / 4
+    }
 
+}
+
+impl Writer {
+    /// Serializes a record tagged with the next LSN and counts it towards the size of the WAL.
+    fn record(&mut self, op: WalOp, key: &[u8], val: &[u8]) -> Vec<u8> {
+        let record = WalRecord::new(self.next_lsn, op, key, val).serialize();
+        self.wal_size += record.len() as u64;
+        self.next_lsn += 1;
+        record
     }
+}
 
-    fn check_underflow(&mut self, node_id: u32) -> bool {
-        let mut underflow = false;
+impl<'a> Change<'a> {
+    fn new(tree: &'a BTree, pages: &'a mut PageAllocator, root: u32, root_latch: Option<RwLockWriteGuard<'a, u32>>,
+           latched: Vec<(u32, RwLockWriteGuard<'a, BTreeNode>)>) -> Self {
+        Self { tree, pages, root, root_latch, latched, nodes: HashMap::new(), changed: Vec::new(), freed: Vec::new(),
+               originals: HashMap::new() }
+    }
 
-        let node = self.get(node_id);
+    /// Page id of the highest latched page. The change starts there and never reaches the pages above it.
+    fn top(&self) -> u32 {
+        self.latched[0].0
+    }
 
-        if node.num_keys < MIN_PAGE_BYTES {
-            underflow = true;
+    /// Returns the change's copy of the page, reading it first if this is the first time the change uses it.
+    /// Latched pages are copied from behind the latch, every other page is read through the buffer pool.
+    pub(crate) fn get(&mut self, key: u32) -> Result<&BTreeNode> {
+        if !self.nodes.contains_key(&key) {
+            let node = self.load(key)?;
+            self.nodes.insert(key, node);
         }
 
-        underflow
+        self.nodes.get(&key).ok_or(DbError::Corruption { page_id: key })
     }
 
-    fn handle_underflow (&mut self, mut stack: Vec<(u32, usize)>) -> () {
-        //Starting from the deepest node. The algorithm will look something like this: 
-        //if the current node has an underflow, go to the parent node. From the parent node
-        // check if any of the child's adjacent nodes has more nodes/keys than minimum. If so, 
-        // then move exactly one key . Remove the extra key
-        // from the parent's list and also remove the node count. The physical node removed also should be
-        // swapped from the lsat node on the disk in order to avoid fragmentation. If the parent is now underflowing, 
-        // repeat the process. If the root is underflowing, that is ok, so we can break if stack is empty after popping. 
+    fn load(&self, key: u32) -> Result<BTreeNode> {
+        match self.latched.iter().find(|&&(page_id, _)| page_id == key) {
+            Some((_, node)) => Ok((**node).clone()),
+            None => self.tree.node(key),
+        }
+    }
 
-        let mut underflow = true;
+    /// Same as the get() method but takes ownership of the page/node, removing it from the change's copies.
+    /// The caller is expected to put the node back with put_page() once it has been modified.
+    ///
+    /// Every change to a node, freeing it included, starts here, so this is where the change keeps the node as
+    /// it was before for the live snapshots, see publish().
+    fn get_object(&mut self, key: u32) -> Result<BTreeNode> {
+        let node = match self.nodes.remove(&key) {
+            Some(node) => node,
+            None => self.load(key)?,
+        };
 
-        loop { 
-        
-            let (parent_id, index)  = stack.pop().unwrap();
-            let parent_info = self.get_node_info(parent_id, vec!["str"], Some(index));
-            let parent_is_leaf = parent_info.get("is_leaf").expect("Boolean").as_bool();
+        if !self.changed.contains(&key) {
+            self.originals.entry(key).or_insert_with(|| node.clone());
+        }
 
-            if stack.is_empty() || underflow == false {
-                break;        
-            } else if parent_is_leaf {
-                continue;
-            } 
+        Ok(node)
+    }
 
-            let has_siblings = parent_info.get("has_siblings").expect("msg").as_bool();
-            let child_id = parent_info.get("child_id").expect("msg").as_u32();
-            let child = self.get_object(child_id);
+    /// Puts a modified node back, where it stays until the change is published.
+    fn put_page(&mut self, node: BTreeNode) -> Result<()> {
+        if !self.changed.contains(&node.id) {
+            self.changed.push(node.id);
+        }
 
-            if has_siblings {
-                let (sibling, has_extra_keys, dir) = self.select_sibling(&parent_info);
+        self.nodes.insert(node.id, node);
+        Ok(())
+    }
 
-                if has_extra_keys == false {
-                    self.merge(sibling, child);
-                } else {
-                    self.shift(sibling, child, dir);
-                }
+    /// Hands the changed pages to the buffer pool as dirty pages, drops the freed ones and stores the new root
+    /// id. The latches are released once the change is dropped at the end, so readers that were waiting for them
+    /// find every page of the change already in place.
+    ///
+    /// The version store is locked throughout and first gets the pages as they were before. Snapshots register
+    /// under the same lock, so a snapshot either reads the copies of every page of the change or sees all of
+    /// the change.
+    fn publish(mut self) -> Result<()> {
+        let versions = self.tree.versions.clone();
+        let mut versions = lock(&versions);
+
+        for (_, node) in self.originals.drain() {
+            versions.preserve(node);
+        }
 
-            } else {
-                let parent = self.get_object(parent_id);
-                self.merge(parent, child);
-            }
-
-            underflow = self.check_underflow(parent_id);
-        }
-            
-    }
-    /// Returns the sibling node with most extra-keys to remove keys from. If no sibling has extra keys
-    /// to give up to the child node, then a sibling node to merge child node into. 
-    fn select_sibling(&mut self, parent_info : &HashMap<String, NodeInfo>) -> (BTreeNode, bool, char) {
-        let mut has_extra_keys = true;
-        let mut sibling_id = None;
-        let mut dir = 'r'; 
-
-        if parent_info.contains_key("right_child") && !parent_info.contains_key("left_child") {
-            let right_sibling_id = parent_info.get("right_child_id").expect("msg").as_u32();
-            let right_sibling_info = self.get_node_info(right_sibling_id, vec!["num_keys"], None);
-            let right_sibling_num_keys = right_sibling_info.get("num_keys").expect("msg").as_u16();
-            has_extra_keys = right_sibling_num_keys > MAX_PAGE_BYTES;
-            sibling_id = Some(right_sibling_id);
-        } else if !parent_info.contains_key("right_child") && parent_info.contains_key("left_child")  {
-            let left_sibling_id = parent_info.get("left_child_id").expect("msg").as_u32();
-            let left_sibling_info = self.get_node_info(left_sibling_id, vec!["num_keys"], None);
-            let left_sibling_num_keys = left_sibling_info.get("num_keys").expect("msg").as_u16();
-            has_extra_keys = left_sibling_num_keys > MAX_PAGE_BYTES;
-            sibling_id = Some(left_sibling_id);
-            dir = 'l';
-        } else {
-            let right_sibling_id = parent_info.get("right_child_id").expect("msg").as_u32();
-            let right_sibling_info = self.get_node_info(right_sibling_id, vec!["num_keys"], None);
-            let right_sibling_num_keys = right_sibling_info.get("num_keys").expect("msg").as_u16();
-
-            let left_sibling_id = parent_info.get("left_child_id").expect("msg").as_u32();
-            let left_sibling_info = self.get_node_info(left_sibling_id, vec!["num_keys"], None);
-            let left_sibling_num_keys = left_sibling_info.get("num_keys").expect("msg").as_u16();
-
-            if left_sibling_num_keys > right_sibling_num_keys {
-                sibling_id = Some(left_sibling_id);
-                has_extra_keys = left_sibling_num_keys > MAX_PAGE_BYTES;
-                dir = 'l';
-            } else {
-                sibling_id = Some(right_sibling_id);
-                has_extra_keys = right_sibling_num_keys > MAX_PAGE_BYTES;
+        for page_id in mem::take(&mut self.changed) {
+            let node = match self.nodes.remove(&page_id) {
+                Some(node) => node,
+                None => continue,
+            };
+
+            match self.latched.iter_mut().find(|&&mut (latched_id, _)| latched_id == page_id) {
+                Some(&mut (_, ref mut latched)) => {
+                    **latched = node;
+                    self.tree.pager.pool().mark_dirty(page_id);
+                },
+                None => self.tree.pager.publish(node)?,
             }
+        }
 
+        for &page_id in &self.freed {
+            self.tree.pager.discard(page_id);
+        }
 
+        if let Some(ref mut root) = self.root_latch {
+            **root = self.root;
         }
-        let sibling = self.get_object(sibling_id.unwrap());
-        (sibling, has_extra_keys, dir)
+
+        Ok(())
     }
 
-    /// Merge node since it has less keys than the MIN value and cannot borrow from sibling. 
+    /// Returns a map containing information about a node depending on requested fields.
     ///
-    /// It can either merge with a sibling node, or if none exist, the parent node. 
-    /// This will convert the parent node into a leaf node. 
-    fn merge(&mut self, mut sibling : BTreeNode, mut child: BTreeNode){
+    /// This method is preferable to calling .get() since the latter returns a reference, meaning that the entire BTree
+    /// class was locked until the node reference was released. This class will return information about a node to the user
+    /// and release the node reference so other class methods can be called. Additionally, by passing in a parameter of fields
+    /// needed from the start, the user can avoid having to re-search every time an additional field is needed.
+    fn get_node_info(&mut self, node_id: u32, fields: Vec<&str>, index: Option<usize>) -> Result<HashMap<String, NodeInfo>> {
+        let node = self.get(node_id)?;
+        let mut node_info = HashMap::<String, NodeInfo>::new();
+
+        for field in fields {
+            match node.get_field_info(field, index.unwrap_or(0)) {
+                Some(field_value) => node_info.insert(field.to_string(), field_value),
+                None => return Err(DbError::InvalidField(field.to_string())),
+            };
+        }
 
+        Ok(node_info)
     }
 
-    /// Shift value or child node from sibling to child. Parent needs to be updated to 
-    /// reflect in change in key-range. 
-    fn shift(&mut self, mut sibling : BTreeNode, mut child: BTreeNode, dir : char){
-        
-        let (key_to_shift, insert_key) = match dir {
-            'l' => (sibling.keys.pop().unwrap(), 0),
-            'r' => (sibling.keys.remove(0), child.num_keys),
-            _ => (sibling.keys.pop().unwrap(), 0),
-        };
+    /// Inserts the key-value pair into the leaf it belongs to.
+    fn insert(&mut self, key: &[u8], val: &[u8]) -> Result<()> {
+        //Start from the highest latched page, since the change does not reach the pages above it.
+        let mut offset = self.top();
+        let mut stack = vec![];
 
-        let insert_key : usize = insert_key.into();
+        loop {
+            stack.push(offset);
+            let cur_node = self.get(offset)?;
+            let index:usize = cur_node.search(key);
 
-        sibling.num_keys = sibling.num_keys - 1;
-        child.num_keys = child.num_keys + 1; 
-        child.keys.insert(insert_key, key_to_shift);
+            if cur_node.leaf == 1{
+                //No longer just borrowing the object, we need owernship to modify it before putting it back.
+                let mut removed_node = self.get_object(offset)?;
+                let numk : usize = removed_node.num_keys.into();
 
-        if sibling.is_leaf() {
-            let val_to_shift = match dir {
-                'l' => sibling.vals.pop().unwrap(),
-                'r' => sibling.vals.remove(0),
-                _ => sibling.vals.pop().unwrap(),
-            };
+                if numk > index && removed_node.keys[index] == key {
+                    //Update the key if it already exists. Check that index is valid before trying to acess it.
+                    removed_node.vals[index] = val.to_vec();
+                } else {
+                    removed_node.keys.insert(index, key.to_vec());
+                    removed_node.vals.insert(index, val.to_vec());
+                    removed_node.num_keys += 1;
+                }
 
-            child.vals.insert(insert_key, val_to_shift);
-            
-        } else {
-            let node_to_shift = match dir {
-                'l' => sibling.children.pop().unwrap(),
-                'r' => sibling.children.remove(0),
-                _ => sibling.children.pop().unwrap(),
-            };
+                let is_full = removed_node.is_full(self.tree.page_size);
+                self.put_page(removed_node)?;
 
-            child.children.insert(insert_key, node_to_shift);
-        }
+                if is_full {
+                    self.handle_overflow(stack)?;
+                }
 
-        self.dirty_pages.insert(sibling.id, sibling);
-        self.dirty_pages.insert(child.id,  child);
+                return Ok(());
+            } else {
+                offset = cur_node.children[index];
+            }
+        }
     }
 
+    /// Rebalances the B-tree when any node no longer fits into a page.
+    ///
+    /// The full node gets split into two and the parent node is updated to include
+    /// a reference to the new child node created and the key value where it begins.
+    ///
+    /// If the parent is also full, this process is repeated until all nodes fit into a page again.
+    fn handle_overflow (&mut self, mut stack: Vec<u32>) -> Result<()> {
+        let mut split_nodes: Option<Vec<u32>> = None;
+        let mut insert_key:Option<Vec<u8>> = None;
 
-}
+        while let Some(offset) = stack.pop() {
+            let mut cur_node = self.get_object(offset)?;
 
-#[cfg(test)]
-mod tests {
-    // Note this useful idiom: importing names from outer (for mod tests) scope.
+            let modified = self.update_parent_node(&mut cur_node, insert_key.take(), split_nodes.take());
 
-    #[test]
-    fn test_add() {
-        assert_eq!(true, true)
-    }
+            //Check if the current node has reached capacity.
+            if cur_node.is_full(self.tree.page_size) {
+                let (split_nodes_cpy,
+                    insert_key_cpy,
+                    new_node) = self.split(&mut cur_node)?;
 
-    #[test]
-    fn test_bad_add() {
-        // This assert would fire and test will fail.
-        // Please note, that private functions can be tested too!
-        assert_eq!(true, false)
-    }
-}
\ No newline at end of file
+                split_nodes = split_nodes_cpy;
+                insert_key = insert_key_cpy;
+
+                self.put_page(cur_node)?;
+                self.put_page(new_node)?;
+            } else {
+
+                if modified {
+                    self.put_page(cur_node)?;
+                }
+
+                return Ok(());
+            }
+        }
+
+        // The root itself was split.
+        self.create_new_root(split_nodes, insert_key)
+    }
+
+    fn create_new_root(&mut self, split_nodes : Option<Vec<u32>>, insert_key : Option<Vec<u8>>) -> Result<()> {
+        if let (Some(split_nodes), Some(insert_key)) = (split_nodes, insert_key) {
+            let mut new_root = BTreeNode::new();
+            new_root.leaf = 0;
+            new_root.num_keys = 1;
+            new_root.keys.push(insert_key);
+            new_root.children.push(split_nodes[0]);
+            new_root.children.push(split_nodes[1]);
+
+            new_root.id = self.allocate_page();
+            self.root = new_root.id;
+            self.put_page(new_root)?;
+        }
+
+        Ok(())
+    }
+
+    fn update_parent_node(&mut self, node : &mut BTreeNode, insert_key : Option<Vec<u8>>, split_nodes : Option<Vec<u32>>) -> bool {
+        if let (Some(insert_key), Some(split_nodes)) = (insert_key, split_nodes) {
+            let index = node.search(&insert_key);
+            node.keys.insert(index, insert_key);
+            node.children.insert(index+1, split_nodes[1]);
+            node.num_keys +=1;
+            return true;
+        }
+
+        false
+    }
+
+    /// Splits a full node into two nodes holding roughly the same number of bytes.
+    ///
+    /// Leaves keep their last key as the separator pushed into the parent, since the parent's children[i]
+    /// holds all keys less than or equal to keys[i]. Internal nodes move their middle key up instead.
+    fn split(&mut self, cur_node: &mut BTreeNode) -> Result<SplitResult> {
+        let is_leaf = cur_node.leaf == 1;
+        let split_index = cur_node.split_index();
+
+        // Split keys, children, and values
+        let (new_node_keys, new_node_children, new_node_vals) = {
+            let (keys, children, vals) = (&mut cur_node.keys, &mut cur_node.children, &mut cur_node.vals);
+
+            if is_leaf {
+                (keys.split_off(split_index), Vec::new(), vals.split_off(split_index))
+            } else {
+                (keys.split_off(split_index + 1), children.split_off(split_index + 1), Vec::new())
+            }
+        };
+
+        let separator = match is_leaf {
+            true => cur_node.keys[split_index - 1].clone(),
+            false => cur_node.keys.remove(split_index),
+        };
+        cur_node.num_keys = cur_node.keys.len().try_into().unwrap();
+
+        // Create a new node
+        let new_node_id = self.allocate_page();
+        let mut new_node = BTreeNode::new_from_params(
+            new_node_id,
+            if is_leaf {1} else {0},
+            new_node_keys.len().try_into().unwrap(),
+            new_node_keys,
+            new_node_children,
+            new_node_vals,
+        );
+
+        // Link the new leaf in between the split leaf and its old right sibling.
+        if is_leaf {
+            new_node.prev_leaf = cur_node.id;
+            new_node.next_leaf = cur_node.next_leaf;
+            cur_node.next_leaf = new_node_id;
+
+            if new_node.next_leaf != NO_SIBLING {
+                let mut next_node = self.get_object(new_node.next_leaf)?;
+                next_node.prev_leaf = new_node_id;
+                self.put_page(next_node)?;
+            }
+        }
+
+        // Prepare return values
+        let split_nodes = Some(vec![cur_node.id, new_node_id]);
+        let insert_key = Some(separator);
+
+        Ok((split_nodes, insert_key, new_node))
+    }
+
+    /// Removes the key from its leaf without logging it. Used by delete() and when replaying the WAL.
+    fn remove(&mut self, key: &[u8]) -> Result<()> {
+        //Start from the highest latched page, since the change does not reach the pages above it.
+        let mut offset = self.top();
+        let mut stack = vec![];
+
+        loop {
+            let cur_node = self.get(offset)?;
+            let index: usize = cur_node.search(key);
+
+            stack.push((offset, index));
+
+            if cur_node.is_leaf() {
+                match cur_node.keys.get(index) {
+                    Some(found) if found.as_slice() == key => (),
+                    _ => return Ok(()),
+                }
+
+                let mut removed_node = self.get_object(offset)?;
+                removed_node.keys.remove(index);
+                removed_node.vals.remove(index);
+                removed_node.num_keys -= 1;
+                self.put_page(removed_node)?;
+
+                if self.check_underflow(offset)? {
+                    self.handle_underflow(stack)?;
+                }
+
+                return Ok(());
+            } else {
+                offset = cur_node.children[index];
+            }
+        }
+    }
+
+    /// The root is allowed to hold any number of bytes, every other node has to keep at least a quarter of a page.
+    fn check_underflow(&mut self, node_id: u32) -> Result<bool> {
+        let node_info = self.get_node_info(node_id, vec!["size"], None)?;
+        let size: usize = field(&node_info, "size")?.as_u16()?.into();
+
+        Ok(node_id != self.root && size < self.tree.min_page_bytes())
+    }
+
+    /// Rebalances the B-tree after a delete leaves a node with less than a quarter of a page.
+    ///
+    /// The stack holds the path from the root to the underflowing leaf as (node id, index of the child taken).
+    /// Starting from the deepest node, the parent is used to select one of the node's adjacent siblings. If both
+    /// nodes fit into one page they get merged, which removes a key and child from the parent. Otherwise the
+    /// sibling has bytes to spare and entries are shifted over from it, which replaces their separator in the
+    /// parent. If that separator is longer and the parent no longer fits into a page, the parent is split like
+    /// after an insert. If the parent now underflows the process is repeated one level up. Finally, if the root
+    /// is left without keys, its only child becomes the new root.
+    fn handle_underflow (&mut self, mut stack: Vec<(u32, usize)>) -> Result<()> {
+        stack.pop();
+
+        while let Some((parent_id, index)) = stack.pop() {
+            let (sibling_id, dir) = self.select_sibling(parent_id, index)?;
+
+            let mut parent = self.get_object(parent_id)?;
+            let child = self.get_object(parent.children[index])?;
+            let sibling = self.get_object(sibling_id)?;
+
+            // Always merge the right node into the left one so the leaf links only change on one side.
+            let (left, right, left_index) = match dir {
+                'l' => (sibling, child, index - 1),
+                _ => (child, sibling, index),
+            };
+
+            let separator_size = if left.is_leaf() { 0 } else { 2 + parent.keys[left_index].len() };
+
+            if left.size() + right.size() - NODE_HEADER_SIZE + separator_size <= self.tree.page_size {
+                self.merge(&mut parent, left_index, left, right)?;
+            } else {
+                match dir {
+                    'l' => self.shift(&mut parent, index, left, right, dir)?,
+                    _ => self.shift(&mut parent, index, right, left, dir)?,
+                }
+            }
+
+            let is_root = parent.id == self.root;
+            let root_is_empty = is_root && parent.num_keys == 0 && !parent.is_leaf();
+            let overflows = parent.is_full(self.tree.page_size);
+            self.put_page(parent)?;
+
+            // The shift put a longer separator into the parent, which no longer fits into a page.
+            if overflows {
+                let mut path: Vec<u32> = stack.iter().map(|&(page_id, _)| page_id).collect();
+                path.push(parent_id);
+                return self.handle_overflow(path);
+            }
+
+            if root_is_empty {
+                self.shrink_root()?;
+            }
+
+            if is_root || !self.check_underflow(parent_id)? {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the sibling node with most bytes to borrow entries from, along with the direction ('l' or 'r')
+    /// of the sibling. If no sibling has extra entries to give up to the child node, it is the sibling
+    /// the child node will be merged with.
+    fn select_sibling(&mut self, parent_id: u32, index: usize) -> Result<(u32, char)> {
+        let parent_info = self.get_node_info(parent_id, vec!["num_keys"], Some(index))?;
+        let num_keys: usize = field(&parent_info, "num_keys")?.as_u16()?.into();
+
+        let sibling_size = |change: &mut Self, sibling_id: u32| -> Result<u16> {
+            let sibling_info = change.get_node_info(sibling_id, vec!["size"], None)?;
+            field(&sibling_info, "size")?.as_u16()
+        };
+
+        if index == 0 {
+            let right_info = self.get_node_info(parent_id, vec!["right_child_id"], Some(index))?;
+            Ok((field(&right_info, "right_child_id")?.as_u32()?, 'r'))
+        } else if index == num_keys {
+            let left_info = self.get_node_info(parent_id, vec!["left_child_id"], Some(index))?;
+            Ok((field(&left_info, "left_child_id")?.as_u32()?, 'l'))
+        } else {
+            let sibling_info = self.get_node_info(parent_id, vec!["left_child_id", "right_child_id"], Some(index))?;
+            let left_sibling_id = field(&sibling_info, "left_child_id")?.as_u32()?;
+            let right_sibling_id = field(&sibling_info, "right_child_id")?.as_u32()?;
+
+            if sibling_size(self, left_sibling_id)? > sibling_size(self, right_sibling_id)? {
+                Ok((left_sibling_id, 'l'))
+            } else {
+                Ok((right_sibling_id, 'r'))
+            }
+        }
+    }
+
+    /// Merge the right node into the left node since one of them has less bytes than the minimum and
+    /// together they fit into a single page.
+    ///
+    /// The separator between the two nodes is removed from the parent along with the pointer to the right node,
+    /// whose page is put on the free list. For internal nodes the separator moves down into the merged node.
+    fn merge(&mut self, parent: &mut BTreeNode, left_index: usize, mut left: BTreeNode, mut right: BTreeNode) -> Result<()> {
+        let separator = parent.keys.remove(left_index);
+        parent.children.remove(left_index + 1);
+        parent.num_keys -= 1;
+
+        if left.is_leaf() {
+            left.next_leaf = right.next_leaf;
+
+            if right.next_leaf != NO_SIBLING {
+                let mut next_node = self.get_object(right.next_leaf)?;
+                next_node.prev_leaf = left.id;
+                self.put_page(next_node)?;
+            }
+        } else {
+            left.keys.push(separator);
+            left.children.append(&mut right.children);
+        }
+
+        left.keys.append(&mut right.keys);
+        left.vals.append(&mut right.vals);
+        left.num_keys = left.keys.len().try_into().unwrap();
+
+        self.free_page(right.id);
+        self.put_page(left)
+    }
+
+    /// Shift values or child nodes from sibling to child until the child no longer underflows. The parent key
+    /// separating the two nodes is updated to reflect the change in key-range.
+    ///
+    /// Leaves move their entries directly, while internal nodes rotate keys through the parent.
+    fn shift(&mut self, parent: &mut BTreeNode, index: usize, mut sibling : BTreeNode, mut child: BTreeNode, dir : char) -> Result<()> {
+        let separator_index = if dir == 'l' { index - 1 } else { index };
+
+        while child.size() < self.tree.min_page_bytes() && sibling.num_keys > 1 {
+            let (key_to_shift, insert_key) = match dir {
+                'l' => (sibling.keys.pop().unwrap(), 0),
+                _ => (sibling.keys.remove(0), child.num_keys),
+            };
+
+            let insert_key : usize = insert_key.into();
+
+            sibling.num_keys -= 1;
+            child.num_keys += 1;
+
+            if sibling.is_leaf() {
+                let val_to_shift = match dir {
+                    'l' => sibling.vals.pop().unwrap(),
+                    _ => sibling.vals.remove(0),
+                };
+
+                child.keys.insert(insert_key, key_to_shift);
+                child.vals.insert(insert_key, val_to_shift);
+
+                parent.keys[separator_index] = match dir {
+                    'l' => sibling.keys[sibling.keys.len() - 1].clone(),
+                    _ => child.keys[child.keys.len() - 1].clone(),
+                };
+            } else {
+                let node_to_shift = match dir {
+                    'l' => sibling.children.pop().unwrap(),
+                    _ => sibling.children.remove(0),
+                };
+
+                // The old separator moves down into the child and the shifted key takes its place in the parent.
+                let separator = std::mem::replace(&mut parent.keys[separator_index], key_to_shift);
+
+                match dir {
+                    'l' => child.children.insert(0, node_to_shift),
+                    _ => child.children.push(node_to_shift),
+                }
+                child.keys.insert(insert_key, separator);
+            }
+        }
+
+        self.put_page(sibling)?;
+        self.put_page(child)
+    }
+
+    /// Replaces the root once it has no keys left. Its only child becomes the new root and the old root's page is freed.
+    fn shrink_root(&mut self) -> Result<()> {
+        let root = self.get_object(self.root)?;
+
+        self.root = root.children[0];
+        self.free_page(root.id);
+        Ok(())
+    }
+
+    /// Returns the id of a page for a new node, reusing pages freed by merges before growing the file. A page
+    /// freed earlier in the same change is in use again, so it must not be dropped by publish().
+    fn allocate_page(&mut self) -> u32 {
+        let page_id = self.pages.allocate();
+        self.freed.retain(|&id| id != page_id);
+        page_id
+    }
+
+    /// Drops a node that has been removed from the tree and puts its page on the free list. The page leaves the
+    /// buffer pool when the change is published.
+    fn free_page(&mut self, page_id: u32) {
+        self.nodes.remove(&page_id);
+        self.changed.retain(|&id| id != page_id);
+        self.freed.push(page_id);
+        self.pages.free(page_id);
+    }
+
+}
+
+/// Looks up a field returned by get_node_info().
+fn field<'a>(node_info: &'a HashMap<String, NodeInfo>, name: &str) -> Result<&'a NodeInfo> {
+    node_info.get(name).ok_or_else(|| DbError::InvalidField(name.to_string()))
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([buf[offset], buf[offset+1], buf[offset+2], buf[offset+3]])
+}
+
+/// Reads a length-prefixed byte string from the page buffer and advances the offset past it. Returns None
+/// if the string would run past the end of the page.
+fn read_bytes(buf: &[u8], offset: &mut usize) -> Option<Vec<u8>> {
+    let len_bytes = buf.get(*offset..*offset+2)?;
+    let len: usize = u16::from_le_bytes([len_bytes[0], len_bytes[1]]).into();
+    let bytes = buf.get(*offset+2..*offset+2+len)?.to_vec();
+    *offset += 2 + len;
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::BTree;
+    use btree::batch::WriteBatch;
+    use btree::check;
+    use btree::checksum::seal_page;
+    use btree::meta::FORMAT_VERSION;
+    use btree::latch::lock;
+    use btree::wal::{WalOp, WalRecord};
+    use btree::error::DbError;
+    use btree::key::EncodeKey;
+    use btree::doublewrite::{self, PageWrites};
+    use btree::store::{MemoryStore, PageStore};
+    use btree::test_files::{create_files, remove_files};
+    use options::{Options, SyncMode};
+    use std::collections::BTreeMap;
+    use std::convert::TryInto;
+    use std::fs::{self, File, OpenOptions};
+    use std::io::{self, Seek, SeekFrom, Write};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+    use std::time::Duration;
+    use std::ops::Bound;
+    use std::path::Path;
+
+    #[test]
+    fn test_variable_length_entries() {
+        let (file_path, wal_path) = create_files("variable_length");
+        let tree = BTree::open(&file_path, &wal_path, &Options::default()).unwrap();
+
+        tree.write(b"b", b"short").unwrap();
+        tree.write(b"a", &[7u8; 300]).unwrap();
+        tree.write(b"ab", b"").unwrap();
+        tree.write(b"b", b"updated").unwrap();
+
+        assert_eq!(tree.read(b"a").unwrap(), Some(vec![7u8; 300]));
+        assert_eq!(tree.read(b"ab").unwrap(), Some(Vec::new()));
+        assert_eq!(tree.read(b"b").unwrap(), Some(b"updated".to_vec()));
+        assert_eq!(tree.read(b"c").unwrap(), None);
+
+        remove_files(&file_path, &wal_path);
+    }
+
+    #[test]
+    fn test_splits_by_page_size() {
+        let (file_path, wal_path) = create_files("splits");
+        let key = |i: u32| format!("{:0>200}", i).into_bytes();
+
+        {
+            let tree = BTree::open(&file_path, &wal_path, &Options::default()).unwrap();
+
+            // Large keys force leaves and internal nodes to split after a handful of entries. 
+            for i in (0..1000).rev() {
+                tree.write(&key(i), &i.to_le_bytes()).unwrap();
+            }
+
+            assert!(tree.pages().page_count() > 50);
+            tree.flush().unwrap();
+        }
+
+        let tree = BTree::open(&file_path, &wal_path, &Options::default()).unwrap();
+        for i in 0..1000 {
+            assert_eq!(tree.read(&key(i)).unwrap(), Some(i.to_le_bytes().to_vec()));
+        }
+        assert_eq!(tree.read(b"1").unwrap(), None);
+        assert_eq!(tree.range::<[u8], _>(..).count(), 1000);
+
+        remove_files(&file_path, &wal_path);
+    }
+
+    #[test]
+    fn test_typed_keys() {
+        let (file_path, wal_path) = create_files("typed_keys");
+        let tree = BTree::open(&file_path, &wal_path, &Options::default()).unwrap();
+
+        tree.write(&-5i32, b"negative").unwrap();
+        tree.write(&7i32, b"positive").unwrap();
+        tree.write("name", b"string").unwrap();
+        tree.write(&("user", 42u64), b"tuple").unwrap();
+
+        assert_eq!(tree.read(&-5i32).unwrap(), Some(b"negative".to_vec()));
+        assert_eq!(tree.read(&7i32).unwrap(), Some(b"positive".to_vec()));
+        assert_eq!(tree.read(&String::from("name")).unwrap(), Some(b"string".to_vec()));
+        assert_eq!(tree.read(&("user", 42u64)).unwrap(), Some(b"tuple".to_vec()));
+        assert_eq!(tree.read(&("user", 43u64)).unwrap(), None);
+
+        remove_files(&file_path, &wal_path);
+    }
+
+    #[test]
+    fn test_range_scan() {
+        let (file_path, wal_path) = create_files("range_scan");
+        let tree = BTree::open(&file_path, &wal_path, &Options::default()).unwrap();
+        let val = [1u8; 300];
+
+        // Values are large enough that the keys end up spread across many leaves.
+        for i in (0..200u32).rev() {
+            tree.write(&i, &val).unwrap();
+        }
+
+        let keys = |tree: &BTree, range: (Bound<u32>, Bound<u32>), rev: bool| -> Vec<u32> {
+            let pairs: Vec<(Vec<u8>, Vec<u8>)> = match rev {
+                true => tree.range(range).rev().collect::<Result<_, _>>().unwrap(),
+                false => tree.range(range).collect::<Result<_, _>>().unwrap(),
+            };
+            pairs.iter().map(|(key, _)| u32::from_be_bytes(key[..].try_into().unwrap())).collect()
+        };
+
+        assert_eq!(keys(&tree, (Bound::Unbounded, Bound::Unbounded), false), (0..200).collect::<Vec<_>>());
+        assert_eq!(keys(&tree, (Bound::Unbounded, Bound::Unbounded), true), (0..200).rev().collect::<Vec<_>>());
+        assert_eq!(keys(&tree, (Bound::Included(10), Bound::Excluded(150)), false), (10..150).collect::<Vec<_>>());
+        assert_eq!(keys(&tree, (Bound::Excluded(10), Bound::Included(150)), true), (11..=150).rev().collect::<Vec<_>>());
+        assert_eq!(keys(&tree, (Bound::Included(190), Bound::Unbounded), false), (190..200).collect::<Vec<_>>());
+        assert_eq!(keys(&tree, (Bound::Included(50), Bound::Excluded(50)), false), Vec::<u32>::new());
+
+        // Both ends can be consumed from the same iterator without handing out a key twice.
+        let mut iter = tree.range(5u32..=9);
+        assert_eq!(iter.next().unwrap().unwrap().0, 5u32.to_be_bytes().to_vec());
+        assert_eq!(iter.next_back().unwrap().unwrap().0, 9u32.to_be_bytes().to_vec());
+        assert_eq!(iter.count(), 3);
+
+        remove_files(&file_path, &wal_path);
+    }
+
+    #[test]
+    fn test_delete_rebalances() {
+        let (file_path, wal_path) = create_files("delete");
+        let key = |i: u32| format!("{:0>200}", i).into_bytes();
+        let tree = BTree::open(&file_path, &wal_path, &Options::default()).unwrap();
+
+        for i in 0..600u32 {
+            tree.write(&key(i), &i.to_le_bytes()).unwrap();
+        }
+        let grown_nodes = tree.pages().page_count();
+
+        // Delete every other key first so nodes borrow from their siblings, then delete in 
+        // bulk so nodes have to be merged and the tree shrinks back down. 
+        for i in (0..600).step_by(2) {
+            tree.delete(&key(i)).unwrap();
+        }
+        for i in 0..600u32 {
+            let expected = if i % 2 == 0 { None } else { Some(i.to_le_bytes().to_vec()) };
+            assert_eq!(tree.read(&key(i)).unwrap(), expected);
+        }
+
+        for i in (1..590).step_by(2) {
+            tree.delete(&key(i)).unwrap();
+        }
+        assert!(matches!(tree.delete(b"missing"), Err(DbError::NotFound)));
+
+        let remaining: Vec<Vec<u8>> = tree.range::<[u8], _>(..).map(|pair| pair.unwrap().0).collect();
+        assert_eq!(remaining, (591..600).step_by(2).map(key).collect::<Vec<_>>());
+        let reversed: Vec<Vec<u8>> = tree.range::<[u8], _>(..).rev().map(|pair| pair.unwrap().0).collect();
+        assert_eq!(reversed, (591..600).step_by(2).rev().map(key).collect::<Vec<_>>());
+        assert!(tree.pages().free_count() > 0);
+
+        // Freed pages are reused before the file grows again. 
+        for i in 0..600 {
+            tree.write(&key(i), &i.to_le_bytes()).unwrap();
+        }
+        assert!(tree.pages().page_count() <= grown_nodes + 1);
+        tree.flush().unwrap();
+        drop(tree);
+
+        let tree = BTree::open(&file_path, &wal_path, &Options::default()).unwrap();
+        for i in 0..600 {
+            assert_eq!(tree.read(&key(i)).unwrap(), Some(i.to_le_bytes().to_vec()));
+        }
+
+        remove_files(&file_path, &wal_path);
+    }
+
+    #[test]
+    fn test_delete_until_empty() {
+        let (file_path, wal_path) = create_files("delete_empty");
+        let tree = BTree::open(&file_path, &wal_path, &Options::default()).unwrap();
+
+        for i in 0..300u32 {
+            tree.write(&i, &[2u8; 100]).unwrap();
+        }
+        for i in (0..300u32).rev() {
+            tree.delete(&i).unwrap();
+            assert_eq!(tree.read(&i).unwrap(), None);
+        }
+
+        assert_eq!(tree.range::<[u8], _>(..).count(), 0);
+        let root = tree.root();
+        assert!(tree.node(root).unwrap().is_leaf());
+
+        remove_files(&file_path, &wal_path);
+    }
+
+    #[test]
+    fn test_random_operations() {
+        let (file_path, wal_path) = create_files("random");
+        let tree = BTree::open(&file_path, &wal_path, &Options::default()).unwrap();
+        let mut expected = BTreeMap::new();
+        let mut seed: u64 = 42;
+
+        for _ in 0..4000 {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            let num = (seed >> 33) % 500;
+            let key = format!("{:0>width$}", num, width = (num * 7 % 300) as usize).into_bytes();
+            let val = vec![(seed >> 40) as u8; ((seed >> 8) % 400) as usize];
+
+            if (seed >> 60) < 5 {
+                let deleted = tree.delete(&key);
+                assert_eq!(deleted.is_ok(), expected.remove(&key).is_some());
+            } else {
+                tree.write(&key, &val).unwrap();
+                expected.insert(key, val);
+            }
+        }
+
+        let pairs: Vec<(Vec<u8>, Vec<u8>)> = tree.range::<[u8], _>(..).collect::<Result<_, _>>().unwrap();
+        assert_eq!(pairs, expected.into_iter().collect::<Vec<_>>());
+
+        remove_files(&file_path, &wal_path);
+    }
+
+    #[test]
+    fn test_shifts_with_mixed_key_lengths_keep_pages_in_size() {
+        // Shifting entries between siblings replaces the separator in their parent, here often with a much longer
+        // one, so parents can overflow without any insert reaching them. The small buffer pool of the last run
+        // evicts pages a change has read but not published yet.
+        for &(page_size, long, mut seed, cache_size) in &[(512, 100, 12u64, 64), (4096, 900, 0, 64), (1024, 200, 23, 8)] {
+            let tree = BTree::open_in_memory(&Options { page_size, cache_size, ..Options::default() }).unwrap();
+            let mut expected = BTreeMap::new();
+
+            for i in 0..5000 {
+                seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                let mut key = format!("{:03}", (seed >> 33) % 300).into_bytes();
+                key.resize(if (seed >> 13) % 2 == 0 { long } else { 3 }, b'x');
+
+                if (seed >> 62) < 2 {
+                    let deleted = tree.delete(&key);
+                    assert_eq!(deleted.is_ok(), expected.remove(&key).is_some());
+                } else {
+                    tree.write(&key, b"v").unwrap();
+                    expected.insert(key, b"v".to_vec());
+                }
+
+                if i % 50 == 0 {
+                    tree.checkpoint().unwrap();
+                }
+            }
+
+            let report = check::check(&tree).unwrap();
+            assert!(report.is_ok(), "page size {}: {}", page_size, report);
+
+            let pairs: Vec<(Vec<u8>, Vec<u8>)> = tree.range::<[u8], _>(..).collect::<Result<_, _>>().unwrap();
+            assert_eq!(pairs, expected.into_iter().collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn test_small_buffer_pool_writes_back_evicted_pages() {
+        let (file_path, wal_path) = create_files("small_pool");
+        let options = Options { cache_size: 3, ..Options::default() };
+        let mut expected = BTreeMap::new();
+
+        {
+            let tree = BTree::open(&file_path, &wal_path, &options).unwrap();
+
+            for i in 0..3000u32 {
+                let val = vec![i as u8; (i % 200) as usize];
+                tree.write(&(i * 7919 % 3000), &val).unwrap();
+                expected.insert((i * 7919 % 3000).encode_key(), val);
+            }
+            for i in (0..3000u32).step_by(3) {
+                tree.delete(&i).unwrap();
+                expected.remove(&i.encode_key());
+            }
+
+            // Most pages were evicted long ago, so they must have been spilled to hold the changes.
+            let cached = (0..tree.pages().page_count()).filter(|&page_id| tree.pager.pool().contains_key(page_id)).count();
+            assert!(cached <= 3 + 4);
+            let pairs: Vec<(Vec<u8>, Vec<u8>)> = tree.range::<[u8], _>(..).collect::<Result<_, _>>().unwrap();
+            assert_eq!(pairs, expected.clone().into_iter().collect::<Vec<_>>());
+            tree.flush().unwrap();
+        }
+
+        let tree = BTree::open(&file_path, &wal_path, &options).unwrap();
+        let pairs: Vec<(Vec<u8>, Vec<u8>)> = tree.range::<[u8], _>(..).collect::<Result<_, _>>().unwrap();
+        assert_eq!(pairs, expected.into_iter().collect::<Vec<_>>());
+
+        remove_files(&file_path, &wal_path);
+    }
+
+    #[test]
+    fn test_recover_replays_deletes() {
+        let (file_path, wal_path) = create_files("recover");
+
+        {
+            let tree = BTree::open(&file_path, &wal_path, &Options::default()).unwrap();
+            tree.write(b"kept", b"1").unwrap();
+            tree.write(b"deleted", b"2").unwrap();
+            tree.flush().unwrap();
+
+            // Crash before these changes are flushed. 
+            tree.write(b"new", b"3").unwrap();
+            tree.delete(b"deleted").unwrap();
+            tree.delete(b"new").unwrap();
+            tree.write(b"new", b"4").unwrap();
+        }
+
+        let tree = BTree::open(&file_path, &wal_path, &Options::default()).unwrap();
+        tree.recover().unwrap();
+
+        assert_eq!(tree.read(b"kept").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(tree.read(b"deleted").unwrap(), None);
+        assert_eq!(tree.read(b"new").unwrap(), Some(b"4".to_vec()));
+        assert_eq!(lock(&tree.writer).next_lsn, 7);
+        assert_eq!(tree.checkpoint_lsn(), 6);
+        assert_eq!(fs::metadata(&wal_path).unwrap().len(), tree.wal_len());
+        assert_eq!(tree.read_from_wal().unwrap(), vec![WalRecord::new(6, WalOp::Checkpoint, b"", b"")]);
+
+        remove_files(&file_path, &wal_path);
+    }
+
+    #[test]
+    fn test_apply_batch() {
+        let (file_path, wal_path) = create_files("batch");
+
+        {
+            let tree = BTree::open(&file_path, &wal_path, &Options::default()).unwrap();
+            tree.write(b"deleted", b"1").unwrap();
+            tree.flush().unwrap();
+
+            let mut batch = WriteBatch::new();
+            for i in (0..1000u32).rev() {
+                batch.put(&i, format!("value{}", i).as_bytes());
+            }
+            batch.delete(b"deleted");
+            batch.put(&7u32, b"replaced");
+            tree.apply_batch(batch).unwrap();
+
+            // The whole batch went into a single record after the checkpoint.
+            assert_eq!(tree.read_from_wal().unwrap().len(), 2);
+            assert_eq!(tree.read(&7u32).unwrap(), Some(b"replaced".to_vec()));
+
+            let mut batch = WriteBatch::new();
+            batch.put(b"small", b"1");
+            batch.put(b"large", &[0u8; 2000]);
+            assert!(matches!(tree.apply_batch(batch), Err(DbError::KeyTooLarge { .. })));
+            assert_eq!(tree.read(b"small").unwrap(), None);
+        }
+
+        let tree = BTree::open(&file_path, &wal_path, &Options::default()).unwrap();
+        assert_eq!(tree.recover().unwrap(), 1002);
+        assert_eq!(tree.read(&500u32).unwrap(), Some(b"value500".to_vec()));
+        assert_eq!(tree.read(&7u32).unwrap(), Some(b"replaced".to_vec()));
+        assert_eq!(tree.read(b"deleted").unwrap(), None);
+        assert_eq!(tree.range(0u32..1000).count(), 1000);
+
+        remove_files(&file_path, &wal_path);
+    }
+
+    #[test]
+    fn test_checkpoint_bounds_wal() {
+        let (file_path, wal_path) = create_files("checkpoint_wal");
+        let options = Options { checkpoint_wal_size: Some(2048), checkpoint_interval: None, ..Options::default() };
+
+        {
+            let tree = BTree::open(&file_path, &wal_path, &options).unwrap();
+
+            for i in 0..1000u32 {
+                tree.write(&i, b"value").unwrap();
+                assert!(tree.wal_len() < 2048);
+            }
+
+            assert_eq!(fs::metadata(&wal_path).unwrap().len(), tree.wal_len());
+            assert!(tree.checkpoint_lsn() > 900);
+        }
+
+        // Only the writes after the last checkpoint are replayed.
+        let tree = BTree::open(&file_path, &wal_path, &options).unwrap();
+        let replayed = tree.recover().unwrap();
+        assert!(replayed > 0 && replayed < 100);
+        assert_eq!(lock(&tree.writer).next_lsn, 1001);
+        assert_eq!(tree.range(0u32..).count(), 1000);
+
+        remove_files(&file_path, &wal_path);
+    }
+
+    #[test]
+    fn test_checkpoint_on_dirty_pages() {
+        let (file_path, wal_path) = create_files("checkpoint_dirty");
+        let options = Options { checkpoint_dirty_pages: Some(4), checkpoint_wal_size: None, checkpoint_interval: None,
+                                ..Options::default() };
+        let tree = BTree::open(&file_path, &wal_path, &options).unwrap();
+
+        for i in 0..2000u32 {
+            tree.write(&i, &[1u8; 50]).unwrap();
+            assert!(tree.pager.pool().dirty_pages().len() < 4);
+        }
+
+        assert!(tree.checkpoint_lsn() > 0);
+
+        remove_files(&file_path, &wal_path);
+    }
+
+    #[test]
+    fn test_sync_modes() {
+        let (file_path, wal_path) = create_files("sync_modes");
+        let syncs = |sync_mode: SyncMode| {
+            remove_files(&file_path, &wal_path);
+            let options = Options { sync_mode, ..Options::default() };
+            let tree = BTree::open(&file_path, &wal_path, &options).unwrap();
+
+            for i in 0..10u32 {
+                tree.write(&i, b"value").unwrap();
+            }
+            tree.delete(&0u32).unwrap();
+            let mut batch = WriteBatch::new();
+            batch.put(&100u32, b"value");
+            tree.apply_batch(batch).unwrap();
+            let commits = tree.stats().wal_syncs;
+
+            tree.flush().unwrap();
+            let flushed = tree.stats().wal_syncs;
+            drop(tree);
+
+            // Nothing is lost by a clean shutdown, whatever was synced.
+            let tree = BTree::open(&file_path, &wal_path, &options).unwrap();
+            assert_eq!(tree.range(0u32..).count(), 10);
+            (commits, flushed)
+        };
+
+        // Every one of the 12 commits is synced, and a flush syncs the WAL at least once more after truncating it.
+        for &sync_mode in &[SyncMode::Always, SyncMode::GroupCommit { interval: Duration::ZERO }] {
+            let (commits, flushed) = syncs(sync_mode);
+            assert!(commits >= 12 && flushed > commits, "{:?}: {} {}", sync_mode, commits, flushed);
+        }
+
+        // Commits are not synced, only the flush syncs the WAL.
+        for &sync_mode in &[SyncMode::GroupCommit { interval: Duration::from_secs(3600) }, SyncMode::OnFlush] {
+            let (commits, flushed) = syncs(sync_mode);
+            assert!(commits == 0 && flushed > 0, "{:?}: {} {}", sync_mode, commits, flushed);
+        }
+
+        assert_eq!(syncs(SyncMode::Never), (0, 0));
+
+        remove_files(&file_path, &wal_path);
+    }
+
+    #[test]
+    fn test_recover_stops_at_torn_tail() {
+        let (file_path, wal_path) = create_files("torn_tail");
+
+        {
+            let tree = BTree::open(&file_path, &wal_path, &Options::default()).unwrap();
+            tree.write(b"a", b"1").unwrap();
+            tree.write(b"b", b"2").unwrap();
+        }
+
+        // Simulate a crash in the middle of appending the last record, followed by garbage. 
+        let wal_len = fs::metadata(&wal_path).unwrap().len();
+        let wal = OpenOptions::new().write(true).open(&wal_path).unwrap();
+        wal.set_len(wal_len - 3).unwrap();
+        OpenOptions::new().append(true).open(&wal_path).unwrap().write_all(&[0xAB; 40]).unwrap();
+
+        let tree = BTree::open(&file_path, &wal_path, &Options::default()).unwrap();
+        tree.recover().unwrap();
+
+        assert_eq!(tree.read(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(tree.read(b"b").unwrap(), None);
+
+        remove_files(&file_path, &wal_path);
+    }
+
+    #[test]
+    fn test_corrupt_page_is_reported() {
+        let (file_path, wal_path) = create_files("corrupt_page");
+
+        drop(BTree::open(&file_path, &wal_path, &Options::default()).unwrap());
+
+        // A root leaf with a valid checksum, claiming more keys than fit into the page.
+        let mut bytes = vec![0u8; 4096];
+        bytes[4] = 1;
+        bytes[8] = 1;
+        bytes[9] = 0xFF;
+        bytes[20] = 0xFF;
+        seal_page(&mut bytes, 0);
+        let mut file = OpenOptions::new().write(true).open(&file_path).unwrap();
+        file.seek(SeekFrom::Start(4096)).unwrap();
+        file.write_all(&bytes).unwrap();
+
+        let tree = BTree::open(&file_path, &wal_path, &Options::default()).unwrap();
+        assert!(matches!(tree.read(b"key"), Err(DbError::Corruption { page_id: 1 })));
+        assert!(matches!(tree.range::<[u8], _>(..).next(), Some(Err(DbError::Corruption { page_id: 1 }))));
+        assert_eq!(tree.stats().checksum_failures, 0);
+
+        remove_files(&file_path, &wal_path);
+    }
+
+    #[test]
+    fn test_freed_pages_persist_and_stop_growth() {
+        let (file_path, wal_path) = create_files("free_pages");
+        let key = |i: u32| format!("{:0>200}", i).into_bytes();
+        let grown_size;
+
+        {
+            let tree = BTree::open(&file_path, &wal_path, &Options::default()).unwrap();
+            for i in 0..1000u32 {
+                tree.write(&key(i), b"value").unwrap();
+            }
+            tree.flush().unwrap();
+            grown_size = fs::metadata(&file_path).unwrap().len();
+
+            // Deleting keys from the middle frees pages that are not at the end of the file.
+            for i in 100..900u32 {
+                tree.delete(&key(i)).unwrap();
+            }
+            tree.flush().unwrap();
+        }
+
+        let tree = BTree::open(&file_path, &wal_path, &Options::default()).unwrap();
+        let free_count = tree.pages().free_count();
+        assert!(free_count > 50);
+
+        // Inserting the keys again reuses the free pages, so the file stays about the same size.
+        for i in 100..900u32 {
+            tree.write(&key(i), b"value").unwrap();
+        }
+        tree.flush().unwrap();
+        assert!(tree.pages().free_count() < free_count);
+        assert!(fs::metadata(&file_path).unwrap().len() <= grown_size + 4 * 4096);
+        assert_eq!(tree.range::<[u8], _>(..).count(), 1000);
+
+        // Deleting everything shrinks the file down to the meta page and the root.
+        for i in 0..1000u32 {
+            tree.delete(&key(i)).unwrap();
+        }
+        tree.flush().unwrap();
+        drop(tree);
+
+        let tree = BTree::open(&file_path, &wal_path, &Options::default()).unwrap();
+        assert_eq!(fs::metadata(&file_path).unwrap().len(), 4096 * u64::from(tree.pages().page_count()));
+        assert!(tree.pages().page_count() < 10);
+
+        remove_files(&file_path, &wal_path);
+    }
+
+    #[test]
+    fn test_meta_page_is_validated() {
+        let (file_path, wal_path) = create_files("meta_page");
+
+        // The old layout, with the root leaf in page 0, is not a database this version can open.
+        let mut bytes = vec![0u8; 4096];
+        bytes[4] = 1;
+        File::create(&file_path).unwrap().write_all(&bytes).unwrap();
+        assert!(matches!(BTree::open(&file_path, &wal_path, &Options::default()), Err(DbError::InvalidDatabase(_))));
+
+        fs::remove_file(&file_path).unwrap();
+        drop(BTree::open(&file_path, &wal_path, &Options::default()).unwrap());
+
+        let mut file = OpenOptions::new().write(true).open(&file_path).unwrap();
+        file.seek(SeekFrom::Start(8)).unwrap();
+        file.write_all(&(FORMAT_VERSION + 1).to_le_bytes()).unwrap();
+        assert!(matches!(BTree::open(&file_path, &wal_path, &Options::default()),
+                         Err(DbError::UnsupportedVersion { found, supported: FORMAT_VERSION }) if found == FORMAT_VERSION + 1));
+
+        remove_files(&file_path, &wal_path);
+    }
+
+    #[test]
+    fn test_meta_page_survives_reopen() {
+        let (file_path, wal_path) = create_files("meta_reopen");
+        let options = Options { page_size: 1024, ..Options::default() };
+
+        {
+            let tree = BTree::open(&file_path, &wal_path, &options).unwrap();
+            for i in 0..2000u32 {
+                tree.write(&i, b"value").unwrap();
+            }
+
+            // The root split a few times, moving it away from its first page.
+            assert_ne!(tree.root(), 1);
+            tree.flush().unwrap();
+        }
+
+        // The page size is taken from the file rather than the options.
+        let tree = BTree::open(&file_path, &wal_path, &Options::default()).unwrap();
+        assert_eq!(tree.page_size, 1024);
+        assert_eq!(lock(&tree.writer).next_lsn, 2001);
+        assert_eq!(tree.pages().page_count() as u64 * 1024, fs::metadata(&file_path).unwrap().len());
+        assert_eq!(tree.range(0u32..).count(), 2000);
+
+        remove_files(&file_path, &wal_path);
+    }
+
+    #[test]
+    fn test_checksum_mismatch_is_reported() {
+        let (file_path, wal_path) = create_files("checksum");
+
+        {
+            let tree = BTree::open(&file_path, &wal_path, &Options::default()).unwrap();
+            tree.write(b"key", b"value").unwrap();
+            tree.flush().unwrap();
+        }
+
+        // Flip a single bit of the stored value, which still leaves a well-formed leaf.
+        let mut bytes = fs::read(&file_path).unwrap();
+        let offset = 4096 + bytes[4096..].windows(5).position(|window| window == b"value").unwrap();
+        bytes[offset] ^= 1;
+        fs::write(&file_path, &bytes).unwrap();
+
+        let tree = BTree::open(&file_path, &wal_path, &Options::default()).unwrap();
+        assert!(matches!(tree.read(b"key"), Err(DbError::Corruption { page_id: 1 })));
+        assert_eq!(tree.stats().checksum_failures, 1);
+        assert_eq!(tree.stats().pages_read, 1);
+
+        remove_files(&file_path, &wal_path);
+    }
+
+    #[test]
+    fn test_torn_pages_are_restored_on_open() {
+        let (file_path, wal_path) = create_files("torn_pages");
+        let dwb_path = doublewrite::path_for(Path::new(&file_path));
+        let key = |i: u32| format!("{:0>100}", i).into_bytes();
+
+        {
+            let tree = BTree::open(&file_path, &wal_path, &Options::default()).unwrap();
+            for i in 0..200u32 {
+                tree.write(&key(i), b"old").unwrap();
+            }
+            tree.flush().unwrap();
+        }
+        let old = fs::read(&file_path).unwrap();
+
+        {
+            let tree = BTree::open(&file_path, &wal_path, &Options::default()).unwrap();
+            for i in 0..400u32 {
+                tree.write(&key(i), b"new").unwrap();
+            }
+            tree.flush().unwrap();
+        }
+        let new = fs::read(&file_path).unwrap();
+        assert_eq!(fs::metadata(&dwb_path).unwrap().len(), 0);
+
+        // A crash while the second checkpoint was writing its pages in place: the batch is in the double-write
+        // file, the pages up to the middle one made it, the middle one is torn and the rest are still old.
+        let mut writes = PageWrites::new();
+        for (page_id, page) in new.chunks(4096).enumerate() {
+            writes.seek(SeekFrom::Start(4096 * page_id as u64)).unwrap();
+            writes.write_all(page).unwrap();
+        }
+        fs::write(&dwb_path, doublewrite::serialize(&writes)).unwrap();
+
+        let tear = new.len() / 2 / 4096 * 4096 + 2048;
+        let mut torn = new[..tear].to_vec();
+        torn.extend_from_slice(old.get(tear..).unwrap_or(&[]));
+        fs::write(&file_path, &torn).unwrap();
+
+        let read_only = Options { read_only: true, ..Options::default() };
+        assert!(matches!(BTree::open(&file_path, &wal_path, &read_only), Err(DbError::RecoveryNeeded { .. })));
+
+        let tree = BTree::open(&file_path, &wal_path, &Options::default()).unwrap();
+        tree.recover().unwrap();
+        assert!(tree.stats().pages_restored > 0);
+        assert_eq!(fs::read(&file_path).unwrap(), new);
+        assert_eq!(fs::metadata(&dwb_path).unwrap().len(), 0);
+
+        for i in 0..400u32 {
+            assert_eq!(tree.read(&key(i)).unwrap(), Some(b"new".to_vec()));
+        }
+
+        drop(tree);
+        remove_files(&file_path, &wal_path);
+    }
+
+    #[test]
+    fn test_oversized_entry_is_rejected() {
+        let (file_path, wal_path) = create_files("oversized");
+        let tree = BTree::open(&file_path, &wal_path, &Options::default()).unwrap();
+
+        assert!(matches!(tree.write(b"key", &[0u8; 2000]), Err(DbError::KeyTooLarge { size: 2007, .. })));
+        assert_eq!(tree.read(b"key").unwrap(), None);
+
+        remove_files(&file_path, &wal_path);
+    }
+
+    #[test]
+    fn test_in_memory_tree() {
+        let tree = BTree::open_in_memory(&Options { cache_size: 3, ..Options::default() }).unwrap();
+        let mut expected = BTreeMap::new();
+
+        for i in 0..1000u32 {
+            tree.write(&i, &i.to_le_bytes()).unwrap();
+            expected.insert(i.encode_key(), i.to_le_bytes().to_vec());
+        }
+        for i in (0..1000u32).step_by(4) {
+            tree.delete(&i).unwrap();
+            expected.remove(&i.encode_key());
+        }
+
+        // Pages evicted from the tiny buffer pool were spilled into memory.
+        assert!(tree.stats().pages_spilled > 0);
+        tree.checkpoint().unwrap();
+
+        let pairs: Vec<(Vec<u8>, Vec<u8>)> = tree.range::<[u8], _>(..).collect::<Result<_, _>>().unwrap();
+        assert_eq!(pairs, expected.into_iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_memory_stores_are_reopened() {
+        let (pages, log) = (MemoryStore::new(), MemoryStore::new());
+
+        {
+            let tree = BTree::open_with_stores(Box::new(pages.clone()), Box::new(log.clone()), &Options::default()).unwrap();
+            tree.write(b"flushed", b"1").unwrap();
+            tree.flush().unwrap();
+
+            // Only in the WAL when the tree is dropped.
+            tree.write(b"logged", b"2").unwrap();
+            tree.delete(b"flushed").unwrap();
+        }
+
+        let tree = BTree::open_with_stores(Box::new(pages), Box::new(log), &Options::default()).unwrap();
+        assert_eq!(tree.read(b"flushed").unwrap(), Some(b"1".to_vec()));
+
+        tree.recover().unwrap();
+        assert_eq!(tree.read(b"flushed").unwrap(), None);
+        assert_eq!(tree.read(b"logged").unwrap(), Some(b"2".to_vec()));
+    }
+
+    /// Pages kept in memory whose reads fail once fail is set.
+    struct FailingStore {
+        pages: MemoryStore,
+        fail: Arc<AtomicBool>,
+    }
+
+    impl PageStore for FailingStore {
+        fn read_page(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+            match self.fail.load(Ordering::SeqCst) {
+                true => Err(io::Error::other("injected read error")),
+                false => self.pages.read_page(offset, buf),
+            }
+        }
+
+        fn write_page(&mut self, offset: u64, buf: &[u8]) -> io::Result<()> {
+            self.pages.write_page(offset, buf)
+        }
+
+        fn sync(&mut self) -> io::Result<()> {
+            PageStore::sync(&mut self.pages)
+        }
+
+        fn len(&self) -> io::Result<u64> {
+            PageStore::len(&self.pages)
+        }
+
+        fn truncate(&mut self, len: u64) -> io::Result<()> {
+            PageStore::truncate(&mut self.pages, len)
+        }
+    }
+
+    #[test]
+    fn test_failed_commit_is_not_logged() {
+        let (pages, log) = (MemoryStore::new(), MemoryStore::new());
+        let fail = Arc::new(AtomicBool::new(false));
+        let options = Options { page_size: 512, cache_size: 4, ..Options::default() };
+
+        {
+            let store = FailingStore { pages: pages.clone(), fail: fail.clone() };
+            let tree = BTree::open_with_stores(Box::new(store), Box::new(log.clone()), &options).unwrap();
+
+            for i in 0..200u32 {
+                tree.write(&i, b"old").unwrap();
+            }
+            tree.flush().unwrap();
+
+            // The leaf of the first key is in the buffer pool, the leaf of the second one has to be read.
+            assert_eq!(tree.read(&0u32).unwrap(), Some(b"old".to_vec()));
+            let mut txn = tree.begin();
+            txn.put(&0u32, b"new").unwrap();
+            txn.put(&100u32, b"new").unwrap();
+            fail.store(true, Ordering::SeqCst);
+            assert!(txn.commit().is_err());
+            fail.store(false, Ordering::SeqCst);
+
+            assert_eq!(tree.read(&0u32).unwrap(), Some(b"new".to_vec()));
+            assert!(tree.write(&1u32, b"new").is_err());
+            assert!(tree.delete(&2u32).is_err());
+        }
+
+        let tree = BTree::open_with_stores(Box::new(pages), Box::new(log), &options).unwrap();
+        tree.recover().unwrap();
+        assert_eq!(tree.read(&0u32).unwrap(), Some(b"old".to_vec()));
+        assert_eq!(tree.read(&100u32).unwrap(), Some(b"old".to_vec()));
+        assert_eq!(tree.range(0u32..).count(), 200);
+    }
+
+    #[test]
+    fn test_writer_panic_fails_the_log() {
+        let tree = Arc::new(BTree::open_in_memory(&Options::default()).unwrap());
+        tree.write(b"a", b"1").unwrap();
+
+        let panicked = tree.clone();
+        assert!(thread::spawn(move || {
+            let _writer = panicked.writer.lock().unwrap();
+            panic!("writer panicked");
+        }).join().is_err());
+
+        assert!(matches!(tree.write(b"b", b"2"), Err(DbError::Io(_))));
+        assert!(matches!(tree.checkpoint(), Err(DbError::Io(_))));
+        assert_eq!(tree.read(b"a").unwrap(), Some(b"1".to_vec()));
+    }
+}
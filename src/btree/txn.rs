@@ -0,0 +1,133 @@
+use btree::error::{DbError, Result};
+use btree::key::EncodeKey;
+use btree::tree::BTree;
+
+use std::collections::BTreeMap;
+
+/// A group of writes that is applied all at once or not at all, returned by BTree::begin().
+///
+/// Puts and deletes are kept in the transaction until commit(), and reads see them on top of the tree. On commit
+/// the writes are applied to the tree, then logged between a Begin and a Commit record, and the WAL is synced as
+/// the sync mode asks. Recovery throws away a transaction whose Commit record did not make it to the WAL, so a
+/// crash never leaves part of a transaction behind.
+///
+/// Other writers can change the tree while the transaction is open, and its reads see those changes under the
+/// writes of the transaction. The writes of a transaction are applied together at commit, so readers never see
+/// part of them. Dropping a transaction without committing it rolls it back.
+pub struct Transaction<'a> {
+    tree: &'a BTree,
+    /// The latest value written for each key, or None once the key was deleted.
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl<'a> Transaction<'a> {
+    pub(crate) fn new(tree: &'a BTree) -> Self {
+        Self { tree, writes: BTreeMap::new() }
+    }
+
+    /// Inserts the value for key, replacing any value already stored for it once the transaction commits.
+    /// Returns a KeyTooLarge error right away if the entry does not fit into a page.
+    pub fn put<K: EncodeKey + ?Sized>(&mut self, key: &K, val: &[u8]) -> Result<()> {
+        let key = key.encode_key();
+        self.tree.check_entry_size(&key, val)?;
+
+        self.writes.insert(key, Some(val.to_vec()));
+        Ok(())
+    }
+
+    /// Returns the value stored for key, including the writes of this transaction.
+    pub fn get<K: EncodeKey + ?Sized>(&mut self, key: &K) -> Result<Option<Vec<u8>>> {
+        let key = key.encode_key();
+
+        match self.writes.get(&key) {
+            Some(val) => Ok(val.clone()),
+            None => self.tree.lookup(&key),
+        }
+    }
+
+    /// Removes key once the transaction commits. Returns DbError::NotFound if the key is not stored, counting
+    /// the writes of this transaction.
+    pub fn delete<K: EncodeKey + ?Sized>(&mut self, key: &K) -> Result<()> {
+        if self.get(key)?.is_none() {
+            return Err(DbError::NotFound);
+        }
+
+        self.writes.insert(key.encode_key(), None);
+        Ok(())
+    }
+
+    /// Makes every write of the transaction durable and visible. If the commit fails after it started applying
+    /// the writes, the ones applied so far stay visible and every later write fails until the database is
+    /// reopened. Reopening recovers the transaction only if its records made it to the WAL, see BTree::commit().
+    pub fn commit(self) -> Result<()> {
+        self.tree.commit(&self.writes)
+    }
+
+    /// Throws away every write of the transaction. Same as dropping it.
+    pub fn rollback(self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use btree::error::DbError;
+    use btree::test_files::{create_files, remove_files};
+    use btree::tree::BTree;
+    use options::Options;
+    use btree::wal::{WalOp, WalRecord};
+    use std::fs::OpenOptions;
+    use std::io::Write;
+
+    #[test]
+    fn test_commit_and_rollback() {
+        let (file_path, wal_path) = create_files("txn_commit");
+        let tree = BTree::open(&file_path, &wal_path, &Options::default()).unwrap();
+        tree.write(b"a", b"1").unwrap();
+
+        let mut txn = tree.begin();
+        txn.put(b"b", b"2").unwrap();
+        txn.delete(b"a").unwrap();
+        assert_eq!(txn.get(b"a").unwrap(), None);
+        assert_eq!(txn.get(b"b").unwrap(), Some(b"2".to_vec()));
+        assert!(matches!(txn.delete(b"a"), Err(DbError::NotFound)));
+        txn.rollback();
+
+        assert_eq!(tree.read(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(tree.read(b"b").unwrap(), None);
+
+        let mut txn = tree.begin();
+        txn.put(b"b", b"2").unwrap();
+        txn.delete(b"a").unwrap();
+        txn.commit().unwrap();
+        drop(tree);
+
+        // Nothing was checkpointed, so the committed transaction has to come back from the WAL.
+        let tree = BTree::open(&file_path, &wal_path, &Options::default()).unwrap();
+        assert_eq!(tree.recover().unwrap(), 3);
+        assert_eq!(tree.read(b"a").unwrap(), None);
+        assert_eq!(tree.read(b"b").unwrap(), Some(b"2".to_vec()));
+
+        remove_files(&file_path, &wal_path);
+    }
+
+    #[test]
+    fn test_uncommitted_tail_is_discarded() {
+        let (file_path, wal_path) = create_files("txn_uncommitted");
+        let tree = BTree::open(&file_path, &wal_path, &Options::default()).unwrap();
+        tree.write(b"a", b"1").unwrap();
+        drop(tree);
+
+        // A crash in the middle of logging a transaction leaves a Begin record without its Commit.
+        let mut wal = OpenOptions::new().append(true).open(&wal_path).unwrap();
+        wal.write_all(&WalRecord::new(2, WalOp::Begin, b"", b"").serialize()).unwrap();
+        wal.write_all(&WalRecord::new(3, WalOp::Put, b"b", b"2").serialize()).unwrap();
+        wal.write_all(&WalRecord::new(4, WalOp::Delete, b"a", b"").serialize()).unwrap();
+        drop(wal);
+
+        let tree = BTree::open(&file_path, &wal_path, &Options::default()).unwrap();
+        assert_eq!(tree.recover().unwrap(), 1);
+        assert_eq!(tree.read(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(tree.read(b"b").unwrap(), None);
+
+        remove_files(&file_path, &wal_path);
+    }
+}
@@ -0,0 +1,194 @@
+use btree::checksum::crc32c;
+
+use std::convert::TryInto;
+
+/// Bytes used by the header of a record: checksum (4), LSN (8), op type (1) and payload length (4). 
+pub const RECORD_HEADER_SIZE: usize = 17;
+
+/// The type of operation a WAL record describes. 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WalOp {
+    Put = 1,
+    Delete = 2,
+    /// Every change up to the record's LSN has been written to the data file, so recovery can skip them.
+    Checkpoint = 3,
+    /// Starts a transaction. The puts and deletes up to the matching Commit record only count once it is logged.
+    Begin = 4,
+    Commit = 5,
+    /// A whole WriteBatch, serialized into the value of the record.
+    Batch = 6,
+}
+
+impl WalOp {
+    fn from_u8(op: u8) -> Option<WalOp> {
+        match op {
+            1 => Some(WalOp::Put),
+            2 => Some(WalOp::Delete),
+            3 => Some(WalOp::Checkpoint),
+            4 => Some(WalOp::Begin),
+            5 => Some(WalOp::Commit),
+            6 => Some(WalOp::Batch),
+            _ => None,
+        }
+    }
+}
+
+/// A single entry of the write-ahead log (WAL). 
+/// 
+/// On disk a record is laid out as 
+/// 
+/// | crc32c (4) | lsn (8) | op (1) | payload length (4) | key length (2) | key | value | 
+/// 
+/// where the checksum covers everything after itself. Records are only ever appended, so a crash while writing 
+/// leaves a torn record at the tail of the log which is detected through the length and checksum. 
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WalRecord {
+    /// Log sequence number. Every record gets a larger LSN than the records before it. 
+    pub lsn: u64,
+    pub op: WalOp,
+    pub key: Vec<u8>,
+    /// Empty for deletes, checkpoints and transaction markers. 
+    pub val: Vec<u8>,
+}
+
+impl WalRecord {
+    pub fn new(lsn: u64, op: WalOp, key: &[u8], val: &[u8]) -> Self {
+        Self { lsn, op, key: key.to_vec(), val: val.to_vec() }
+    }
+
+    /// Serializes the record, including its header and checksum, so it can be appended to the WAL. 
+    pub fn serialize(&self) -> Vec<u8> {
+        let payload_len = 2 + self.key.len() + self.val.len();
+        let mut buffer = Vec::with_capacity(RECORD_HEADER_SIZE + payload_len);
+
+        buffer.extend_from_slice(&[0u8; 4]);
+        buffer.extend_from_slice(&self.lsn.to_le_bytes());
+        buffer.push(self.op as u8);
+        buffer.extend_from_slice(&(payload_len as u32).to_le_bytes());
+        buffer.extend_from_slice(&(self.key.len() as u16).to_le_bytes());
+        buffer.extend_from_slice(&self.key);
+        buffer.extend_from_slice(&self.val);
+
+        let crc = crc32c(&buffer[4..]);
+        buffer[0..4].copy_from_slice(&crc.to_le_bytes());
+        buffer
+    }
+
+    /// Deserializes the record at the start of the buffer and returns it with the number of bytes it took up. 
+    /// 
+    /// Returns None if the buffer ends before the record does, or the record fails its checksum. Either one 
+    /// means the record was torn by a crash (or corrupted), so it and everything after it must be ignored. 
+    pub fn deserialize(buf: &[u8]) -> Option<(WalRecord, usize)> {
+        if buf.len() < RECORD_HEADER_SIZE {
+            return None;
+        }
+
+        let crc = u32::from_le_bytes(buf[0..4].try_into().unwrap());
+        let lsn = u64::from_le_bytes(buf[4..12].try_into().unwrap());
+        let op = buf[12];
+        let payload_len = u32::from_le_bytes(buf[13..17].try_into().unwrap()) as usize;
+        let record_len = RECORD_HEADER_SIZE.checked_add(payload_len)?;
+
+        if payload_len < 2 || buf.len() < record_len || crc32c(&buf[4..record_len]) != crc {
+            return None;
+        }
+
+        let payload = &buf[RECORD_HEADER_SIZE..record_len];
+        let key_len = u16::from_le_bytes(payload[0..2].try_into().unwrap()) as usize;
+
+        if 2 + key_len > payload.len() {
+            return None;
+        }
+
+        let record = WalRecord {
+            lsn,
+            op: WalOp::from_u8(op)?,
+            key: payload[2..2 + key_len].to_vec(),
+            val: payload[2 + key_len..].to_vec(),
+        };
+
+        Some((record, record_len))
+    }
+}
+
+/// Returns the puts, deletes and batches logged after after_lsn that recovery has to replay, in the order they were logged,
+/// along with the number of operations it has to throw away.
+///
+/// Operations outside of a transaction are always replayed. Those of a transaction are only replayed once its
+/// Commit record is reached, so a transaction that was cut short by a crash leaves no trace.
+pub fn replayable(records: &[WalRecord], after_lsn: u64) -> (Vec<&WalRecord>, usize) {
+    let mut replay = Vec::new();
+    let mut transaction: Option<Vec<&WalRecord>> = None;
+    let mut discarded = 0;
+
+    for record in records.iter().filter(|record| record.lsn > after_lsn) {
+        match record.op {
+            WalOp::Put | WalOp::Delete | WalOp::Batch => match transaction {
+                Some(ref mut ops) => ops.push(record),
+                None => replay.push(record),
+            },
+            // A Begin while a transaction is still open means that transaction never committed.
+            WalOp::Begin => discarded += transaction.replace(Vec::new()).map_or(0, |ops| ops.len()),
+            WalOp::Commit => replay.extend(transaction.take().unwrap_or_default()),
+            WalOp::Checkpoint => (),
+        }
+    }
+
+    (replay, discarded + transaction.map_or(0, |ops| ops.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{replayable, WalOp, WalRecord};
+
+    #[test]
+    fn test_round_trip() {
+        let put = WalRecord::new(7, WalOp::Put, b"key", b"value");
+        let delete = WalRecord::new(8, WalOp::Delete, b"key", b"");
+        let checkpoint = WalRecord::new(8, WalOp::Checkpoint, b"", b"");
+
+        let mut log = put.serialize();
+        log.extend_from_slice(&delete.serialize());
+        log.extend_from_slice(&checkpoint.serialize());
+
+        let (record, len) = WalRecord::deserialize(&log).unwrap();
+        assert_eq!(record, put);
+        let (record, next) = WalRecord::deserialize(&log[len..]).unwrap();
+        assert_eq!(record, delete);
+        assert_eq!(WalRecord::deserialize(&log[len + next..]).unwrap().0, checkpoint);
+    }
+
+    #[test]
+    fn test_torn_and_corrupt_records() {
+        let log = WalRecord::new(1, WalOp::Put, b"key", b"value").serialize();
+
+        for len in 0..log.len() {
+            assert!(WalRecord::deserialize(&log[..len]).is_none());
+        }
+
+        let mut corrupt = log.clone();
+        corrupt[20] ^= 1;
+        assert!(WalRecord::deserialize(&corrupt).is_none());
+    }
+
+    #[test]
+    fn test_uncommitted_transactions_are_not_replayed() {
+        let records = vec![
+            WalRecord::new(1, WalOp::Put, b"a", b"1"),
+            WalRecord::new(2, WalOp::Begin, b"", b""),
+            WalRecord::new(3, WalOp::Put, b"b", b"2"),
+            WalRecord::new(4, WalOp::Delete, b"a", b""),
+            WalRecord::new(5, WalOp::Commit, b"", b""),
+            WalRecord::new(6, WalOp::Put, b"c", b"3"),
+            WalRecord::new(7, WalOp::Begin, b"", b""),
+            WalRecord::new(8, WalOp::Put, b"d", b"4"),
+        ];
+
+        let (replay, discarded) = replayable(&records, 0);
+        assert_eq!(replay.iter().map(|record| record.lsn).collect::<Vec<_>>(), vec![1, 3, 4, 6]);
+        assert_eq!(discarded, 1);
+
+        let (replay, _) = replayable(&records, 4);
+        assert_eq!(replay.iter().map(|record| record.lsn).collect::<Vec<_>>(), vec![6]);
+    }
+}
@@ -0,0 +1,347 @@
+use btree::batch::WriteBatch;
+use btree::check::{self, CheckReport};
+use btree::dump::{self, DumpFormat};
+use btree::error::Result;
+use btree::iter::Range;
+use btree::key::EncodeKey;
+use btree::stats::Stats;
+use btree::snapshot::Snapshot;
+use btree::store::{LogStore, PageStore};
+use btree::tree::BTree;
+use btree::txn::Transaction;
+use options::Options;
+
+use std::ffi::OsString;
+use std::ops::RangeBounds;
+use std::path::{Path, PathBuf};
+
+/// Handle to an open database. 
+/// 
+/// Opening a database replays whatever is left in its write-ahead log (WAL), so changes that were logged but 
+/// not flushed before a crash are visible again. Changes are kept in memory until flush() is called. 
+///
+/// The handle can be shared between threads, e.g. behind an Arc. Any number of reads run at the same time,
+/// and they keep going while a write, delete or batch is applied, which only ever runs one at a time.
+/// Writers then wait for the WAL together, so a single fsync commits the writes of every waiting thread.
+/// Readers can see a write as soon as it is applied, shortly before the WAL has committed it.
+pub struct Db {
+    tree: BTree,
+}
+
+impl Db {
+    /// Opens the database at path, creating it if it is missing and options.create_if_missing is set. 
+    ///
+    /// Only one handle can have a database open for writing, so this returns DbError::Locked while another
+    /// handle has it open, from this process or another one. Read-only handles (options.read_only) can share
+    /// the database with each other, and return DbError::ReadOnly from every call that would change it.
+    pub fn open<P: AsRef<Path>>(path: P, options: Options) -> Result<Db> {
+        let path = path.as_ref();
+        let tree = BTree::open(path, wal_path(path, &options), &options)?;
+        tree.recover()?;
+
+        Ok(Db { tree })
+    }
+
+    /// Creates a new, empty database that is kept in memory instead of files, and gone once the handle is
+    /// dropped. Every option that describes files, like the WAL path, is ignored.
+    pub fn open_in_memory(options: Options) -> Result<Db> {
+        Ok(Db { tree: BTree::open_in_memory(&options)? })
+    }
+
+    /// Opens the database kept in the given stores instead of files, creating it if the page store is empty, and
+    /// replays whatever is left in the log. The stores are not locked, so only one handle may use them at a time.
+    /// Every option that describes files, like the WAL path, is ignored.
+    pub fn open_with_stores(pages: Box<dyn PageStore>, log: Box<dyn LogStore>, options: Options) -> Result<Db> {
+        let tree = BTree::open_with_stores(pages, log, &options)?;
+        tree.recover()?;
+
+        Ok(Db { tree })
+    }
+
+    /// Checks the integrity of the database at path and its WAL without opening it for use. The WAL is not
+    /// replayed, so the report describes the files exactly as they are on disk. The database is opened read-only,
+    /// so it cannot be checked while a handle has it open for writing.
+    pub fn check<P: AsRef<Path>>(path: P, options: Options) -> Result<CheckReport> {
+        let path = path.as_ref();
+        let options = Options { read_only: true, ..options };
+        let tree = BTree::open(path, wal_path(path, &options), &options)?;
+
+        check::check(&tree)
+    }
+
+    /// Describes the page page_id of the database at path, or every node of its tree level by level when
+    /// page_id is None. Like check(), the WAL is not replayed, so the pages are shown as they are on disk.
+    pub fn dump<P: AsRef<Path>>(path: P, options: Options, page_id: Option<u32>, format: DumpFormat) -> Result<String> {
+        let path = path.as_ref();
+        let options = Options { read_only: true, ..options };
+        let tree = BTree::open(path, wal_path(path, &options), &options)?;
+
+        match page_id {
+            Some(page_id) => dump::dump_page(&tree, page_id, format),
+            None => dump::dump_tree(&tree, format),
+        }
+    }
+
+    /// Renders the tree of the database at path as a Graphviz DOT graph, without replaying the WAL.
+    pub fn viz<P: AsRef<Path>>(path: P, options: Options) -> Result<String> {
+        let path = path.as_ref();
+        let options = Options { read_only: true, ..options };
+        let tree = BTree::open(path, wal_path(path, &options), &options)?;
+
+        tree.to_dot()
+    }
+
+    /// Inserts the value for key, replacing any value already stored for it. 
+    pub fn put<K: EncodeKey + ?Sized>(&self, key: &K, val: &[u8]) -> Result<()> {
+        self.tree.write(key, val)
+    }
+
+    /// Returns the value stored for key, if any. 
+    pub fn get<K: EncodeKey + ?Sized>(&self, key: &K) -> Result<Option<Vec<u8>>> {
+        self.tree.read(key)
+    }
+
+    /// Removes key from the database. Returns DbError::NotFound if it was not stored. 
+    pub fn delete<K: EncodeKey + ?Sized>(&self, key: &K) -> Result<()> {
+        self.tree.delete(key)
+    }
+
+    /// Applies every put and delete of the batch as one unit, with a single WAL record and at most one sync.
+    pub fn apply_batch(&self, batch: WriteBatch) -> Result<()> {
+        self.tree.apply_batch(batch)
+    }
+
+    /// Returns a read-only view of the database as it is now, which stays the same while the database changes.
+    pub fn snapshot(&self) -> Snapshot {
+        self.tree.snapshot()
+    }
+
+    /// Starts a transaction, whose writes become durable and visible all at once when it commits.
+    pub fn begin(&self) -> Transaction<'_> {
+        self.tree.begin()
+    }
+
+    /// Returns an iterator over the key-value pairs whose keys fall within range, in key order. 
+    pub fn range<K: EncodeKey + ?Sized, R: RangeBounds<K>>(&self, range: R) -> Range<'_> {
+        self.tree.range(range)
+    }
+
+    /// Renders the tree as a Graphviz DOT graph, colouring pages by whether they are dirty or cached.
+    pub fn to_dot(&self) -> Result<String> {
+        self.tree.to_dot()
+    }
+
+    /// Writes every changed page to disk and clears the WAL.
+    pub fn flush(&self) -> Result<()> {
+        self.tree.flush()
+    }
+
+    /// Returns the counters collected since the database was opened.
+    pub fn stats(&self) -> Stats {
+        self.tree.stats()
+    }
+
+    /// Writes every changed page to disk and truncates the WAL down to a checkpoint record. Checkpoints are
+    /// also taken automatically according to the checkpoint options.
+    pub fn checkpoint(&self) -> Result<()> {
+        self.tree.checkpoint()
+    }
+}
+
+/// Returns the WAL path set in the options, or the default one next to the database file.
+fn wal_path(path: &Path, options: &Options) -> PathBuf {
+    match options.wal_path {
+        Some(ref wal_path) => wal_path.clone(),
+        None => default_wal_path(path),
+    }
+}
+
+/// The WAL lives next to the database file, with ".wal" appended to its name. 
+fn default_wal_path(path: &Path) -> PathBuf {
+    let mut wal_path = OsString::from(path.as_os_str());
+    wal_path.push(".wal");
+    PathBuf::from(wal_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use btree::error::DbError;
+    use btree::store::MemoryStore;
+    use btree::test_files::{create_files, remove_files};
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn test_open_put_get_reopen() {
+        let (path, wal_path) = create_files("db_reopen");
+
+        let options = Options { page_size: 1024, ..Options::default() };
+        let db = Db::open(&path, options.clone()).unwrap();
+        for i in 0..500u32 {
+            db.put(&i, format!("value{}", i).as_bytes()).unwrap();
+        }
+        db.delete(&7u32).unwrap();
+        drop(db);
+
+        // Nothing was flushed, so everything has to come back from the WAL. 
+        let db = Db::open(&path, options).unwrap();
+        assert_eq!(db.get(&42u32).unwrap(), Some(b"value42".to_vec()));
+        assert_eq!(db.get(&7u32).unwrap(), None);
+        assert_eq!(db.range(10u32..20).count(), 10);
+        db.flush().unwrap();
+
+        remove_files(&path, &wal_path);
+    }
+
+    #[test]
+    fn test_db_is_shared_between_threads() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Db>();
+
+        let (path, wal_path) = create_files("db_threads");
+
+        // A small page and buffer pool make the writers split, merge and evict pages under the readers.
+        let options = Options { page_size: 512, cache_size: 16, ..Options::default() };
+        let db = Arc::new(Db::open(&path, options).unwrap());
+        for i in 0..1000u32 {
+            db.put(&i, b"stable").unwrap();
+        }
+
+        let writers: Vec<_> = (0..2u32).map(|w| {
+            let db = db.clone();
+            thread::spawn(move || {
+                for i in (1000..3000u32).filter(|i| i % 2 == w) {
+                    db.put(&i, b"new").unwrap();
+                }
+                for i in (1000..3000u32).filter(|i| i % 2 == w && i % 3 == 0) {
+                    db.delete(&i).unwrap();
+                }
+            })
+        }).collect();
+
+        let readers: Vec<_> = (0..4u32).map(|r| {
+            let db = db.clone();
+            thread::spawn(move || {
+                for round in 0..5u32 {
+                    for i in (0..1000u32).filter(|i| i % 4 == r) {
+                        assert_eq!(db.get(&i).unwrap(), Some(b"stable".to_vec()), "key {} in round {}", i, round);
+                    }
+                    assert_eq!(db.range(0u32..1000).count(), 1000);
+                }
+            })
+        }).collect();
+
+        for handle in writers.into_iter().chain(readers) {
+            handle.join().unwrap();
+        }
+
+        let kept = (1000..3000u32).filter(|i| i % 3 != 0).count();
+        assert_eq!(db.range(1000u32..).count(), kept);
+        db.flush().unwrap();
+        drop(db);
+
+        let report = Db::check(&path, Options { page_size: 512, ..Options::default() }).unwrap();
+        assert!(report.is_ok(), "{}", report);
+        assert_eq!(report.keys, 1000 + kept as u64);
+
+        remove_files(&path, &wal_path);
+    }
+
+    #[test]
+    fn test_check() {
+        let (path, wal_path) = create_files("db_check");
+
+        let db = Db::open(&path, Options::default()).unwrap();
+        for i in 0..500u32 {
+            db.put(&i, b"value").unwrap();
+        }
+        db.flush().unwrap();
+        drop(db);
+
+        let report = Db::check(&path, Options::default()).unwrap();
+        assert!(report.is_ok(), "{}", report);
+        assert_eq!(report.keys, 500);
+
+        remove_files(&path, &wal_path);
+        assert!(matches!(Db::check(&path, Options::default()), Err(DbError::Io(_))));
+    }
+
+    #[test]
+    fn test_lock_and_read_only() {
+        let (path, wal_path) = create_files("db_lock");
+
+        let read_only = Options { read_only: true, ..Options::default() };
+        assert!(matches!(Db::open(&path, read_only.clone()), Err(DbError::Io(_))));
+
+        let db = Db::open(&path, Options::default()).unwrap();
+        db.put(&1u32, b"one").unwrap();
+        assert!(matches!(Db::open(&path, Options::default()), Err(DbError::Locked)));
+        assert!(matches!(Db::open(&path, read_only.clone()), Err(DbError::Locked)));
+        assert!(matches!(Db::check(&path, Options::default()), Err(DbError::Locked)));
+        drop(db);
+
+        // The put was never checkpointed, so it has to be recovered by a handle that can write.
+        assert!(matches!(Db::open(&path, read_only.clone()), Err(DbError::RecoveryNeeded { pending: 1 })));
+        Db::open(&path, Options::default()).unwrap();
+
+        let first = Db::open(&path, read_only.clone()).unwrap();
+        let second = Db::open(&path, read_only).unwrap();
+        assert_eq!(first.get(&1u32).unwrap(), Some(b"one".to_vec()));
+        assert_eq!(second.range(0u32..).count(), 1);
+        assert!(matches!(first.put(&2u32, b"two"), Err(DbError::ReadOnly)));
+        assert!(matches!(first.delete(&1u32), Err(DbError::ReadOnly)));
+        assert!(matches!(second.flush(), Err(DbError::ReadOnly)));
+        assert!(Db::check(&path, Options::default()).unwrap().is_ok());
+        assert!(matches!(Db::open(&path, Options::default()), Err(DbError::Locked)));
+
+        drop(first);
+        drop(second);
+        remove_files(&path, &wal_path);
+    }
+
+    #[test]
+    fn test_invalid_options_are_rejected() {
+        let (path, wal_path) = create_files("db_options");
+
+        let options = Options { page_size: 1000, ..Options::default() };
+        assert!(matches!(Db::open(&path, options), Err(DbError::InvalidOptions(_))));
+
+        let options = Options { create_if_missing: false, ..Options::default() };
+        assert!(matches!(Db::open(&path, options), Err(DbError::Io(_))));
+
+        remove_files(&path, &wal_path);
+    }
+
+    #[test]
+    fn test_open_in_memory() {
+        let db = Db::open_in_memory(Options { page_size: 512, ..Options::default() }).unwrap();
+
+        for i in 0..200u32 {
+            db.put(&i, &i.to_be_bytes()).unwrap();
+        }
+        db.delete(&7u32).unwrap();
+        db.flush().unwrap();
+
+        assert_eq!(db.get(&8u32).unwrap(), Some(8u32.to_be_bytes().to_vec()));
+        assert_eq!(db.get(&7u32).unwrap(), None);
+        assert_eq!(db.range(10u32..20).count(), 10);
+        assert!(matches!(Db::open_in_memory(Options { cache_size: 0, ..Options::default() }), Err(DbError::InvalidOptions(_))));
+    }
+
+    #[test]
+    fn test_open_with_stores() {
+        let (pages, log) = (MemoryStore::new(), MemoryStore::new());
+
+        let db = Db::open_with_stores(Box::new(pages.clone()), Box::new(log.clone()), Options::default()).unwrap();
+        db.put(b"flushed", b"1").unwrap();
+        db.flush().unwrap();
+        db.put(b"logged", b"2").unwrap();
+        drop(db);
+
+        // Reopening replays the write that was only in the log.
+        let db = Db::open_with_stores(Box::new(pages), Box::new(log), Options::default()).unwrap();
+        assert_eq!(db.get(b"flushed").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(db.get(b"logged").unwrap(), Some(b"2".to_vec()));
+    }
+}
@@ -0,0 +1,20 @@
+//! RustDB is an embedded key-value store backed by an on-disk B+ tree and a write-ahead log (WAL).
+//!
+//! Open a database with Db::open() and read or write it through the returned handle.
+
+pub(crate) mod btree;
+pub(crate) mod options;
+pub(crate) mod db;
+
+pub use db::Db;
+pub use options::{Options, SyncMode, MIN_PAGE_SIZE, MAX_PAGE_SIZE};
+pub use btree::error::{DbError, Result};
+pub use btree::key::EncodeKey;
+pub use btree::stats::Stats;
+pub use btree::check::CheckReport;
+pub use btree::dump::DumpFormat;
+pub use btree::txn::Transaction;
+pub use btree::batch::WriteBatch;
+pub use btree::iter::Range;
+pub use btree::snapshot::Snapshot;
+pub use btree::store::{PageStore, LogStore, MemoryStore};
@@ -1,101 +1,164 @@
-mod btree;
-use btree::tree::BTree;
-use std::fs::File;
-use std::io::{self, Write};
-
-//  writing different types of data
-//  imposing key order for different key types 
-//  test split pages
-//  removing nodes
-//  replicas
-//  partitions 
+extern crate rust_db;
 
+use rust_db::{Db, DumpFormat, Options};
+use std::env;
+use std::io;
+use std::process;
 
 fn main() {
-    let file_path = "/Users/anishganti/RustDB/src/test.bin";
-    let wal_path = "/Users/anishganti/RustDB/src/test_wal.bin";
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    if args.first().map(String::as_str) == Some("check") {
+        match args.get(1) {
+            Some(file_path) => process::exit(check(file_path)),
+            None => {
+                eprintln!("Usage: rust_db check <file>");
+                process::exit(2);
+            }
+        }
+    }
 
-    match create_binary_file(file_path, 4096) {
-        Ok(_) => println!("New database '{}' created successfully.", file_path),
-        Err(err) => eprintln!("Error creating database file: {}", err),
+    if args.first().map(String::as_str) == Some("viz") {
+        process::exit(viz(args.get(1)));
     }
 
-    match create_binary_file(wal_path, 0) {
-        Ok(_) => println!("New write ahead log (WAL) '{}' created successfully.", file_path),
-        Err(err) => eprintln!("Error creating WAL file: {}", err),
+    if args.first().map(String::as_str) == Some("dump") {
+        process::exit(dump(&args[1..]));
     }
 
-    // Load the database by opening the file and WAL from disk. 
-    let mut database = match BTree::new(file_path,  wal_path) {
-        Ok(btree) => btree,
+    // --read-only opens the database without changing it, which other read-only processes can do at the same time.
+    let read_only = args.iter().any(|arg| arg == "--read-only");
+    let file_path = args.iter().find(|arg| *arg != "--read-only").cloned().unwrap_or_else(|| "test.bin".to_string());
+
+    // Load the database from disk, recovering any changes left in the WAL. 
+    let database = match Db::open(&file_path, Options { read_only, ..Options::default() }) {
+        Ok(db) => db,
         Err(err) => {
-            eprintln!("Error creating BTree instance: {}", err);
+            eprintln!("Error opening database '{}': {}", file_path, err);
             return;
         }
     };
 
-    // Recover any lost changes made before. 
-    database.recover();
-    
     println!("Please type something, or stop to escape:");
     let mut input_string = String::new();
 
 
     loop {
         input_string.clear(); 
-        io::stdin().read_line(&mut input_string).unwrap(); // 
+        match io::stdin().read_line(&mut input_string) {
+            Ok(0) => break,
+            Ok(_) => (),
+            Err(err) => {
+                eprintln!("Error reading input: {}", err);
+                break;
+            }
+        }
 
         let trimmed_input = input_string.trim();
 
         if trimmed_input == "stop" {
-            database.flush();
             break;
         }
 
         let mut args = trimmed_input.split_whitespace();
         let op = args.next().unwrap_or(""); 
-        let key = args.next().unwrap_or("").parse::<u16>().unwrap_or(1);
-        let value = args.next().unwrap_or("").parse::<u16>().unwrap_or(0);
-        let mut result = None;
-
-        if op == "read" {
-            result = database.read(key);
-        } else if op == "write" {
-            database.write(key, value);
-        } else if op == "delete" {
-            database.delete(key);
-        }
+        let key = args.next().unwrap_or("").as_bytes();
+        let value = args.next().unwrap_or("").as_bytes();
+
+        let result = match op {
+            "read" => database.get(key),
+            "write" => database.put(key, value).map(|_| None),
+            "delete" => database.delete(key).map(|_| None),
+            _ => Ok(None),
+        };
 
         match result {
-            Some(value) => println!("Result: {}", value),
-            None => println!("No result"),
+            Ok(Some(value)) => println!("Result: {}", String::from_utf8_lossy(&value)),
+            Ok(None) => println!("No result"),
+            Err(err) => eprintln!("Error: {}", err),
+        }
+    }
+
+    if !read_only {
+        match database.flush() {
+            Ok(_) => println!("Successfully flushed changes to disk"),
+            Err(err) => eprintln!("Error flushing changes to disk: {}", err),
         }
     }
 
     println!("See you later!");
 }
 
-fn create_binary_file(file_path: &str, length : usize) -> io::Result<()> {
-    // Create a new file at the specified file_path
-    let mut file = File::create(file_path)?;
-
-    // Define the content of the binary file (4096 bytes)
-    let mut bytes = vec![0u8; length];  // Create a vector initialized with 4096 zeros
-
-    // Set the 5th byte (index 4) to 1 since the root should be initialized as the leaf
-    if bytes.len() > 4 && length == 4096  {
-        bytes[4] = 1;
-    } else {
-        //return Err(io::Error::new(io::ErrorKind::InvalidInput, "Insufficient bytes in buffer"));
+/// Checks the database at file_path and prints a report. Returns the exit code: 0 when the database is sound,
+/// 1 when problems were found and 2 when it could not be checked at all.
+fn check(file_path: &str) -> i32 {
+    match Db::check(file_path, Options::default()) {
+        Ok(report) => {
+            println!("{}", report);
+            if report.is_ok() { 0 } else { 1 }
+        },
+        Err(err) => {
+            eprintln!("Error checking database '{}': {}", file_path, err);
+            2
+        }
     }
+}
 
-    // Write the bytes to the file
-    file.write_all(&bytes)?;
+/// Prints the page given in args, or the whole tree level by level, of the database named by the first argument.
+/// --json switches to JSON output. Returns the exit code: 0 on success and 2 when the database could not be read.
+fn dump(args: &[String]) -> i32 {
+    let usage = "Usage: rust_db dump <file> [page] [--json]";
+    let format = if args.iter().any(|arg| arg == "--json") { DumpFormat::Json } else { DumpFormat::Text };
+    let args: Vec<&String> = args.iter().filter(|arg| *arg != "--json").collect();
+
+    let page_id = match args.get(1).map(|page| page.parse::<u32>()) {
+        None => None,
+        Some(Ok(page_id)) => Some(page_id),
+        Some(Err(_)) => {
+            eprintln!("{}", usage);
+            return 2;
+        }
+    };
 
-    // Close the file
-    file.flush()?;  // Flush any buffered data to ensure all bytes are written
-    drop(file);     // Close the file explicitly by dropping the file variable
+    let file_path = match args.first() {
+        Some(file_path) if args.len() <= 2 => file_path,
+        _ => {
+            eprintln!("{}", usage);
+            return 2;
+        }
+    };
 
-    Ok(())
+    match Db::dump(file_path, Options::default(), page_id, format) {
+        Ok(dump) => {
+            println!("{}", dump);
+            0
+        },
+        Err(err) => {
+            eprintln!("Error dumping database '{}': {}", file_path, err);
+            2
+        }
+    }
 }
 
+/// Prints the tree of the database at file_path as a DOT graph. Returns the exit code: 0 on success and 2 when
+/// the database could not be read.
+fn viz(file_path: Option<&String>) -> i32 {
+    let file_path = match file_path {
+        Some(file_path) => file_path,
+        None => {
+            eprintln!("Usage: rust_db viz <file>");
+            return 2;
+        }
+    };
+
+    match Db::viz(file_path, Options::default()) {
+        Ok(dot) => {
+            println!("{}", dot);
+            0
+        },
+        Err(err) => {
+            eprintln!("Error drawing database '{}': {}", file_path, err);
+            2
+        }
+    }
+}
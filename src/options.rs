@@ -0,0 +1,91 @@
+use btree::error::{DbError, Result};
+use btree::node::PAGE_SIZE;
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Smallest and largest page sizes the database supports. Pages must be able to hold a few of the largest
+/// entries, and sizes must stay within the u16 lengths used by the page layout. 
+pub const MIN_PAGE_SIZE: usize = 512;
+pub const MAX_PAGE_SIZE: usize = 32768;
+
+/// When the WAL and the data file are fsynced, trading the writes a power loss can take away for speed.
+/// A process that crashes loses nothing in any mode, since the WAL is written to the OS before each write
+/// returns. Only a crash of the whole machine can lose writes the OS had not put on disk yet.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SyncMode {
+    /// The WAL is synced before every write, delete, batch and transaction commit returns, so none of them
+    /// is ever lost once acknowledged.
+    Always,
+    /// The WAL is synced by the first commit made once interval has passed since the last sync. A power loss
+    /// loses at most the commits made during the last interval.
+    GroupCommit { interval: Duration },
+    /// The WAL is only synced by flushes and checkpoints. A power loss loses every commit made since the last one.
+    OnFlush,
+    /// Nothing is ever synced, not even by flushes, leaving it all to the OS. A power loss can lose any commit
+    /// and leave the data file corrupt.
+    Never,
+}
+
+/// Settings used when opening a database with Db::open(). 
+/// 
+/// Options::default() gives a database that is created if missing, opened for writing, uses 4096 byte pages,
+/// caches 64 pages and keeps its WAL next to the database file. It checkpoints once the WAL grows past 4 MiB
+/// or a minute has passed since the last checkpoint, and syncs the WAL on every commit. 
+#[derive(Clone, Debug)]
+pub struct Options {
+    /// Create a new, empty database if the file does not exist yet. 
+    pub create_if_missing: bool,
+    /// Open the database without changing it. Any number of read-only handles, from any number of processes,
+    /// can have the database open at once, but not together with a handle that writes. A missing database is
+    /// never created, and one whose WAL still needs recovering cannot be opened read-only.
+    pub read_only: bool,
+    /// Size of a single page (node) in bytes. Must be a power of two between MIN_PAGE_SIZE and MAX_PAGE_SIZE. 
+    /// Only used when creating a database, an existing database keeps the page size stored in its meta page. 
+    pub page_size: usize,
+    /// Number of pages kept in memory by the buffer pool. Changed pages are written back when they get evicted. 
+    pub cache_size: usize,
+    /// Location of the write-ahead log. Defaults to the database path with ".wal" appended. 
+    pub wal_path: Option<PathBuf>,
+    /// Take a checkpoint once the WAL holds this many bytes. None turns this trigger off.
+    pub checkpoint_wal_size: Option<u64>,
+    /// Take a checkpoint once this many pages in the buffer pool are dirty. None turns this trigger off.
+    pub checkpoint_dirty_pages: Option<usize>,
+    /// Take a checkpoint once this much time has passed since the last one. Only checked when a write or
+    /// delete happens, an idle database does not checkpoint. None turns this trigger off.
+    pub checkpoint_interval: Option<Duration>,
+    /// When the WAL and the data file are fsynced, see SyncMode.
+    pub sync_mode: SyncMode,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            create_if_missing: true,
+            read_only: false,
+            page_size: PAGE_SIZE,
+            cache_size: 64,
+            wal_path: None,
+            checkpoint_wal_size: Some(4 << 20),
+            checkpoint_dirty_pages: None,
+            checkpoint_interval: Some(Duration::from_secs(60)),
+            sync_mode: SyncMode::Always,
+        }
+    }
+}
+
+impl Options {
+    /// Checks that the options describe a database that can be opened. 
+    pub fn validate(&self) -> Result<()> {
+        if !self.page_size.is_power_of_two() || self.page_size < MIN_PAGE_SIZE || self.page_size > MAX_PAGE_SIZE {
+            return Err(DbError::InvalidOptions(format!(
+                "page size must be a power of two between {} and {} bytes, got {}", MIN_PAGE_SIZE, MAX_PAGE_SIZE, self.page_size)));
+        }
+
+        if self.cache_size == 0 {
+            return Err(DbError::InvalidOptions("cache size must hold at least one page".to_string()));
+        }
+
+        Ok(())
+    }
+}